@@ -0,0 +1,541 @@
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+use std::net::IpAddr;
+
+/// Networks a client must not be able to reach through the tunnel unless explicitly allowed:
+/// "this" network, private, shared (CGNAT), loopback, link-local (incl. the cloud metadata endpoint 169.254.169.254),
+/// multicast, reserved and broadcast.
+/// https://www.iana.org/assignments/iana-ipv4-special-registry/iana-ipv4-special-registry.xhtml
+/// https://www.iana.org/assignments/iana-ipv6-special-registry/iana-ipv6-special-registry.xhtml
+const DEFAULT_DENIED_NETWORKS: [&str; 17] = [
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+    "64:ff9b::/96",
+];
+
+/// What to do with a tunnel request matched by a rule.
+#[derive(Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessAction {
+    Allow,
+    Deny,
+}
+
+/// The outcome of an access check.
+/// The resolved IP of a target is known only after the DNS look-up,
+/// so a request can't always be decided when the handshake is decoded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AccessDecision {
+    Allow,
+    Deny,
+    /// A rule depends on the resolved address, check again once it's known.
+    Undecided,
+}
+
+/// A single allow/deny rule. Each condition is optional, a rule matches if all its conditions match.
+///
+/// ```yaml
+/// - action: allow
+///   users: [alice]                  # authenticated users (Proxy-Authorization)
+///   clients: ["192.168.0.0/16"]     # networks of the client source address
+///   hosts: ["*.example.com"]        # host globs, `*` and `?` are supported
+///   ports: [443, "8000-8999"]       # ports or port ranges
+///   networks: ["10.0.0.0/8"]        # networks of the resolved target address
+/// ```
+#[derive(Deserialize, Clone, Debug)]
+pub struct AccessRule {
+    pub action: AccessAction,
+    #[serde(default)]
+    pub users: Option<Vec<String>>,
+    #[serde(default)]
+    pub clients: Option<Vec<IpNet>>,
+    #[serde(default)]
+    pub hosts: Option<Vec<String>>,
+    #[serde(default)]
+    pub ports: Option<Vec<PortRange>>,
+    #[serde(default)]
+    pub networks: Option<Vec<IpNet>>,
+}
+
+/// Ordered access rules, the first matching rule wins.
+/// If no rule matches, `default` applies.
+#[derive(Deserialize, Clone, Debug)]
+pub struct AccessControlList {
+    #[serde(default = "default_action")]
+    pub default: AccessAction,
+    #[serde(default)]
+    pub rules: Vec<AccessRule>,
+}
+
+fn default_action() -> AccessAction {
+    AccessAction::Allow
+}
+
+impl Default for AccessControlList {
+    fn default() -> Self {
+        Self {
+            default: default_action(),
+            rules: vec![],
+        }
+    }
+}
+
+/// Everything known about a tunnel request at the time of the check.
+pub struct AccessRequest<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    /// The target address, `None` until it's resolved (unless the host is an IP literal).
+    pub address: Option<IpAddr>,
+    pub user: Option<&'a str>,
+    pub client: Option<IpAddr>,
+}
+
+impl<'a> AccessRequest<'a> {
+    /// Builds a request from a `host:port` target, `[ipv6]:port` is supported as well.
+    pub fn from_target(target: &'a str) -> Option<Self> {
+        let colon = target.rfind(':')?;
+        let port = target[colon + 1..].parse::<u16>().ok()?;
+        let host = target[..colon].trim_start_matches('[').trim_end_matches(']');
+
+        Some(Self {
+            host,
+            port,
+            address: host.parse::<IpAddr>().ok(),
+            user: None,
+            client: None,
+        })
+    }
+}
+
+impl AccessControlList {
+    pub fn check(&self, request: &AccessRequest) -> AccessDecision {
+        for rule in self.rules.iter() {
+            match rule.matches(request) {
+                Some(true) => return rule.action.into(),
+                Some(false) => continue,
+                // The rule might match once the address is known,
+                // and it takes precedence over the rules that follow.
+                None => return AccessDecision::Undecided,
+            }
+        }
+        self.default.into()
+    }
+}
+
+impl From<AccessAction> for AccessDecision {
+    fn from(action: AccessAction) -> Self {
+        match action {
+            AccessAction::Allow => AccessDecision::Allow,
+            AccessAction::Deny => AccessDecision::Deny,
+        }
+    }
+}
+
+impl AccessRule {
+    /// `Some(matched)`, or `None` if it depends on the target address which isn't known yet.
+    fn matches(&self, request: &AccessRequest) -> Option<bool> {
+        if let Some(users) = &self.users {
+            match request.user {
+                Some(user) if users.iter().any(|u| u == user) => {}
+                _ => return Some(false),
+            }
+        }
+
+        if let Some(clients) = &self.clients {
+            match request.client {
+                Some(client) if clients.iter().any(|net| net.contains(&client)) => {}
+                _ => return Some(false),
+            }
+        }
+
+        if let Some(hosts) = &self.hosts {
+            if !hosts.iter().any(|glob| glob_match(glob, request.host)) {
+                return Some(false);
+            }
+        }
+
+        if let Some(ports) = &self.ports {
+            if !ports.iter().any(|range| range.contains(request.port)) {
+                return Some(false);
+            }
+        }
+
+        if let Some(networks) = &self.networks {
+            return request
+                .address
+                .map(|address| networks.iter().any(|net| net.contains(&address)));
+        }
+
+        Some(true)
+    }
+}
+
+/// Case-insensitive glob, `*` matches any sequence (including dots), `?` matches a single character.
+fn glob_match(glob: &str, host: &str) -> bool {
+    let glob = glob.as_bytes();
+    let host = host.as_bytes();
+
+    let (mut g, mut h) = (0, 0);
+    // the position of the last `*` and the host position it's matched against
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while h < host.len() {
+        if g < glob.len() && (glob[g] == b'?' || glob[g].eq_ignore_ascii_case(&host[h])) {
+            g += 1;
+            h += 1;
+        } else if g < glob.len() && glob[g] == b'*' {
+            backtrack = Some((g, h));
+            g += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // let the last `*` absorb one more character
+            g = star + 1;
+            h = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    glob[g..].iter().all(|c| *c == b'*')
+}
+
+/// An inclusive port range, written as `443` or `"8000-8999"`.
+#[derive(Clone, Copy, Debug)]
+pub struct PortRange {
+    from: u16,
+    to: u16,
+}
+
+impl PortRange {
+    fn contains(&self, port: u16) -> bool {
+        self.from <= port && port <= self.to
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // untagged: tries each variant in order
+        // https://serde.rs/enum-representations.html#untagged
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Port {
+            Single(u16),
+            Range(String),
+        }
+
+        let range = match Port::deserialize(deserializer)? {
+            Port::Single(port) => Some(PortRange { from: port, to: port }),
+            Port::Range(range) => {
+                let mut bounds = range.splitn(2, '-').map(|p| p.trim().parse::<u16>());
+                match (bounds.next(), bounds.next()) {
+                    (Some(Ok(from)), Some(Ok(to))) if from <= to => Some(PortRange { from, to }),
+                    (Some(Ok(port)), None) => Some(PortRange { from: port, to: port }),
+                    _ => None,
+                }
+            }
+        };
+
+        range.ok_or_else(|| serde::de::Error::custom("expected a port or a `from-to` port range"))
+    }
+}
+
+/// Which target addresses the tunnel may connect to, checked on the address actually dialed.
+/// As the check happens after the DNS look-up, a host name can't be used to sneak into a denied network
+/// (e.g. DNS rebinding).
+///
+/// ```yaml
+/// destination_policy:
+///   allowed_networks: ["10.1.0.0/16"]   # exceptions, checked first
+///   denied_networks: [...]              # replaces the default list
+/// ```
+#[derive(Deserialize, Clone, Debug)]
+pub struct DestinationPolicy {
+    #[serde(default)]
+    pub allowed_networks: Vec<IpNet>,
+    #[serde(default = "default_denied_networks")]
+    pub denied_networks: Vec<IpNet>,
+}
+
+fn default_denied_networks() -> Vec<IpNet> {
+    DEFAULT_DENIED_NETWORKS
+        .iter()
+        .map(|net| net.parse().expect("Bug: bad default network"))
+        .collect()
+}
+
+impl Default for DestinationPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_networks: vec![],
+            denied_networks: default_denied_networks(),
+        }
+    }
+}
+
+impl DestinationPolicy {
+    /// No restrictions, for destinations chosen by the operator rather than by the client.
+    pub fn allow_all() -> Self {
+        Self {
+            allowed_networks: vec![],
+            denied_networks: vec![],
+        }
+    }
+
+    pub fn is_allowed(&self, address: &IpAddr) -> bool {
+        // An IPv4-mapped IPv6 address (::ffff:127.0.0.1) reaches the IPv4 host, so check it as IPv4.
+        // https://doc.rust-lang.org/std/net/struct.Ipv6Addr.html#method.to_ipv4_mapped
+        let address = match address {
+            IpAddr::V6(v6) => v6
+                .to_ipv4_mapped()
+                .map(IpAddr::V4)
+                .unwrap_or(*address),
+            IpAddr::V4(_) => *address,
+        };
+
+        self.allowed_networks.iter().any(|net| net.contains(&address))
+            || !self.denied_networks.iter().any(|net| net.contains(&address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(yaml: &str) -> AccessControlList {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn request<'a>(target: &'a str, user: Option<&'a str>, client: Option<&str>) -> AccessRequest<'a> {
+        let mut request = AccessRequest::from_target(target).unwrap();
+        request.user = user;
+        request.client = client.map(|client| client.parse().unwrap());
+        request
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("*", "www.example.com"));
+        assert!(glob_match("*.example.com", "www.example.com"));
+        assert!(glob_match("*.example.com", "a.b.example.com"));
+        assert!(!glob_match("*.example.com", "example.com"));
+        assert!(!glob_match("*.example.com", "www.example.com.evil.net"));
+        assert!(glob_match("api?.example.com", "api1.example.com"));
+        assert!(!glob_match("api?.example.com", "api.example.com"));
+        assert!(glob_match("*.example.*", "www.example.org"));
+        // only if compared case-insensitively
+        assert!(glob_match("*.Example.COM", "WWW.example.com"));
+    }
+
+    #[test]
+    fn port_ranges() {
+        let acl = acl("
+default: deny
+rules:
+  - action: allow
+    ports: [443, \"8000-8999\"]
+");
+        for (port, expected) in [
+            (443, AccessDecision::Allow),
+            (442, AccessDecision::Deny),
+            (444, AccessDecision::Deny),
+            (7999, AccessDecision::Deny),
+            (8000, AccessDecision::Allow),
+            (8999, AccessDecision::Allow),
+            (9000, AccessDecision::Deny),
+        ] {
+            let target = format!("www.example.com:{}", port);
+            assert_eq!(acl.check(&request(&target, None, None)), expected, "{}", port);
+        }
+    }
+
+    #[test]
+    fn bad_port_ranges_are_rejected() {
+        for ports in ["[\"9000-8000\"]", "[\"80-\"]", "[\"http\"]", "[70000]"] {
+            let yaml = format!("rules: [{{action: allow, ports: {}}}]", ports);
+            assert!(serde_yaml::from_str::<AccessControlList>(&yaml).is_err(), "{}", ports);
+        }
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let acl = acl("
+rules:
+  - action: allow
+    hosts: [\"good.example.com\"]
+  - action: deny
+    hosts: [\"*.example.com\"]
+  - action: allow
+    hosts: [\"*\"]
+");
+        assert_eq!(acl.check(&request("good.example.com:443", None, None)), AccessDecision::Allow);
+        assert_eq!(acl.check(&request("bad.example.com:443", None, None)), AccessDecision::Deny);
+        assert_eq!(acl.check(&request("www.example.org:443", None, None)), AccessDecision::Allow);
+    }
+
+    #[test]
+    fn default_applies_without_a_match() {
+        let acl = acl("
+default: deny
+rules:
+  - action: allow
+    hosts: [\"*.example.com\"]
+");
+        assert_eq!(acl.check(&request("www.example.org:443", None, None)), AccessDecision::Deny);
+        assert_eq!(AccessControlList::default().check(&request("www.example.org:443", None, None)), AccessDecision::Allow);
+    }
+
+    #[test]
+    fn network_rules_wait_for_the_address() {
+        let acl = acl("
+default: deny
+rules:
+  - action: allow
+    networks: [\"93.184.216.0/24\"]
+");
+        let mut unresolved = request("www.example.com:443", None, None);
+        assert_eq!(acl.check(&unresolved), AccessDecision::Undecided);
+
+        unresolved.address = Some("93.184.216.34".parse().unwrap());
+        assert_eq!(acl.check(&unresolved), AccessDecision::Allow);
+        // Falls through to the default once the address doesn't match.
+        unresolved.address = Some("192.0.2.1".parse().unwrap());
+        assert_eq!(acl.check(&unresolved), AccessDecision::Deny);
+        // IP literals are known up front.
+        assert_eq!(acl.check(&request("192.0.2.1:443", None, None)), AccessDecision::Deny);
+        assert_eq!(acl.check(&request("93.184.216.34:443", None, None)), AccessDecision::Allow);
+    }
+
+    #[test]
+    fn undecided_rule_takes_precedence() {
+        let acl = acl("
+rules:
+  - action: deny
+    networks: [\"10.0.0.0/8\"]
+  - action: allow
+    hosts: [\"*\"]
+");
+        assert_eq!(acl.check(&request("internal.example.com:443", None, None)), AccessDecision::Undecided);
+    }
+
+    #[test]
+    fn users_and_clients() {
+        let acl = acl("
+default: deny
+rules:
+  - action: allow
+    users: [alice]
+    clients: [\"192.168.0.0/16\"]
+");
+        assert_eq!(
+            acl.check(&request("a.com:443", Some("alice"), Some("192.168.1.2"))),
+            AccessDecision::Allow
+        );
+        assert_eq!(acl.check(&request("a.com:443", Some("bob"), Some("192.168.1.2"))), AccessDecision::Deny);
+        assert_eq!(acl.check(&request("a.com:443", None, Some("192.168.1.2"))), AccessDecision::Deny);
+        assert_eq!(acl.check(&request("a.com:443", Some("alice"), Some("10.0.0.1"))), AccessDecision::Deny);
+        assert_eq!(acl.check(&request("a.com:443", Some("alice"), None)), AccessDecision::Deny);
+    }
+
+    #[test]
+    fn empty_lists_match_nobody() {
+        let acl = acl("
+rules:
+  - action: deny
+    users: []
+  - action: deny
+    clients: []
+");
+        assert_eq!(
+            acl.check(&request("a.com:443", Some("alice"), Some("192.168.1.2"))),
+            AccessDecision::Allow
+        );
+    }
+
+    #[test]
+    fn targets() {
+        let request = AccessRequest::from_target("[2001:db8::1]:443").unwrap();
+        assert_eq!((request.host, request.port), ("2001:db8::1", 443));
+        assert_eq!(request.address, Some("2001:db8::1".parse().unwrap()));
+        assert!(AccessRequest::from_target("www.example.com").is_none());
+        assert!(AccessRequest::from_target("www.example.com:https").is_none());
+    }
+
+    fn ips(list: &[&str]) -> Vec<IpAddr> {
+        list.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    const INTERNAL: [&str; 12] = [
+        "127.0.0.1",
+        "169.254.169.254",
+        "10.1.2.3",
+        "172.16.0.1",
+        "172.31.255.255",
+        "192.168.1.1",
+        "100.64.0.1",
+        "0.0.0.0",
+        "::1",
+        "fd00::1",
+        "fe80::1",
+        "::ffff:127.0.0.1",
+    ];
+
+    #[test]
+    fn internal_networks_are_denied_by_default() {
+        let policy = DestinationPolicy::default();
+        for ip in ips(&INTERNAL) {
+            assert!(!policy.is_allowed(&ip), "{}", ip);
+        }
+        // IPv4-mapped addresses are checked as IPv4.
+        assert!(!policy.is_allowed(&"::ffff:169.254.169.254".parse().unwrap()));
+        assert!(!policy.is_allowed(&"::ffff:10.0.0.1".parse().unwrap()));
+
+        for ip in ips(&["93.184.216.34", "172.32.0.1", "2606:2800:220:1::1", "::ffff:93.184.216.34"]) {
+            assert!(policy.is_allowed(&ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn configured_default_is_the_same() {
+        let policy: DestinationPolicy = serde_yaml::from_str("{}").unwrap();
+        for ip in ips(&INTERNAL) {
+            assert!(!policy.is_allowed(&ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn allowed_networks_override_the_denied_ones() {
+        let policy: DestinationPolicy =
+            serde_yaml::from_str("allowed_networks: [127.0.0.0/8, \"10.1.0.0/16\", \"fd00::/8\"]").unwrap();
+        for ip in ips(&["127.0.0.1", "::ffff:127.0.0.1", "10.1.2.3", "fd00::1"]) {
+            assert!(policy.is_allowed(&ip), "{}", ip);
+        }
+        for ip in ips(&["10.2.0.1", "169.254.169.254", "::1"]) {
+            assert!(!policy.is_allowed(&ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn denied_networks_replace_the_default() {
+        let policy: DestinationPolicy = serde_yaml::from_str("denied_networks: [\"169.254.0.0/16\"]").unwrap();
+        assert!(!policy.is_allowed(&"169.254.169.254".parse().unwrap()));
+        assert!(policy.is_allowed(&"10.1.2.3".parse().unwrap()));
+
+        for ip in ips(&INTERNAL) {
+            assert!(DestinationPolicy::allow_all().is_allowed(&ip), "{}", ip);
+        }
+    }
+}
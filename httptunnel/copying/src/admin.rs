@@ -0,0 +1,57 @@
+use crate::http_endpoint::Response;
+use crate::registry::TunnelRegistry;
+
+const TUNNELS_PATH: &str = "/tunnels";
+
+#[derive(Serialize)]
+struct Terminated {
+    id: u128,
+    terminated: bool,
+}
+
+/// The admin API, ids are the same as `CTX=` in the logs.
+///   GET    /tunnels       lists open tunnels, the oldest first
+///   GET    /tunnels/{id}  shows one
+///   DELETE /tunnels/{id}  terminates one, its relays report `Terminated`
+pub fn handle_admin_request(registry: &TunnelRegistry, method: &str, path: &str) -> Response {
+    if path == TUNNELS_PATH {
+        return match method {
+            "GET" => Response::json("200 OK", &registry.list()),
+            _ => method_not_allowed(),
+        };
+    }
+
+    let id = match path
+        .strip_prefix(TUNNELS_PATH)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .and_then(|id| id.parse::<u128>().ok())
+    {
+        Some(id) => id,
+        None => return Response::not_found(),
+    };
+
+    match method {
+        "GET" => match registry.get(id) {
+            Some(tunnel) => Response::json("200 OK", &tunnel),
+            None => Response::not_found(),
+        },
+        // The relays close asynchronously, the tunnel is gone from the list once they have.
+        "DELETE" if registry.terminate(id) => Response::json(
+            "202 Accepted",
+            &Terminated {
+                id,
+                terminated: true,
+            },
+        ),
+        "DELETE" => Response::not_found(),
+        _ => method_not_allowed(),
+    }
+}
+
+fn method_not_allowed() -> Response {
+    Response::new(
+        "405 Method Not Allowed",
+        "text/plain",
+        "Method Not Allowed\n".to_string(),
+    )
+}
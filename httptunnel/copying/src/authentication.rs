@@ -0,0 +1,309 @@
+use log::{debug, error, warn};
+use serde::{Deserialize, Deserializer};
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io;
+
+/// bcrypt is slow on purpose, so verified credentials are remembered
+/// to avoid burning CPU (and blocking the runtime) on every tunnel of the same client.
+const MAX_VERIFIED_CREDENTIALS: usize = 1024;
+
+const DEFAULT_REALM: &str = "copying";
+
+/// Proxy authentication settings.
+/// https://datatracker.ietf.org/doc/html/rfc7235#section-3.2
+/// > The 407 (Proxy Authentication Required) status code is similar to 401 (Unauthorized),
+/// > but it indicates that the client needs to authenticate itself in order to use a proxy.
+#[derive(Deserialize, Clone)]
+pub struct AuthenticationConfig {
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    // The file is loaded while the configuration is read, the same way as `serde_regex` compiles regular expressions.
+    #[serde(rename = "credentials_file", deserialize_with = "credentials_from_file")]
+    pub credentials: Arc<Credentials>,
+}
+
+fn default_realm() -> String {
+    DEFAULT_REALM.to_string()
+}
+
+fn credentials_from_file<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Arc<Credentials>, D::Error> {
+    let filename = String::deserialize(deserializer)?;
+    Credentials::from_file(&filename)
+        .map(Arc::new)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Supported htpasswd hashes.
+/// https://httpd.apache.org/docs/2.4/misc/password_encryptions.html
+enum PasswordHash {
+    /// `$2y$...`, `$2a$...` or `$2b$...`
+    Bcrypt(String),
+    /// `{SHA}` followed by a Base64-encoded SHA-1 digest of the password
+    Sha1(Vec<u8>),
+}
+
+/// Credentials store, a htpasswd-style file: a `user:hash` pair per line.
+pub struct Credentials {
+    users: HashMap<String, PasswordHash>,
+    verified: Mutex<HashSet<String>>,
+    // bcrypt checks run on the runtime threads (in the codecs), each one blocks a thread for a while.
+    // Capped, so a flood of wrong passwords can't block all of them and stall every tunnel.
+    max_bcrypt_verifications: usize,
+    bcrypt_verifications: AtomicUsize,
+}
+
+impl Credentials {
+    pub fn from_file(filename: &str) -> io::Result<Self> {
+        let mut file = File::open(filename).map_err(|e| {
+            error!("Error opening credentials file {}: {}", filename, e);
+            e
+        })?;
+
+        let mut content = String::new();
+        file.read_to_string(&mut content).map_err(|e| {
+            error!("Error reading file {}: {}", filename, e);
+            e
+        })?;
+
+        Credentials::parse(&content).map_err(|e| {
+            error!("Error parsing credentials file {}: {}", filename, e);
+            e
+        })
+    }
+
+    pub(crate) fn parse(content: &str) -> io::Result<Self> {
+        let mut users = HashMap::new();
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (user, hash) = match line.find(':') {
+                Some(colon) => (&line[..colon], &line[colon + 1..]),
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("line {}: expected `user:hash`", number + 1),
+                    ))
+                }
+            };
+
+            let password_hash = if hash.starts_with("$2y$")
+                || hash.starts_with("$2a$")
+                || hash.starts_with("$2b$")
+            {
+                PasswordHash::Bcrypt(hash.to_string())
+            } else if let Some(digest) = hash.strip_prefix("{SHA}") {
+                let digest = base64::decode(digest).map_err(|_| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("line {}: bad {{SHA}} digest", number + 1),
+                    )
+                })?;
+                PasswordHash::Sha1(digest)
+            } else {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "line {}: unsupported hash for `{}`, use bcrypt (htpasswd -B) or SHA-1 (htpasswd -s)",
+                        number + 1,
+                        user
+                    ),
+                ));
+            };
+
+            if users.insert(user.to_string(), password_hash).is_some() {
+                warn!("Duplicate user `{}` in credentials, the last entry wins", user);
+            }
+        }
+
+        Ok(Self {
+            users,
+            verified: Mutex::new(HashSet::new()),
+            // The runtime has a thread per CPU by default, half of them are left for the tunnels.
+            max_bcrypt_verifications: std::thread::available_parallelism()
+                .map(|threads| (threads.get() / 2).max(1))
+                .unwrap_or(1),
+            bcrypt_verifications: AtomicUsize::new(0),
+        })
+    }
+
+    /// Checks a `Proxy-Authorization` header value and returns the authenticated user.
+    /// https://datatracker.ietf.org/doc/html/rfc7617#section-2
+    /// > credentials = "Basic" 1*SP token68, where token68 is Base64 of user-id ":" password
+    pub fn authenticate(&self, proxy_authorization: &str) -> Option<String> {
+        let mut parts = proxy_authorization.splitn(2, ' ');
+        let scheme = parts.next()?;
+        let token = parts.next()?.trim();
+
+        // > the scheme name is case-insensitive
+        if !scheme.eq_ignore_ascii_case("Basic") {
+            debug!("Unsupported authentication scheme `{}`", scheme);
+            return None;
+        }
+
+        let decoded = String::from_utf8(base64::decode(token).ok()?).ok()?;
+        let colon = decoded.find(':')?;
+        let (user, password) = (&decoded[..colon], &decoded[colon + 1..]);
+
+        if self.verify(user, password) {
+            Some(user.to_string())
+        } else {
+            None
+        }
+    }
+
+    /// Checks a user and password pair, e.g. from the SOCKS5 username/password authentication.
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        // The same as the Basic token, there's no need to keep another copy of the password.
+        let token = base64::encode(format!("{}:{}", user, password));
+        if self.verified.lock().expect("Poisoned lock").contains(&token) {
+            return true;
+        }
+
+        let authenticated = match self.users.get(user) {
+            Some(PasswordHash::Bcrypt(hash)) => self.verify_bcrypt(user, password, hash),
+            Some(PasswordHash::Sha1(digest)) => {
+                constant_time_eq(&Sha1::digest(password.as_bytes()), digest)
+            }
+            None => false,
+        };
+
+        if !authenticated {
+            debug!("Wrong password for `{}`", user);
+            return false;
+        }
+
+        let mut verified = self.verified.lock().expect("Poisoned lock");
+        if verified.len() >= MAX_VERIFIED_CREDENTIALS {
+            verified.clear();
+        }
+        verified.insert(token);
+
+        true
+    }
+
+    /// Fails if as many bcrypt checks are already running, the client can retry.
+    /// Credentials verified before are cached and don't count.
+    fn verify_bcrypt(&self, user: &str, password: &str, hash: &str) -> bool {
+        let acquired = self
+            .bcrypt_verifications
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |running| {
+                if running < self.max_bcrypt_verifications {
+                    Some(running + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok();
+        if !acquired {
+            warn!("Too many password checks in progress, rejected `{}`", user);
+            return false;
+        }
+
+        let verified = bcrypt::verify(password, hash).unwrap_or(false);
+        self.bcrypt_verifications.fetch_sub(1, Ordering::AcqRel);
+        verified
+    }
+}
+
+/// Compares digests without exiting early, so the time doesn't tell how many bytes matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha1_entry(user: &str, password: &str) -> String {
+        format!("{}:{{SHA}}{}", user, base64::encode(Sha1::digest(password.as_bytes())))
+    }
+
+    fn credentials() -> Credentials {
+        let bcrypt_hash = bcrypt::hash("bcrypt-secret", 4).unwrap();
+        let content = format!(
+            "# users\n\nalice:{}\n{}\n",
+            bcrypt_hash,
+            sha1_entry("bob", "sha-secret")
+        );
+        Credentials::parse(&content).unwrap()
+    }
+
+    #[test]
+    fn parse_bcrypt_and_sha1() {
+        let credentials = credentials();
+        assert_eq!(credentials.users.len(), 2);
+        assert!(matches!(credentials.users.get("alice"), Some(PasswordHash::Bcrypt(_))));
+        assert!(matches!(credentials.users.get("bob"), Some(PasswordHash::Sha1(digest)) if digest.len() == 20));
+    }
+
+    #[test]
+    fn parse_rejects_bad_lines() {
+        for content in [
+            "alice",
+            "alice:plaintext",
+            "alice:$apr1$salt$hash",
+            "alice:{SHA}not base64!",
+        ] {
+            let error = Credentials::parse(content)
+                .err()
+                .unwrap_or_else(|| panic!("{:?} accepted", content));
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn verify_passwords() {
+        let credentials = credentials();
+        assert!(credentials.verify("alice", "bcrypt-secret"));
+        assert!(!credentials.verify("alice", "wrong"));
+        assert!(credentials.verify("bob", "sha-secret"));
+        assert!(!credentials.verify("bob", "wrong"));
+        assert!(!credentials.verify("carol", "sha-secret"));
+    }
+
+    #[test]
+    fn authenticate_basic() {
+        let credentials = credentials();
+        let header = |user_password: &str| format!("Basic {}", base64::encode(user_password));
+        assert_eq!(
+            credentials.authenticate(&header("bob:sha-secret")),
+            Some("bob".to_string())
+        );
+        assert_eq!(
+            credentials.authenticate(&format!("basic {}", base64::encode("bob:sha-secret"))),
+            Some("bob".to_string())
+        );
+        assert_eq!(credentials.authenticate(&header("bob:wrong")), None);
+        assert_eq!(credentials.authenticate(&header("bob")), None);
+        assert_eq!(credentials.authenticate("Basic !!!"), None);
+        assert_eq!(credentials.authenticate("Bearer token"), None);
+    }
+
+    #[test]
+    fn bcrypt_checks_are_capped() {
+        let mut credentials = credentials();
+        credentials.max_bcrypt_verifications = 0;
+        assert!(!credentials.verify("alice", "bcrypt-secret"));
+        // Only bcrypt is capped.
+        assert!(credentials.verify("bob", "sha-secret"));
+
+        credentials.max_bcrypt_verifications = 1;
+        assert!(credentials.verify("alice", "bcrypt-secret"));
+        assert_eq!(credentials.bcrypt_verifications.load(Ordering::Acquire), 0);
+
+        // Cached now, doesn't need a check.
+        credentials.max_bcrypt_verifications = 0;
+        assert!(credentials.verify("alice", "bcrypt-secret"));
+    }
+}
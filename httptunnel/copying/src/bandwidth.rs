@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+// The clock of the runtime, it can be paused in the tests.
+use tokio::time::Instant;
+
+/// Bandwidth caps shared by tunnels, a missing value means no cap.
+/// Bytes relayed in both directions count against the same bucket.
+#[derive(Deserialize, Clone, Default, Debug)]
+pub struct BandwidthLimits {
+    /// Per authenticated user, tunnels without a user are not capped by it.
+    #[serde(default)]
+    pub per_user: Option<BandwidthLimit>,
+    #[serde(default)]
+    pub per_target: Option<BandwidthLimit>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct BandwidthLimit {
+    pub rate_bpm: u64, // bpm = bytes per minute
+    /// Bytes that may be sent at once after a quiet period, one second worth of `rate_bpm` by default.
+    #[serde(default)]
+    pub burst_bytes: Option<u64>,
+}
+
+/// Token bucket: tokens are added at `rate_bpm` up to `burst_bytes`, every relayed byte takes one.
+/// https://en.wikipedia.org/wiki/Token_bucket
+/// The bucket may go into debt, the sender then waits until it's paid off,
+/// so concurrent senders queue up behind each other and writes larger than the burst still pass.
+pub struct TokenBucket {
+    limit: BandwidthLimit,
+    // bytes per second
+    rate: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// `limit.rate_bpm` must be positive, see `TunnelConfig::validate`.
+    pub fn new(limit: BandwidthLimit) -> Self {
+        let rate = limit.rate_bpm as f64 / 60.;
+        let burst = limit.burst_bytes.map(|b| b as f64).unwrap_or(rate).max(1.);
+        Self {
+            limit,
+            rate,
+            burst,
+            state: Mutex::new(BucketState {
+                tokens: burst,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `bytes` tokens and returns how long to wait before sending them.
+    pub fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        let now = Instant::now();
+        let refill = now.duration_since(state.updated).as_secs_f64() * self.rate;
+        state.tokens = (state.tokens + refill).min(self.burst) - bytes as f64;
+        state.updated = now;
+
+        if state.tokens >= 0. {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / self.rate)
+        }
+    }
+}
+
+/// Hands out the buckets shared by tunnels of the same user or target.
+/// Buckets are kept only while some tunnel holds them.
+#[derive(Default)]
+pub struct BandwidthLimiter {
+    per_user: Mutex<HashMap<String, Weak<TokenBucket>>>,
+    per_target: Mutex<HashMap<String, Weak<TokenBucket>>>,
+}
+
+impl BandwidthLimiter {
+    /// The limits are passed on every call, so they can change while the proxy is running.
+    /// Tunnels opened before a change keep the old bucket.
+    pub fn shared_buckets(
+        &self,
+        limits: &BandwidthLimits,
+        user: Option<&str>,
+        target: &str,
+    ) -> Vec<Arc<TokenBucket>> {
+        let mut buckets = vec![];
+        if let (Some(limit), Some(user)) = (limits.per_user, user) {
+            buckets.push(shared_bucket(&self.per_user, user, limit));
+        }
+        if let Some(limit) = limits.per_target {
+            buckets.push(shared_bucket(&self.per_target, target, limit));
+        }
+        buckets
+    }
+}
+
+fn shared_bucket(
+    buckets: &Mutex<HashMap<String, Weak<TokenBucket>>>,
+    key: &str,
+    limit: BandwidthLimit,
+) -> Arc<TokenBucket> {
+    let mut buckets = buckets.lock().expect("Poisoned lock");
+
+    if let Some(bucket) = buckets.get(key).and_then(Weak::upgrade) {
+        if bucket.limit == limit {
+            return bucket;
+        }
+    }
+
+    // Drops the buckets of closed tunnels, so the map doesn't grow with every key ever seen.
+    buckets.retain(|_, bucket| bucket.strong_count() > 0);
+
+    let bucket = Arc::new(TokenBucket::new(limit));
+    buckets.insert(key.to_string(), Arc::downgrade(&bucket));
+    bucket
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::advance;
+
+    // 100 bytes per second
+    fn limit(burst_bytes: Option<u64>) -> BandwidthLimit {
+        BandwidthLimit {
+            rate_bpm: 6000,
+            burst_bytes,
+        }
+    }
+
+    fn millis(wait: Duration) -> u128 {
+        wait.as_millis()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_starts_full() {
+        let bucket = TokenBucket::new(limit(None));
+        assert_eq!(bucket.take(60), Duration::ZERO);
+        assert_eq!(bucket.take(40), Duration::ZERO);
+        assert_eq!(millis(bucket.take(50)), 500);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refill() {
+        let bucket = TokenBucket::new(limit(None));
+        assert_eq!(bucket.take(100), Duration::ZERO);
+
+        advance(Duration::from_millis(500)).await;
+        assert_eq!(bucket.take(50), Duration::ZERO);
+        assert_eq!(millis(bucket.take(10)), 100);
+
+        advance(Duration::from_millis(300)).await;
+        assert_eq!(bucket.take(20), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refill_stops_at_the_burst() {
+        let bucket = TokenBucket::new(limit(Some(250)));
+        assert_eq!(bucket.take(250), Duration::ZERO);
+
+        advance(Duration::from_secs(60)).await;
+        assert_eq!(bucket.take(250), Duration::ZERO);
+        assert_eq!(millis(bucket.take(1)), 10);
+
+        // The default burst is one second worth of the rate.
+        let bucket = TokenBucket::new(limit(None));
+        advance(Duration::from_secs(60)).await;
+        assert_eq!(bucket.take(100), Duration::ZERO);
+        assert_eq!(millis(bucket.take(1)), 10);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn debt_is_carried_over() {
+        let bucket = TokenBucket::new(limit(None));
+        // Larger than the burst, it passes and the bucket goes into debt.
+        assert_eq!(millis(bucket.take(300)), 2000);
+        // The next sender queues up behind the first one.
+        assert_eq!(millis(bucket.take(100)), 3000);
+
+        advance(Duration::from_secs(1)).await;
+        assert_eq!(millis(bucket.take(0)), 2000);
+        advance(Duration::from_secs(2)).await;
+        assert_eq!(bucket.take(0), Duration::ZERO);
+        assert_eq!(millis(bucket.take(100)), 1000);
+    }
+
+    fn limits(per_user: Option<BandwidthLimit>, per_target: Option<BandwidthLimit>) -> BandwidthLimits {
+        BandwidthLimits { per_user, per_target }
+    }
+
+    #[test]
+    fn buckets_are_shared_by_key() {
+        let limiter = BandwidthLimiter::default();
+        let limits = limits(Some(limit(None)), Some(limit(Some(1000))));
+
+        let alice = limiter.shared_buckets(&limits, Some("alice"), "a:443");
+        let again = limiter.shared_buckets(&limits, Some("alice"), "b:443");
+        assert_eq!(alice.len(), 2);
+        assert!(Arc::ptr_eq(&alice[0], &again[0]));
+        assert!(!Arc::ptr_eq(&alice[1], &again[1]));
+
+        let bob = limiter.shared_buckets(&limits, Some("bob"), "a:443");
+        assert!(!Arc::ptr_eq(&alice[0], &bob[0]));
+        assert!(Arc::ptr_eq(&alice[1], &bob[1]));
+
+        // Tunnels without a user are capped per target only.
+        let anonymous = limiter.shared_buckets(&limits, None, "a:443");
+        assert_eq!(anonymous.len(), 1);
+        assert!(Arc::ptr_eq(&alice[1], &anonymous[0]));
+
+        assert!(limiter
+            .shared_buckets(&BandwidthLimits::default(), Some("alice"), "a:443")
+            .is_empty());
+    }
+
+    #[test]
+    fn changed_limit_gets_a_new_bucket() {
+        let limiter = BandwidthLimiter::default();
+
+        let old = limiter.shared_buckets(&limits(None, Some(limit(None))), None, "a:443");
+        let new = limiter.shared_buckets(&limits(None, Some(limit(Some(1000)))), None, "a:443");
+        assert!(!Arc::ptr_eq(&old[0], &new[0]));
+        assert_eq!(new[0].limit, limit(Some(1000)));
+
+        let again = limiter.shared_buckets(&limits(None, Some(limit(Some(1000)))), None, "a:443");
+        assert!(Arc::ptr_eq(&new[0], &again[0]));
+    }
+
+    #[test]
+    fn unused_buckets_are_reclaimed() {
+        let limiter = BandwidthLimiter::default();
+        let limits = limits(Some(limit(None)), None);
+
+        let first = limiter.shared_buckets(&limits, Some("alice"), "a:443");
+        let second = limiter.shared_buckets(&limits, Some("alice"), "a:443");
+        drop(first);
+        // Still held by the second tunnel.
+        let third = limiter.shared_buckets(&limits, Some("alice"), "a:443");
+        assert!(Arc::ptr_eq(&second[0], &third[0]));
+
+        drop(second);
+        drop(third);
+        let _bob = limiter.shared_buckets(&limits, Some("bob"), "a:443");
+        let per_user = limiter.per_user.lock().unwrap();
+        assert_eq!(per_user.keys().collect::<Vec<_>>(), vec!["bob"]);
+    }
+}
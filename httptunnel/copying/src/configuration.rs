@@ -1,4 +1,14 @@
-use crate::relay::{RelayPolicy, NO_BANDWIDTH_LIMIT, NO_TIMEOUT};
+use crate::access_control::{AccessControlList, DestinationPolicy};
+use crate::authentication::AuthenticationConfig;
+use crate::bandwidth::BandwidthLimits;
+use crate::limits::ConcurrencyLimits;
+use crate::nameserver::DnsOverrides;
+use crate::proxy_protocol::ProxyProtocolConfig;
+use crate::upstream_proxy::UpstreamProxy;
+use crate::relay::{
+    default_rate_grace_period, default_rate_window, MaxRateAction, RelayPolicy, NO_BANDWIDTH_LIMIT,
+    NO_TIMEOUT,
+};
 
 use clap::clap_app;
 use log::{info, error};
@@ -17,6 +27,7 @@ use std::fs::File;
 use std::io::{Error, ErrorKind, Read};
 /// A Duration type to represent a span of time, typically used for system timeouts.
 /// https://doc.rust-lang.org/stable/std/time/struct.Duration.html
+use std::net::SocketAddr;
 use std::time::Duration;
 use tokio::io;
 
@@ -25,8 +36,11 @@ use tokio::io;
 /// Enum JA: 列挙型
 /// https://doc.rust-jp.rs/rust-by-example-ja/custom_types/enum.html
 #[derive(Clone)]
+// Named after the protocols, as they are spelled.
+#[allow(clippy::upper_case_acronyms)]
 pub enum ProxyMode {
-    HTTP,
+    // Clients speaking TLS on the same port are served too, if there is an identity.
+    HTTP(Option<Identity>),
     // HTTPS(Identity) says that is will have associated `Identity` value.
     // https://doc.rust-lang.org/book/ch06-01-defining-an-enum.html
     // Identity:
@@ -37,6 +51,13 @@ pub enum ProxyMode {
     // You can create a String from a literal string with String::from:
     // https://doc.rust-lang.org/std/string/struct.String.html
     TCP(String),
+    // SOCKS Protocol Version 5 https://datatracker.ietf.org/doc/html/rfc1928
+    SOCKS5,
+    // TLS clients are routed by the SNI of their ClientHello, TLS isn't terminated.
+    // https://datatracker.ietf.org/doc/html/rfc6066#section-3
+    SNI,
+    // Connections redirected by the firewall (iptables REDIRECT/TPROXY) go to their original destination.
+    Transparent,
 }
 
 #[derive(Deserialize, Clone)]
@@ -56,22 +77,65 @@ pub struct ClientConnectionConfig {
     pub initiation_timeout: Duration,
     // TODO: add configuration to set relay policy
     pub relay_policy: RelayPolicy,
+    // Without `authentication` the tunnel is open to anyone who can reach it.
+    // #[serde(default)] uses Default::default() (None) if the field is missing.
+    // https://serde.rs/field-attrs.html#default
+    #[serde(default)]
+    pub authentication: Option<AuthenticationConfig>,
+    // Behind a TCP load balancer, the client address is taken from the PROXY protocol header it sends.
+    #[serde(default)]
+    pub proxy_protocol: Option<ProxyProtocolConfig>,
 }
 
 #[derive(Deserialize, Clone)]
 pub struct TargetConnectionConfig {
+    // Caps the TTL of the records, the system resolver doesn't tell it so this is the TTL then.
     #[serde(with = "humantime_serde")]
     pub dns_cache_ttl: Duration,
+    // Asked directly (UDP, TCP for long replies) instead of the system resolver, in this order, e.g. "10.0.0.53:53".
+    #[serde(default)]
+    pub nameservers: Vec<SocketAddr>,
+    // Host names pinned to addresses, like /etc/hosts, e.g. `internal.api: [10.1.2.3]`.
+    #[serde(default)]
+    pub dns_overrides: DnsOverrides,
+    // Names kept in the DNS cache, the least recently used one is evicted.
+    #[serde(default = "default_dns_cache_size")]
+    pub dns_cache_size: usize,
+    // How long failed look-ups (e.g. NXDOMAIN) are cached.
+    #[serde(with = "humantime_serde", default = "default_dns_negative_cache_ttl")]
+    pub dns_negative_cache_ttl: Duration,
     // Crate serde_regex: A (de)serializer for regex::Regex
     // https://docs.rs/serde_regex/0.2.0/serde_regex/index.html
     // Crate regex: This crate provides a library for parsing, compiling, and executing regular expressions. 
     // https://docs.rs/regex/1.5.4/regex/
-    #[serde(with = "serde_regex")]
+    // A coarse filter on `host:port`, `access_control` has the fine-grained rules.
+    #[serde(with = "serde_regex", default = "allow_all_targets")]
     pub allowed_targets: Regex,
     #[serde(with = "humantime_serde")]
     pub connect_timeout: Duration,
     // TODO: add configuration to set relay policy
     pub relay_policy: RelayPolicy,
+    // Ordered allow/deny rules by user, client network, host, port and resolved address.
+    #[serde(default)]
+    pub access_control: AccessControlList,
+    // Private, loopback, link-local, multicast and metadata addresses are denied by default.
+    #[serde(default)]
+    pub destination_policy: DestinationPolicy,
+    // Parent proxies by target pattern, the first match wins. Other targets are connected directly.
+    #[serde(default)]
+    pub upstream_proxies: Vec<UpstreamProxy>,
+}
+
+fn default_dns_cache_size() -> usize {
+    10_000
+}
+
+fn default_dns_negative_cache_ttl() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn allow_all_targets() -> Regex {
+    Regex::new(".*").expect("Bug: bad default regexp")
 }
 
 /// serde::Deserialize
@@ -80,6 +144,19 @@ pub struct TargetConnectionConfig {
 pub struct TunnelConfig {
     pub client_connection: ClientConnectionConfig,
     pub target_connection: TargetConnectionConfig,
+    // Unlimited unless configured.
+    #[serde(default)]
+    pub limits: ConcurrencyLimits,
+    // Caps shared by tunnels, on top of `max_rate_bpm` of each relay.
+    #[serde(default)]
+    pub bandwidth: BandwidthLimits,
+    // How long active tunnels may keep relaying after SIGTERM/SIGINT before they are closed.
+    #[serde(with = "humantime_serde", default = "default_drain_timeout")]
+    pub drain_timeout: Duration,
+}
+
+fn default_drain_timeout() -> Duration {
+    Duration::from_secs(30)
 }
 
 /// JA: コンパイラには、[#derive]アトリビュートを用いることで型に対して特定のトレイトの標準的な実装を提供する機能があります。
@@ -99,7 +176,65 @@ pub struct TunnelConfig {
 pub struct ProxyConfiguration {
     pub mode: ProxyMode,
     pub bind_address: String,
+    /// As read at startup, see `SharedTunnelConfig` for the reloaded one.
     pub tunnel_config: TunnelConfig,
+    /// Watched for changes if set.
+    #[builder(default)]
+    pub config_file: Option<String>,
+    /// Serves `/metrics` if set.
+    #[builder(default)]
+    pub metrics_bind_address: Option<String>,
+    /// Serves the admin API (`/tunnels`) if set.
+    #[builder(default)]
+    pub admin_bind_address: Option<String>,
+}
+
+impl TunnelConfig {
+    /// Parses and validates a configuration, e.g. on startup or reload.
+    pub fn from_yaml(yaml: &[u8]) -> Result<TunnelConfig, String> {
+        let tunnel_config: TunnelConfig =
+            serde_yaml::from_slice(yaml).map_err(|e| e.to_string())?;
+        tunnel_config.validate()?;
+        Ok(tunnel_config)
+    }
+
+    /// Checks what the types can't express.
+    fn validate(&self) -> Result<(), String> {
+        let relay_policies = [
+            ("client_connection", &self.client_connection.relay_policy),
+            ("target_connection", &self.target_connection.relay_policy),
+        ];
+        for (section, relay_policy) in relay_policies.iter() {
+            if relay_policy.min_rate_bpm > relay_policy.max_rate_bpm {
+                return Err(format!(
+                    "{}.relay_policy: min_rate_bpm {} is greater than max_rate_bpm {}",
+                    section, relay_policy.min_rate_bpm, relay_policy.max_rate_bpm
+                ));
+            }
+            if relay_policy.max_rate_action == MaxRateAction::Throttle && relay_policy.max_rate_bpm == 0 {
+                return Err(format!("{}.relay_policy: can't throttle to max_rate_bpm 0", section));
+            }
+        }
+        let bandwidth_limits = [
+            ("per_user", &self.bandwidth.per_user),
+            ("per_target", &self.bandwidth.per_target),
+        ];
+        for (name, limit) in bandwidth_limits.iter() {
+            if matches!(limit, Some(limit) if limit.rate_bpm == 0) {
+                return Err(format!("bandwidth.{}: rate_bpm must be positive", name));
+            }
+        }
+        // A pinned host must resolve to something, remove it to look it up instead.
+        if let Some((host, _)) = self
+            .target_connection
+            .dns_overrides
+            .iter()
+            .find(|(_, addrs)| addrs.is_empty())
+        {
+            return Err(format!("target_connection.dns_overrides: no addresses for {}", host));
+        }
+        Ok(())
+    }
 }
 
 /// Implement some functionality for a type.
@@ -114,18 +249,38 @@ impl Default for TunnelConfig {
                     idle_timeout: NO_TIMEOUT,
                     min_rate_bpm: 0,
                     max_rate_bpm: NO_BANDWIDTH_LIMIT,
+                    max_rate_action: MaxRateAction::default(),
+                    burst_bytes: None,
+                    rate_grace_period: default_rate_grace_period(),
+                    rate_window: default_rate_window(),
                 },
+                authentication: None,
+                proxy_protocol: None,
             },
             target_connection: TargetConnectionConfig {
                 dns_cache_ttl: NO_TIMEOUT,
-                allowed_targets: Regex::new(".*").expect("Bug: bad default regexp"),
+                nameservers: vec![],
+                dns_overrides: DnsOverrides::default(),
+                dns_cache_size: default_dns_cache_size(),
+                dns_negative_cache_ttl: default_dns_negative_cache_ttl(),
+                allowed_targets: allow_all_targets(),
                 connect_timeout: NO_TIMEOUT,
                 relay_policy: RelayPolicy {
                     idle_timeout: NO_TIMEOUT,
                     min_rate_bpm: 0,
                     max_rate_bpm: NO_BANDWIDTH_LIMIT,
+                    max_rate_action: MaxRateAction::default(),
+                    burst_bytes: None,
+                    rate_grace_period: default_rate_grace_period(),
+                    rate_window: default_rate_window(),
                 },
+                access_control: AccessControlList::default(),
+                destination_policy: DestinationPolicy::default(),
+                upstream_proxies: vec![],
             },
+            limits: ConcurrencyLimits::default(),
+            bandwidth: BandwidthLimits::default(),
+            drain_timeout: default_drain_timeout(),
         }
     }
 }
@@ -173,9 +328,13 @@ impl ProxyConfiguration {
             // https://doc.rust-lang.org/book/appendix-02-operators.html
             (@arg CONFIG: --config +takes_value "Configuration file")
             (@arg BIND: --bind +required +takes_value "Bind address, e.g. 0.0.0.0:8443")
+            (@arg METRICS_BIND: --("metrics-bind") +takes_value "Bind address of the Prometheus metrics endpoint, e.g. 127.0.0.1:9090")
+            (@arg ADMIN_BIND: --("admin-bind") +takes_value "Bind address of the admin API, e.g. 127.0.0.1:9091. Unauthenticated, keep it local")
             (@subcommand http =>
-                (about: "Run the tunnel in HTTP mode")
+                (about: "Run the tunnel in HTTP mode, SOCKS5 and (with --pk) TLS clients are detected on the same port")
                 (version: "0.0.1")
+                (@arg PKCS12: --pk +takes_value requires[PASSWORD] "pkcs12 filename, enables TLS")
+                (@arg PASSWORD: --password +takes_value requires[PKCS12] "Password for the pkcs12 file")
             )
             (@subcommand https =>
                 (about: "Run the tunnel in HTTPS mode")
@@ -191,6 +350,18 @@ impl ProxyConfiguration {
                 (version: "0.0.1")
                 (@arg DESTINATION: --destination -d +required +takes_value "Destination address, e.g. 10.0.0.2:8443")
             )
+            (@subcommand socks5 =>
+                (about: "Run the tunnel in SOCKS5 mode")
+                (version: "0.0.1")
+            )
+            (@subcommand sni =>
+                (about: "Run the tunnel in SNI mode, TLS connections are routed to the SNI host on port 443")
+                (version: "0.0.1")
+            )
+            (@subcommand transparent =>
+                (about: "Run the tunnel in transparent mode, connections redirected by iptables go to their original destination (Linux only)")
+                (version: "0.0.1")
+            )
         )
         .get_matches();
 
@@ -209,15 +380,21 @@ impl ProxyConfiguration {
         // Argmatches is Option type, which has is_some().
         // > Returns true if the option is a Some value.
         // https://doc.rust-lang.org/beta/core/option/enum.Option.html
-        let mode = if matches.subcommand_matches("http").is_some() {
+        let mode = if let Some(http) = matches.subcommand_matches("http") {
+            let identity = match (http.value_of("PKCS12"), http.value_of("PASSWORD")) {
+                (Some(pkcs12_file), Some(password)) => {
+                    Some(ProxyConfiguration::tls_identify_from_file(pkcs12_file, password)?)
+                }
+                _ => None,
+            };
             // If user has a subcommand that uses http mode
             // Crate info!
             // https://qiita.com/fujitayy/items/590145c0f4b4e7d06de7
             info!(
-                "Starting in HTTP mode: bind: {}, configuration: {:?}",
-                bind_address, config
+                "Starting in HTTP mode: TLS: {}, bind: {}, configuration: {:?}",
+                identity.is_some(), bind_address, config
             );
-            ProxyMode::HTTP
+            ProxyMode::HTTP(identity)
         } else if let Some(https) = matches.subcommand_matches("https") {
             let pkcs12_file = https
                 .value_of("PKCS12")
@@ -248,12 +425,30 @@ impl ProxyConfiguration {
                 destination, config
             );
             ProxyMode::TCP(destination)
+        } else if matches.subcommand_matches("socks5").is_some() {
+            info!(
+                "Starting in SOCKS5 mode: bind: {}, configuration: {:?}",
+                bind_address, config
+            );
+            ProxyMode::SOCKS5
+        } else if matches.subcommand_matches("sni").is_some() {
+            info!(
+                "Starting in SNI mode: bind: {}, configuration: {:?}",
+                bind_address, config
+            );
+            ProxyMode::SNI
+        } else if matches.subcommand_matches("transparent").is_some() {
+            info!(
+                "Starting in transparent mode: bind: {}, configuration: {:?}",
+                bind_address, config
+            );
+            ProxyMode::Transparent
         } else {
             // Indicates unreachable code.
             // This will always panic!.
             // https://doc.rust-lang.org/std/macro.unreachable.html
             // I send nits improvement PR :) https://github.com/xnuter/http-tunnel/pull/8 .
-            unreachable!("only http, https and tcp commands are supported")
+            unreachable!("only http, https, tcp, socks5, sni and transparent commands are supported")
         };
 
         // The match Control Flow Operator
@@ -276,6 +471,9 @@ impl ProxyConfiguration {
             .bind_address(bind_address)
             .mode(mode)
             .tunnel_config(tunnel_config)
+            .config_file(config.map(|c| c.to_string()))
+            .metrics_bind_address(matches.value_of("METRICS_BIND").map(|m| m.to_string()))
+            .admin_bind_address(matches.value_of("ADMIN_BIND").map(|a| a.to_string()))
             // Withoug any binding, we got an error at runtime.
             // > thread 'main' panicked at 'ProxyConfigurationBuilder failed: "`mode` must be initialized"', src/configuration.rs:108:14
             .build()
@@ -339,7 +537,7 @@ impl ProxyConfiguration {
             e
         })?;
 
-        let result: TunnelConfig = serde_yaml::from_slice(&yaml).map_err(|e| {
+        let result = TunnelConfig::from_yaml(&yaml).map_err(|e| {
             error!("Error parsing yaml {}: {}", filename, e);
             Error::from(ErrorKind::InvalidInput)
         })?;
@@ -370,4 +568,34 @@ impl ProxyConfiguration {
         // }
         Ok(result)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMAL_CONFIG: &str = "
+client_connection:
+  initiation_timeout: 10s
+  relay_policy: {idle_timeout: 30s, min_rate_bpm: 0, max_rate_bpm: 10000000}
+target_connection:
+  dns_cache_ttl: 60s
+  allowed_targets: \".*\"
+  connect_timeout: 5s
+  relay_policy: {idle_timeout: 30s, min_rate_bpm: 0, max_rate_bpm: 10000000}
+";
+
+    #[test]
+    fn minimal_config_is_valid() {
+        assert!(TunnelConfig::from_yaml(MINIMAL_CONFIG.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn empty_dns_override_is_rejected() {
+        let yaml = format!("{}  dns_overrides: {{internal.api: []}}\n", MINIMAL_CONFIG);
+        let error = TunnelConfig::from_yaml(yaml.as_bytes()).err().unwrap();
+        assert!(error.contains("internal.api"), "{}", error);
+
+        let yaml = format!("{}  dns_overrides: {{internal.api: [10.1.2.3]}}\n", MINIMAL_CONFIG);
+        assert!(TunnelConfig::from_yaml(yaml.as_bytes()).is_ok());
+    }
+}
@@ -0,0 +1,379 @@
+use futures::future::{BoxFuture, FutureExt, Shared};
+use log::debug;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io;
+
+/// A look-up result, with the TTL of the records if the resolver knows it.
+pub struct Resolved {
+    pub addrs: Vec<SocketAddr>,
+    pub ttl: Option<Duration>,
+}
+
+/// `io::Error` isn't `Clone`, but every waiter of a shared look-up gets the same result.
+type LookupResult = Result<Vec<SocketAddr>, Arc<io::Error>>;
+type SharedLookup = Shared<BoxFuture<'static, LookupResult>>;
+
+/// DNS cache bounded to `capacity` names, the least recently used one is evicted to make room.
+/// https://en.wikipedia.org/wiki/Cache_replacement_policies#Least_recently_used_(LRU)
+///
+/// Entries expire after the TTL of their records, capped by `max_ttl`.
+/// Failures (e.g. NXDOMAIN) are cached for `negative_ttl`, so clients can't make the proxy look up a bad name over and over.
+/// https://datatracker.ietf.org/doc/html/rfc2308
+///
+/// Concurrent look-ups of the same name are coalesced into one (single-flight),
+/// the first caller starts it and the others wait for its result.
+pub struct DnsCache {
+    capacity: usize,
+    max_ttl: Duration,
+    negative_ttl: Duration,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    negative_hits: AtomicU64,
+    misses: AtomicU64,
+    coalesced: AtomicU64,
+    evictions: AtomicU64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    // HashMap: A hash map implemented with quadratic probing and SIMD lookup.
+    // JP: quadratic probing 2次プロービング
+    // - https://www.geeksforgeeks.org/quadratic-probing-in-hashing/
+    // - > Quadratic probing is an open-addressing scheme where we look for i2‘th slot in i’th iteration 
+    // - > if the given hash value x collides in the hash table. 
+    //
+    // JP: SIMD lookup SIMDルックアップ
+    // https://doc.rust-lang.org/std/collections/struct.HashMap.html
+    // > By default, HashMap uses a hashing algorithm selected to provide resistance against HashDoS attacks. 
+    //
+    // HashDoS attacks: https://www.f5.com/services/resources/glossary/hash-dos-attack
+    // > By sending a single POST message filled with thousands of variables,
+    // > the hashing function would overload and a server could be tied up processing this single request for as long as an hour. 
+    // > This is a hash denial-of-service (DoS) attack.
+    //
+    // Rustのコレクション型まとめ (VecやHashMapなど) https://qiita.com/garkimasera/items/a6df4d1cd99bc5010a5e
+    // - Vec 可変長配列
+    // - VecDeque リングバッファによる両端キューです。
+    // - LinkedList: 各要素が前後の要素へのポインタを持つ連結リストです。
+    // - HashMap: キーと値をペアで記録してくれるもので、他の言語では連想配列や辞書型と呼ばれたりします。
+    entries: HashMap<String, CacheEntry>,
+    // last use -> name, the first one is the least recently used
+    recency: BTreeMap<u64, String>,
+    // incremented on every use, a logical clock
+    uses: u64,
+    in_flight: HashMap<String, SharedLookup>,
+}
+
+struct CacheEntry {
+    result: LookupResult,
+    // `None` if the TTL is too long to be represented
+    expires: Option<Instant>,
+    last_use: u64,
+}
+
+/// Live counts for monitoring.
+#[derive(Serialize, Clone, Debug)]
+pub struct DnsCacheSnapshot {
+    pub entries: usize,
+    pub hits: u64,
+    pub negative_hits: u64,
+    pub misses: u64,
+    pub coalesced: u64,
+    pub evictions: u64,
+}
+
+impl DnsCache {
+    pub fn new(capacity: usize, max_ttl: Duration, negative_ttl: Duration) -> Self {
+        Self {
+            capacity,
+            max_ttl,
+            negative_ttl,
+            state: Mutex::new(CacheState::default()),
+            hits: AtomicU64::new(0),
+            negative_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the cached addresses of `name`, or runs `lookup` and caches its result.
+    /// The second value tells whether the result was cached.
+    pub async fn get_or_resolve<F>(
+        self: &Arc<Self>,
+        name: &str,
+        lookup: F,
+    ) -> (io::Result<Vec<SocketAddr>>, bool)
+    where
+        F: FnOnce() -> BoxFuture<'static, io::Result<Resolved>>,
+    {
+        let shared_lookup = {
+            let mut state = self.state.lock().expect("Poisoned lock");
+
+            if let Some(result) = state.get(name, Instant::now()) {
+                match result {
+                    Ok(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+                    Err(_) => self.negative_hits.fetch_add(1, Ordering::Relaxed),
+                };
+                return (result.map_err(unshared), true);
+            }
+
+            match state.in_flight.get(name) {
+                Some(shared_lookup) => {
+                    self.coalesced.fetch_add(1, Ordering::Relaxed);
+                    shared_lookup.clone()
+                }
+                None => {
+                    self.misses.fetch_add(1, Ordering::Relaxed);
+                    // Whoever polls it caches the result, so it's done even if the first caller gives up (e.g. on a timeout).
+                    let cache = self.clone();
+                    let key = name.to_string();
+                    let lookup = lookup();
+                    let shared_lookup = async move {
+                        let result = lookup.await;
+                        cache.complete(key, result)
+                    }
+                    .boxed()
+                    .shared();
+                    state.in_flight.insert(name.to_string(), shared_lookup.clone());
+                    shared_lookup
+                }
+            }
+        };
+
+        (shared_lookup.await.map_err(unshared), false)
+    }
+
+    pub fn snapshot(&self) -> DnsCacheSnapshot {
+        let state = self.state.lock().expect("Poisoned lock");
+        DnsCacheSnapshot {
+            entries: state.entries.len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            negative_hits: self.negative_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    fn complete(&self, name: String, result: io::Result<Resolved>) -> LookupResult {
+        let (result, ttl) = match result {
+            Ok(resolved) => {
+                let ttl = resolved.ttl.map_or(self.max_ttl, |ttl| ttl.min(self.max_ttl));
+                (Ok(resolved.addrs), ttl)
+            }
+            Err(e) => (Err(Arc::new(e)), self.negative_ttl),
+        };
+
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state.in_flight.remove(&name);
+
+        if self.capacity > 0 && !ttl.is_zero() {
+            if !state.entries.contains_key(&name) && state.entries.len() >= self.capacity {
+                if let Some(evicted) = state.evict() {
+                    debug!("Evicted DNS cache entry {}", evicted);
+                    self.evictions.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            state.insert(name, result.clone(), Instant::now().checked_add(ttl));
+        }
+
+        result
+    }
+}
+
+impl CacheState {
+    /// Expired entries are dropped on access, or evicted as the least recently used ones.
+    fn get(&mut self, name: &str, now: Instant) -> Option<LookupResult> {
+        let entry = self.entries.get(name)?;
+        if matches!(entry.expires, Some(expires) if expires <= now) {
+            self.remove(name);
+            return None;
+        }
+
+        self.uses += 1;
+        let uses = self.uses;
+        let entry = self.entries.get_mut(name).expect("Checked above");
+        self.recency.remove(&entry.last_use);
+        self.recency.insert(uses, name.to_string());
+        entry.last_use = uses;
+        Some(entry.result.clone())
+    }
+
+    fn insert(&mut self, name: String, result: LookupResult, expires: Option<Instant>) {
+        self.remove(&name);
+        self.uses += 1;
+        self.recency.insert(self.uses, name.clone());
+        self.entries.insert(
+            name,
+            CacheEntry {
+                result,
+                expires,
+                last_use: self.uses,
+            },
+        );
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some(entry) = self.entries.remove(name) {
+            self.recency.remove(&entry.last_use);
+        }
+    }
+
+    /// Removes the least recently used entry.
+    fn evict(&mut self) -> Option<String> {
+        let (_, name) = self.recency.pop_first()?;
+        self.entries.remove(&name);
+        Some(name)
+    }
+}
+
+fn unshared(e: Arc<io::Error>) -> io::Error {
+    io::Error::new(e.kind(), e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::join_all;
+    use std::sync::atomic::AtomicUsize;
+
+    fn addr(last: u8) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, last], 443))
+    }
+
+    /// Resolves to `addr`, counting the calls.
+    fn resolve(
+        calls: &Arc<AtomicUsize>,
+        addr: SocketAddr,
+    ) -> impl FnOnce() -> BoxFuture<'static, io::Result<Resolved>> {
+        let calls = calls.clone();
+        move || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async move {
+                Ok(Resolved {
+                    addrs: vec![addr],
+                    ttl: None,
+                })
+            }
+            .boxed()
+        }
+    }
+
+    fn fail(calls: &Arc<AtomicUsize>) -> impl FnOnce() -> BoxFuture<'static, io::Result<Resolved>> {
+        let calls = calls.clone();
+        move || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async { Err(io::Error::from(io::ErrorKind::AddrNotAvailable)) }.boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_is_evicted() {
+        let cache = Arc::new(DnsCache::new(2, Duration::from_secs(300), Duration::from_secs(30)));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        cache.get_or_resolve("a", resolve(&calls, addr(1))).await.0.unwrap();
+        cache.get_or_resolve("b", resolve(&calls, addr(2))).await.0.unwrap();
+        // `a` is used again, so `b` is the least recently used one.
+        assert!(cache.get_or_resolve("a", resolve(&calls, addr(1))).await.1);
+        cache.get_or_resolve("c", resolve(&calls, addr(3))).await.0.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(cache.snapshot().evictions, 1);
+        assert_eq!(cache.snapshot().entries, 2);
+
+        let (result, cached) = cache.get_or_resolve("a", resolve(&calls, addr(1))).await;
+        assert_eq!((result.unwrap(), cached), (vec![addr(1)], true));
+        let (result, cached) = cache.get_or_resolve("c", resolve(&calls, addr(3))).await;
+        assert_eq!((result.unwrap(), cached), (vec![addr(3)], true));
+        let (result, cached) = cache.get_or_resolve("b", resolve(&calls, addr(2))).await;
+        assert_eq!((result.unwrap(), cached), (vec![addr(2)], false));
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        // `a` was used before `c`.
+        assert_eq!(cache.snapshot().evictions, 2);
+        assert!(cache.get_or_resolve("c", resolve(&calls, addr(3))).await.1);
+    }
+
+    #[tokio::test]
+    async fn failures_expire_after_negative_ttl() {
+        let cache = Arc::new(DnsCache::new(10, Duration::from_secs(300), Duration::from_secs(30)));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let (result, cached) = cache.get_or_resolve("missing", fail(&calls)).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::AddrNotAvailable);
+        assert!(!cached);
+        let (result, cached) = cache.get_or_resolve("missing", fail(&calls)).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::AddrNotAvailable);
+        assert!(cached);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.snapshot().negative_hits, 1);
+
+        let mut state = cache.state.lock().unwrap();
+        let now = Instant::now();
+        assert!(matches!(state.get("missing", now + Duration::from_secs(29)), Some(Err(_))));
+        assert!(state.get("missing", now + Duration::from_secs(31)).is_none());
+        // Dropped on access.
+        assert!(state.entries.is_empty());
+        assert!(state.recency.is_empty());
+    }
+
+    #[tokio::test]
+    async fn ttl_is_capped() {
+        let cache = Arc::new(DnsCache::new(10, Duration::from_secs(60), Duration::from_secs(30)));
+        let lookup = || {
+            async {
+                Ok(Resolved {
+                    addrs: vec![addr(1)],
+                    ttl: Some(Duration::from_secs(86400)),
+                })
+            }
+            .boxed()
+        };
+        cache.get_or_resolve("a", lookup).await.0.unwrap();
+
+        let mut state = cache.state.lock().unwrap();
+        let now = Instant::now();
+        assert!(state.get("a", now + Duration::from_secs(59)).is_some());
+        assert!(state.get("a", now + Duration::from_secs(61)).is_none());
+    }
+
+    #[tokio::test]
+    async fn concurrent_lookups_are_coalesced() {
+        let cache = Arc::new(DnsCache::new(10, Duration::from_secs(300), Duration::from_secs(30)));
+        let calls = Arc::new(AtomicUsize::new(0));
+        // Keeps the look-up in flight until all of them have started.
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let mut released = Some(released);
+
+        let lookups = (0..10).map(|_| {
+            let calls = calls.clone();
+            let released = released.take();
+            cache.get_or_resolve("a", move || {
+                calls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    released.expect("Looked up more than once").await.unwrap();
+                    Ok(Resolved {
+                        addrs: vec![addr(1)],
+                        ttl: None,
+                    })
+                }
+                .boxed()
+            })
+        });
+        // Runs once every look-up is waiting.
+        tokio::spawn(async move { release.send(()).unwrap() });
+        for (result, cached) in join_all(lookups).await {
+            assert_eq!(result.unwrap(), vec![addr(1)]);
+            assert!(!cached);
+        }
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        let snapshot = cache.snapshot();
+        assert_eq!((snapshot.misses, snapshot.coalesced), (1, 9));
+        assert!(cache.state.lock().unwrap().in_flight.is_empty());
+    }
+}
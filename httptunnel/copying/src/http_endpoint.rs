@@ -0,0 +1,95 @@
+use log::{debug, error};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+const MAX_REQUEST_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A response of a local endpoint (metrics, admin API).
+pub struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    pub fn new(status: &'static str, content_type: &'static str, body: String) -> Self {
+        Self {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    pub fn json<T: Serialize>(status: &'static str, value: &T) -> Self {
+        let body = serde_json::to_string(value).expect("JSON serializtion failed");
+        Self::new(status, "application/json", body)
+    }
+
+    pub fn not_found() -> Self {
+        Self::new("404 Not Found", "text/plain", "Not Found\n".to_string())
+    }
+}
+
+/// A minimal HTTP/1.x server: one request per connection, no request body.
+/// Good enough for scrapers and `curl`, it must not be exposed to untrusted networks.
+pub async fn serve_endpoint<F>(listener: TcpListener, handler: F)
+where
+    F: Fn(&str, &str) -> Response + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    if timeout(REQUEST_TIMEOUT, handle(stream, &*handler)).await.is_err() {
+                        debug!("Endpoint request timed out");
+                    }
+                });
+            }
+            Err(e) => error!("Failed TCP handshake{}", e),
+        }
+    }
+}
+
+async fn handle<F: Fn(&str, &str) -> Response>(mut stream: TcpStream, handler: &F) {
+    let mut request = vec![];
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => request.extend_from_slice(&buffer[..n]),
+        }
+        if request.len() > MAX_REQUEST_SIZE {
+            return;
+        }
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let response = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(path)) => handler(method, path),
+        _ => Response::new("400 Bad Request", "text/plain", "Bad Request\n".to_string()),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+
+    if let Err(e) = stream.write_all(head.as_bytes()).await {
+        debug!("Failed to write response: {}", e);
+        return;
+    }
+    if let Err(e) = stream.write_all(response.body.as_bytes()).await {
+        debug!("Failed to write response: {}", e);
+    }
+    let _ = stream.shutdown().await;
+}
@@ -4,7 +4,10 @@ use core::fmt;
 use regex::Regex;
 use log::debug;
 use std::fmt::Write;
+use std::net::{Ipv6Addr, SocketAddr};
 
+use crate::access_control::{AccessControlList, AccessDecision, AccessRequest};
+use crate::authentication::AuthenticationConfig;
 use crate::proxy_target::Nugget;
 use crate::tunnel::{TunnelCtx, EstablishTunnelResult, TunnelTarget};
 
@@ -22,6 +25,26 @@ const MAX_HTTP_REQUEST_SIZE: usize = 16384;
 
 const REQUEST_END_MARKER: &[u8] = b"\r\n\r\n";
 
+/// A reasonable value to limit the number of headers a client can send.
+const MAX_HTTP_HEADERS: usize = 64;
+
+const HTTP_DEFAULT_PORT: u16 = 80;
+
+/// Headers meaningful only for a single transport-level connection, i.e. between the client and the proxy.
+/// They must not be forwarded to the target.
+/// https://datatracker.ietf.org/doc/html/rfc2616#section-13.5.1
+/// `Transfer-Encoding` is kept as the body is relayed without any changes.
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Upgrade",
+];
+
 /// (Original comments)
 /// HTTP/1.1 request representation
 /// Supports only `CONNECT` method, unless the `plain_text` feature is enabled
@@ -31,17 +54,369 @@ struct HttpConnectRequest {
     // (Original comments)
     // out of scope of this demo, but let's put it here for extensibility
     // e.g. Authorization/Policies headers
-    // headers: Vec<(String, String)>,
+    //
+    // (My comments)
+    // Read by the authentication (`Proxy-Authorization`) and copied into plain text requests.
+    // Header names are kept as they were sent, look them up with `eq_ignore_ascii_case`.
+    // > Each header field consists of a case-insensitive field name followed by a colon
+    // https://datatracker.ietf.org/doc/html/rfc7230#section-3.2
+    headers: Vec<(String, String)>,
 }
 
 impl HttpConnectRequest {
+    /// Parses the request head, i.e. everything up to and including the empty line.
+    ///
+    /// The format is described in RFC 7230
+    /// https://datatracker.ietf.org/doc/html/rfc7230#section-3
+    /// > HTTP-message = start-line *( header-field CRLF ) CRLF [ message-body ]
     pub fn parse(http_request: &[u8]) -> Result<Self, EstablishTunnelResult> {
-        // TODO: http_request parser
+        HttpConnectRequest::precondition_size(http_request)?;
+        HttpConnectRequest::precondition_legal_characters(http_request)?;
+
+        let http_request_as_string =
+            std::str::from_utf8(http_request).expect("Contains only ASCII");
+
+        let mut lines = http_request_as_string.split("\r\n");
+
+        let (method, uri, version, has_nugget) = HttpConnectRequest::parse_request_line(
+            lines
+                .next()
+                .expect("At least a single line is present at this point"),
+        )?;
+
+        let headers = HttpConnectRequest::parse_headers(&mut lines)?;
+
+        if has_nugget {
+            HttpConnectRequest::forward_request(method, uri, version, headers)
+        } else {
+            Ok(Self {
+                uri: uri.to_string(),
+                nugget: None,
+                headers,
+            })
+        }
+    }
+
+    /// A plain text request (e.g. `GET http://host/path HTTP/1.1`) is sent to the target as is,
+    /// except that the absolute URI is rewritten to the origin form and hop-by-hop headers are dropped.
+    /// https://datatracker.ietf.org/doc/html/rfc7230#section-5.3.2
+    /// > When making a request to a proxy, other than a CONNECT or server-wide OPTIONS request, a client MUST send the target URI in absolute-form.
+    ///
+    /// The rewritten request becomes the nugget, so the target responds to the client directly.
+    fn forward_request(
+        method: &str,
+        uri: &str,
+        version: &str,
+        headers: Vec<(String, String)>,
+    ) -> Result<Self, EstablishTunnelResult> {
+        let (target, authority, origin_form) = parse_absolute_uri(uri).ok_or_else(|| {
+            debug!("Bad absolute URI `{}`, expected `http://host[:port]/path`", uri);
+            EstablishTunnelResult::BadRequest
+        })?;
+
+        let mut request = String::new();
+        request
+            .write_fmt(format_args!("{} {} {}\r\n", method, origin_form, version))
+            .expect("Writing to a String never fails");
+
+        // https://datatracker.ietf.org/doc/html/rfc7230#section-6.1
+        // > a proxy or gateway MUST parse a received Connection header field before a message is forwarded and,
+        // > for each connection-option in this field, remove any header field(s) from the message with the same name
+        // > as the connection-option
+        let connection_options: Vec<&str> = headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Connection"))
+            .flat_map(|(_, value)| value.split(','))
+            .map(str::trim)
+            .filter(|option| !option.is_empty())
+            .collect();
+
+        let mut has_host = false;
+        for (name, value) in headers.iter() {
+            if HOP_BY_HOP_HEADERS
+                .iter()
+                .chain(connection_options.iter())
+                .any(|hop_by_hop| name.eq_ignore_ascii_case(hop_by_hop))
+            {
+                continue;
+            }
+            has_host |= name.eq_ignore_ascii_case("Host");
+            request
+                .write_fmt(format_args!("{}: {}\r\n", name, value))
+                .expect("Writing to a String never fails");
+        }
+
+        if !has_host {
+            request
+                .write_fmt(format_args!("Host: {}\r\n", authority))
+                .expect("Writing to a String never fails");
+        }
+
+        // The tunnel is bound to a single target, so keep-alive can't be allowed:
+        // the next request of the client may go to a different host.
+        request.push_str("Connection: close\r\n\r\n");
+
         Ok(Self {
-            uri: String::from("aaa"),
-            nugget: None,
+            uri: target,
+            nugget: Some(Nugget::new(request)),
+            headers,
         })
     }
+
+    /// Returns the value of the first header with the given name.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn precondition_size(http_request: &[u8]) -> Result<(), EstablishTunnelResult> {
+        if http_request.len() >= MAX_HTTP_REQUEST_SIZE {
+            debug!(
+                "Bad request header. Size {} exceeds limit {}",
+                http_request.len(),
+                MAX_HTTP_REQUEST_SIZE
+            );
+            Err(EstablishTunnelResult::BadRequest)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn precondition_legal_characters(http_request: &[u8]) -> Result<(), EstablishTunnelResult> {
+        for b in http_request {
+            match b {
+                // (Original comments)
+                // non-ascii characters don't make sense in this context
+                32..=126 | 9 | 10 | 13 => {}
+                _ => {
+                    debug!("Bad request header. Illegal character: {:#04x}", b);
+                    return Err(EstablishTunnelResult::BadRequest);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// https://datatracker.ietf.org/doc/html/rfc7230#section-3.1.1
+    /// > request-line = method SP request-target SP HTTP-version CRLF
+    fn parse_request_line(
+        request_line: &str,
+    ) -> Result<(&str, &str, &str, bool), EstablishTunnelResult> {
+        let request_line_items = request_line.split(' ').collect::<Vec<&str>>();
+        HttpConnectRequest::precondition_well_formed(request_line, &request_line_items)?;
+
+        let method = request_line_items[0];
+        let uri = request_line_items[1];
+        let version = request_line_items[2];
+
+        HttpConnectRequest::check_version(version)?;
+        let has_nugget = HttpConnectRequest::check_method(method)?;
+        if !has_nugget {
+            HttpConnectRequest::check_authority(uri)?;
+        }
+
+        Ok((method, uri, version, has_nugget))
+    }
+
+    fn precondition_well_formed(
+        request_line: &str,
+        request_line_items: &[&str],
+    ) -> Result<(), EstablishTunnelResult> {
+        if request_line_items.len() != 3
+            || request_line_items.iter().any(|item| item.is_empty())
+            || request_line.contains(['\r', '\n', '\t'])
+        {
+            debug!("Bad request line: `{:?}`", request_line);
+            Err(EstablishTunnelResult::BadRequest)
+        } else if !is_token(request_line_items[0]) {
+            debug!("Bad method: `{:?}`", request_line_items[0]);
+            Err(EstablishTunnelResult::BadRequest)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// https://datatracker.ietf.org/doc/html/rfc7230#section-2.6
+    /// > HTTP-version  = HTTP-name "/" DIGIT "." DIGIT
+    ///
+    /// Some clients still send `CONNECT` as `HTTP/1.0`, so both versions are accepted.
+    fn check_version(version: &str) -> Result<(), EstablishTunnelResult> {
+        if version != "HTTP/1.1" && version != "HTTP/1.0" {
+            debug!("Bad version {}", version);
+            Err(EstablishTunnelResult::BadRequest)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns `true` if the request has to be forwarded to the target as a nugget.
+    #[cfg(not(feature = "plain_text"))]
+    fn check_method(method: &str) -> Result<bool, EstablishTunnelResult> {
+        if method != "CONNECT" {
+            debug!("Not allowed method {}", method);
+            Err(EstablishTunnelResult::OperationNotAllowed)
+        } else {
+            Ok(false)
+        }
+    }
+
+    // cfg: Configuration conditional checks are possible through two different operators
+    // https://doc.rust-lang.org/rust-by-example/attribute/cfg.html
+    #[cfg(feature = "plain_text")]
+    fn check_method(method: &str) -> Result<bool, EstablishTunnelResult> {
+        Ok(method != "CONNECT")
+    }
+
+    /// `CONNECT` accepts only the authority form of the request target.
+    /// https://datatracker.ietf.org/doc/html/rfc7230#section-5.3.3
+    /// > authority-form = authority
+    /// > A client MUST send only the target URI's authority component (excluding any userinfo and its "@" delimiter)
+    fn check_authority(uri: &str) -> Result<(), EstablishTunnelResult> {
+        if parse_authority(uri).is_none() {
+            debug!("Bad authority `{}`, expected `host:port`", uri);
+            Err(EstablishTunnelResult::BadRequest)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// https://datatracker.ietf.org/doc/html/rfc7230#section-3.2
+    /// > header-field = field-name ":" OWS field-value OWS
+    fn parse_headers<'a, I: Iterator<Item = &'a str>>(
+        lines: &mut I,
+    ) -> Result<Vec<(String, String)>, EstablishTunnelResult> {
+        let mut headers = vec![];
+
+        for line in lines.by_ref() {
+            if line.is_empty() {
+                break;
+            }
+
+            if headers.len() >= MAX_HTTP_HEADERS {
+                debug!("Bad request. More than {} headers", MAX_HTTP_HEADERS);
+                return Err(EstablishTunnelResult::BadRequest);
+            }
+
+            // > A server that receives an obs-fold in a request message ... MUST either reject the message by sending a 400 (Bad Request)
+            // https://datatracker.ietf.org/doc/html/rfc7230#section-3.2.4
+            if line.starts_with([' ', '\t']) || line.contains(['\r', '\n']) {
+                debug!("Bad header line: `{:?}`", line);
+                return Err(EstablishTunnelResult::BadRequest);
+            }
+
+            let (name, value) = match line.find(':') {
+                Some(colon) => (&line[..colon], &line[colon + 1..]),
+                None => {
+                    debug!("Bad header line, no colon: `{:?}`", line);
+                    return Err(EstablishTunnelResult::BadRequest);
+                }
+            };
+
+            // > No whitespace is allowed between the header field-name and colon.
+            if !is_token(name) {
+                debug!("Bad header name: `{:?}`", name);
+                return Err(EstablishTunnelResult::BadRequest);
+            }
+
+            headers.push((
+                name.to_string(),
+                value.trim_matches([' ', '\t']).to_string(),
+            ));
+        }
+
+        // The head must end right after the empty line, the body (if any) is not part of it.
+        if lines.any(|line| !line.is_empty()) {
+            debug!("Bad request. Data after the end of the request head");
+            return Err(EstablishTunnelResult::BadRequest);
+        }
+
+        Ok(headers)
+    }
+}
+
+/// https://datatracker.ietf.org/doc/html/rfc7230#section-3.2.6
+/// > token = 1*tchar
+/// > tchar = "!" / "#" / "$" / "%" / "&" / "'" / "*" / "+" / "-" / "." / "^" / "_" / "`" / "|" / "~" / DIGIT / ALPHA
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value.bytes().all(|b| {
+            b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+        })
+}
+
+/// Splits `http://host[:port]/path?query` into the target (`host:port`), the authority as sent
+/// and the origin form (`/path?query`), returns `None` if it's not a valid `http` URI.
+/// https://datatracker.ietf.org/doc/html/rfc7230#section-2.7.1
+/// > http-URI = "http:" "//" authority path-abempty [ "?" query ] [ "#" fragment ]
+fn parse_absolute_uri(uri: &str) -> Option<(String, &str, String)> {
+    const SCHEME: &str = "http://";
+    if uri.len() <= SCHEME.len() || !uri[..SCHEME.len()].eq_ignore_ascii_case(SCHEME) {
+        return None;
+    }
+
+    let rest = &uri[SCHEME.len()..];
+    let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (authority, path) = (&rest[..authority_end], &rest[authority_end..]);
+
+    // > If the target URI's path component is empty, the client MUST send "/" as the path
+    let origin_form = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{}", path)
+    };
+
+    // The port is optional in http URIs, unlike in the authority form of `CONNECT`.
+    let has_port = match authority.rfind(':') {
+        Some(colon) => !authority[colon..].contains(']'),
+        None => false,
+    };
+    let target = if has_port {
+        authority.to_string()
+    } else {
+        format!("{}:{}", authority, HTTP_DEFAULT_PORT)
+    };
+
+    parse_authority(&target)?;
+
+    Some((target, authority, origin_form))
+}
+
+/// Splits `host:port` into its parts, returns `None` if it's not a valid authority.
+/// https://datatracker.ietf.org/doc/html/rfc3986#section-3.2
+/// > authority = [ userinfo "@" ] host [ ":" port ]
+fn parse_authority(authority: &str) -> Option<(&str, u16)> {
+    let colon = authority.rfind(':')?;
+    let (host, port) = (&authority[..colon], &authority[colon + 1..]);
+
+    if port.is_empty() || port.len() > 5 || !port.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let port = port.parse::<u16>().ok().filter(|p| *p != 0)?;
+
+    let valid_host = if host.starts_with('[') && host.ends_with(']') {
+        // IP-literal = "[" IPv6address "]"
+        host[1..host.len() - 1].parse::<Ipv6Addr>().is_ok()
+    } else {
+        is_valid_host_name(host)
+    };
+
+    if valid_host {
+        Some((host, port))
+    } else {
+        None
+    }
+}
+
+/// reg-name or IPv4address, userinfo is not allowed.
+/// Also for the host names of the other handshakes (SOCKS5 domains, SNI), which become `host:port` targets as well.
+pub fn is_valid_host_name(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_')
 }
 
 /// Codec to extract `HTTP/1.1 CONNECT` requests and build a corresponding `HTTP` response.
@@ -51,6 +426,64 @@ impl HttpConnectRequest {
 pub struct HttpTunnelCodec {
     tunnel_ctx: TunnelCtx,
     enabled_targets: Regex,
+    // builder(default): the field can be omitted, then Default::default() (None) is used.
+    // https://docs.rs/derive_builder/0.9.0/derive_builder/#default-values
+    #[builder(default)]
+    authentication: Option<AuthenticationConfig>,
+    #[builder(default)]
+    access_control: AccessControlList,
+    #[builder(default)]
+    client_addr: Option<SocketAddr>,
+}
+
+impl HttpTunnelCodec {
+    /// Checks `Proxy-Authorization`, if the authentication is enabled.
+    /// Returns the authenticated user.
+    fn authenticate(
+        &self,
+        request: &HttpConnectRequest,
+    ) -> Result<Option<String>, EstablishTunnelResult> {
+        let authentication = match &self.authentication {
+            None => return Ok(None),
+            Some(authentication) => authentication,
+        };
+
+        match request
+            .header("Proxy-Authorization")
+            .and_then(|credentials| authentication.credentials.authenticate(credentials))
+        {
+            Some(user) => {
+                debug!("Authenticated `{}`, CTX={}", user, self.tunnel_ctx);
+                Ok(Some(user))
+            }
+            None => {
+                debug!("Proxy authentication failed, CTX={}", self.tunnel_ctx);
+                Err(EstablishTunnelResult::ProxyAuthenticationRequired)
+            }
+        }
+    }
+
+    /// Checks the access rules before connecting.
+    /// Rules on the target network can be decided only after the DNS look-up, so `SimpleTcpConnector` checks them again.
+    fn check_access(&self, target: &str, user: Option<&str>) -> Result<(), EstablishTunnelResult> {
+        let mut request = AccessRequest::from_target(target).ok_or_else(|| {
+            debug!("Bad target `{}`, CTX={}", target, self.tunnel_ctx);
+            EstablishTunnelResult::BadRequest
+        })?;
+        request.user = user;
+        request.client = self.client_addr.map(|addr| addr.ip());
+
+        match self.access_control.check(&request) {
+            AccessDecision::Deny => {
+                debug!(
+                    "Target `{}` is denied by the access rules, CTX={}",
+                    target, self.tunnel_ctx
+                );
+                Err(EstablishTunnelResult::Forbidden)
+            }
+            AccessDecision::Allow | AccessDecision::Undecided => Ok(()),
+        }
+    }
 }
 
 // Without this definition, we got an error: error[E0277]: the trait bound `HttpTunnelCodec: Decoder` is not satisfied
@@ -64,12 +497,30 @@ impl Decoder for HttpTunnelCodec {
     // > It is similar to a Vec<u8> but with less copies and allocations.
     // https://docs.rs/bytes/0.4.12/bytes/struct.BytesMut.html
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if !got_http_request(&src) {
-            return Ok(None);
-        }
+        let request_size = match http_request_size(src) {
+            Some(size) => size,
+            None if src.len() >= MAX_HTTP_REQUEST_SIZE => {
+                debug!(
+                    "Bad request header. Size {} exceeds limit {}, CTX={}",
+                    src.len(),
+                    MAX_HTTP_REQUEST_SIZE,
+                    self.tunnel_ctx
+                );
+                return Err(EstablishTunnelResult::BadRequest);
+            }
+            None => return Ok(None),
+        };
 
-        match HttpConnectRequest::parse(&src) {
+        // Take only the request head, bytes pipelined after it stay in the buffer.
+        // split_to: Splits the bytes into two at the given index.
+        // https://docs.rs/bytes/1.0.1/bytes/struct.BytesMut.html#method.split_to
+        let http_request = src.split_to(request_size);
+
+        match HttpConnectRequest::parse(&http_request) {
             Ok(parsed_request) => {
+                // Authenticate first, so the client doesn't learn which targets are allowed.
+                let user = self.authenticate(&parsed_request)?;
+
                 if !self.enabled_targets.is_match(&parsed_request.uri) {
                     debug!(
                         "Target `{}` is not allowed. Allowed: `{}`, CTX={}",
@@ -77,10 +528,21 @@ impl Decoder for HttpTunnelCodec {
                     );
                     Err(EstablishTunnelResult::Forbidden)
                 } else {
+                    self.check_access(&parsed_request.uri, user.as_deref())?;
+
+                    // A plain text request may be followed by its body,
+                    // everything the client has sent so far goes to the target.
+                    let nugget = parsed_request.nugget.map(|nugget| {
+                        let mut data = nugget.data().to_vec();
+                        data.extend_from_slice(&src.split());
+                        Nugget::new(data)
+                    });
+
                     Ok(Some(
                         HttpTunnelTargetBuilder::default()
                             .target(parsed_request.uri)
-                            .nugget(parsed_request.nugget)
+                            .nugget(nugget)
+                            .user(user)
                             .build()
                             .expect("HttpTunnelTargetBuilder failed")
                     ))
@@ -89,6 +551,16 @@ impl Decoder for HttpTunnelCodec {
             Err(e) => Err(e)
         }
     }
+
+    /// The client closed the connection in the middle of a request.
+    /// Without this the default implementation reports an I/O error, which would become `BadGateway`.
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(buf)? {
+            Some(frame) => Ok(Some(frame)),
+            None if buf.is_empty() => Ok(None),
+            None => Err(EstablishTunnelResult::BadRequest),
+        }
+    }
 }
 
 // Without this implementation, we got an error: error[E0277]: the trait bound `HttpTunnelCodec: Encoder<EstablishTunnelResult>` is not satisfied
@@ -109,36 +581,48 @@ impl Encoder<EstablishTunnelResult> for HttpTunnelCodec {
             }
             EstablishTunnelResult::BadRequest => (400, "BAD_REQUEST"),
             EstablishTunnelResult::Forbidden => (403, "FORBIDDEN"),
+            EstablishTunnelResult::ProxyAuthenticationRequired => {
+                (407, "PROXY_AUTHENTICATION_REQUIRED")
+            }
             EstablishTunnelResult::OperationNotAllowed => (405, "NOT_ALLOWED"),
             EstablishTunnelResult::RequestTimeout => (408, "TIMEOUT"),
             EstablishTunnelResult::TooManyRequests => (429, "TOO_MANY_REQUESTS"),
             EstablishTunnelResult::ServerError => (500, "SERVER_ERROR"),
             EstablishTunnelResult::BadGateway => (502, "BAD_GATEWAY"),
             EstablishTunnelResult::GatewayTimeout => (504, "GATEWAY_TIMEOUT"),
+            EstablishTunnelResult::TlsHandshakeFailed => {
+                // there is no TLS session to send a response over
+                return Ok(());
+            }
         };
 
         // use std::fmt::Write; 
         // This trait provides method write_fmt().
         // https://doc.rust-jp.rs/the-rust-programming-language-ja/1.6/std/fmt/trait.Write.html#method.write_fmt
-        dst.write_fmt(format_args!("HTTP/1.1 {} {}\r\n\r\n", code as u32, message))
+        dst.write_fmt(format_args!("HTTP/1.1 {} {}\r\n", code as u32, message))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::Other))?;
+
+        // https://datatracker.ietf.org/doc/html/rfc7235#section-3.2
+        // > The proxy MUST send a Proxy-Authenticate header field containing a challenge applicable to that proxy for the target resource.
+        if let (407, Some(authentication)) = (code, &self.authentication) {
+            dst.write_fmt(format_args!(
+                "Proxy-Authenticate: Basic realm=\"{}\"\r\n",
+                authentication.realm
+            ))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::Other))?;
+        }
+
+        dst.write_fmt(format_args!("\r\n"))
             .map_err(|_| std::io::Error::from(std::io::ErrorKind::Other))
     }
 }
 
-#[cfg(not(feature = "plain_text"))]
-fn got_http_request(buffer: &BytesMut) -> bool {
-    buffer.len() >= MAX_HTTP_REQUEST_SIZE || buffer.ends_with(REQUEST_END_MARKER)
-}
-
-// cfg: Configuration conditional checks are possible through two different operators
-// https://doc.rust-lang.org/rust-by-example/attribute/cfg.html
-#[cfg(feature = "plain_text")]
-fn got_http_request(buffer: &BytesMut) -> bool {
-    buffer.len() >= MAX_HTTP_REQUEST_SIZE
-        || buffer  
-            .windows(REQUEST_END_MARKER.len())
-            .find(|w| *w == REQUEST_END_MARKER)
-            .is_some()
+/// Returns the size of the request head including the empty line, if it has been received in full.
+fn http_request_size(buffer: &BytesMut) -> Option<usize> {
+    buffer
+        .windows(REQUEST_END_MARKER.len())
+        .position(|w| w == REQUEST_END_MARKER)
+        .map(|position| position + REQUEST_END_MARKER.len())
 }
 
 /// Trait std::cmp::Eq 
@@ -157,6 +641,8 @@ fn got_http_request(buffer: &BytesMut) -> bool {
 pub struct HttpTunnelTarget {
     pub target: String,
     pub nugget: Option<Nugget>,
+    #[builder(default)]
+    pub user: Option<String>,
 }
 
 
@@ -178,6 +664,10 @@ impl TunnelTarget for HttpTunnelTarget {
             .as_ref()
             .expect("Cannot use this method without checking `has_nugget`")
     }
+
+    fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
 }
 
 // Without this implementation, we got an error: error[E0277]: `HttpTunnelTarget` doesn't implement `std::fmt::Display`
@@ -192,7 +682,275 @@ impl From<Error> for EstablishTunnelResult {
     fn from(e: Error) -> Self {
         match e.kind() {
             ErrorKind::TimedOut => EstablishTunnelResult::GatewayTimeout,
+            // the connector refused the target, e.g. by the access rules
+            ErrorKind::PermissionDenied => EstablishTunnelResult::Forbidden,
             _ => EstablishTunnelResult::BadGateway,
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::Credentials;
+    use sha1::{Digest, Sha1};
+    use std::sync::Arc;
+
+    #[test]
+    fn parse_requests() {
+        let too_many_headers = format!(
+            "CONNECT a.com:443 HTTP/1.1\r\n{}\r\n",
+            "X-Header: value\r\n".repeat(MAX_HTTP_HEADERS + 1)
+        );
+        let oversized = format!(
+            "CONNECT a.com:443 HTTP/1.1\r\nX-Header: {}\r\n\r\n",
+            "a".repeat(MAX_HTTP_REQUEST_SIZE)
+        );
+
+        let cases: Vec<(&str, &[u8], Result<&str, EstablishTunnelResult>)> = vec![
+            // well-formed
+            ("connect", b"CONNECT www.example.com:443 HTTP/1.1\r\n\r\n", Ok("www.example.com:443")),
+            ("http/1.0", b"CONNECT www.example.com:443 HTTP/1.0\r\n\r\n", Ok("www.example.com:443")),
+            ("headers", b"CONNECT www.example.com:443 HTTP/1.1\r\nHost: www.example.com:443\r\nUser-Agent: test\r\n\r\n", Ok("www.example.com:443")),
+            ("ipv6", b"CONNECT [::1]:443 HTTP/1.1\r\n\r\n", Ok("[::1]:443")),
+            // malformed request line
+            ("no version", b"CONNECT www.example.com:443\r\n\r\n", Err(EstablishTunnelResult::BadRequest)),
+            ("double space", b"CONNECT  www.example.com:443 HTTP/1.1\r\n\r\n", Err(EstablishTunnelResult::BadRequest)),
+            ("bad version", b"CONNECT www.example.com:443 HTTP/2.0\r\n\r\n", Err(EstablishTunnelResult::BadRequest)),
+            ("no port", b"CONNECT www.example.com HTTP/1.1\r\n\r\n", Err(EstablishTunnelResult::BadRequest)),
+            ("port zero", b"CONNECT www.example.com:0 HTTP/1.1\r\n\r\n", Err(EstablishTunnelResult::BadRequest)),
+            ("userinfo", b"CONNECT user@www.example.com:443 HTTP/1.1\r\n\r\n", Err(EstablishTunnelResult::BadRequest)),
+            ("bad ipv6", b"CONNECT [::g]:443 HTTP/1.1\r\n\r\n", Err(EstablishTunnelResult::BadRequest)),
+            ("non-ascii", "CONNECT www.exämple.com:443 HTTP/1.1\r\n\r\n".as_bytes(), Err(EstablishTunnelResult::BadRequest)),
+            ("bare lf", b"CONNECT www.example.com:443 HTTP/1.1\nHost: a\r\n\r\n", Err(EstablishTunnelResult::BadRequest)),
+            // malformed headers
+            ("no colon", b"CONNECT www.example.com:443 HTTP/1.1\r\nHost\r\n\r\n", Err(EstablishTunnelResult::BadRequest)),
+            ("space before colon", b"CONNECT www.example.com:443 HTTP/1.1\r\nHost : a\r\n\r\n", Err(EstablishTunnelResult::BadRequest)),
+            ("obs-fold", b"CONNECT www.example.com:443 HTTP/1.1\r\nX-Header: a\r\n b\r\n\r\n", Err(EstablishTunnelResult::BadRequest)),
+            // oversized
+            ("too many headers", too_many_headers.as_bytes(), Err(EstablishTunnelResult::BadRequest)),
+            ("too long", oversized.as_bytes(), Err(EstablishTunnelResult::BadRequest)),
+            // pipelined, the codec passes only the head
+            ("pipelined", b"CONNECT www.example.com:443 HTTP/1.1\r\n\r\nCONNECT other.com:443 HTTP/1.1\r\n\r\n", Err(EstablishTunnelResult::BadRequest)),
+        ];
+
+        for (name, request, expected) in cases {
+            let parsed = HttpConnectRequest::parse(request).map(|request| request.uri);
+            assert_eq!(parsed.as_deref().map_err(Clone::clone), expected, "{}", name);
+        }
+    }
+
+    #[cfg(not(feature = "plain_text"))]
+    #[test]
+    fn parse_rejects_other_methods() {
+        assert_eq!(
+            HttpConnectRequest::parse(b"GET http://www.example.com/ HTTP/1.1\r\n\r\n").err(),
+            Some(EstablishTunnelResult::OperationNotAllowed)
+        );
+    }
+
+    #[test]
+    fn parse_keeps_headers() {
+        let request = HttpConnectRequest::parse(
+            b"CONNECT www.example.com:443 HTTP/1.1\r\nproxy-authorization:  Basic dG9rZW4= \r\n\r\n",
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(request.header("Proxy-Authorization"), Some("Basic dG9rZW4="));
+        assert_eq!(request.header("Host"), None);
+    }
+
+    #[test]
+    fn decode_leaves_pipelined_data() {
+        let mut codec = new_codec(None);
+        let mut buf = BytesMut::from(&b"CONNECT www.example.com:443 HTTP/1.1\r\n"[..]);
+        assert_eq!(codec.decode(&mut buf), Ok(None));
+
+        buf.extend_from_slice(b"\r\n\x16\x03\x01");
+        let target = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(target.target, "www.example.com:443");
+        assert_eq!(&buf[..], b"\x16\x03\x01");
+    }
+
+    #[test]
+    fn decode_rejects_oversized_head() {
+        let mut codec = new_codec(None);
+        let mut buf = BytesMut::from(&b"CONNECT www.example.com:443 HTTP/1.1\r\nX-Header: "[..]);
+        buf.extend_from_slice(&vec![b'a'; MAX_HTTP_REQUEST_SIZE]);
+        assert_eq!(codec.decode(&mut buf), Err(EstablishTunnelResult::BadRequest));
+    }
+
+    fn forward(method: &str, uri: &str, headers: &[(&str, &str)]) -> Result<(String, String), EstablishTunnelResult> {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let request = HttpConnectRequest::forward_request(method, uri, "HTTP/1.1", headers)?;
+        let nugget = request.nugget.expect("Forwarded requests have a nugget");
+        Ok((request.uri, String::from_utf8(nugget.data().to_vec()).unwrap()))
+    }
+
+    #[test]
+    fn absolute_uri_to_origin_form() {
+        for (uri, target, authority, origin_form) in [
+            ("http://www.example.com/index.html", "www.example.com:80", "www.example.com", "/index.html"),
+            ("http://www.example.com/search?q=a&b=c", "www.example.com:80", "www.example.com", "/search?q=a&b=c"),
+            ("http://www.example.com?q=a", "www.example.com:80", "www.example.com", "/?q=a"),
+            ("http://www.example.com", "www.example.com:80", "www.example.com", "/"),
+            ("http://www.example.com:8080/", "www.example.com:8080", "www.example.com:8080", "/"),
+            ("HTTP://www.example.com/", "www.example.com:80", "www.example.com", "/"),
+            ("http://[::1]/", "[::1]:80", "[::1]", "/"),
+            ("http://[::1]:8080/a", "[::1]:8080", "[::1]:8080", "/a"),
+        ] {
+            assert_eq!(
+                parse_absolute_uri(uri),
+                Some((target.to_string(), authority, origin_form.to_string())),
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[test]
+    fn bad_absolute_uris() {
+        for uri in [
+            "https://www.example.com/",
+            "ftp://www.example.com/",
+            "/index.html",
+            "http://",
+            "http:///index.html",
+            "http://www.example.com:0/",
+            "http://www.example.com:http/",
+            "http://user@www.example.com/",
+        ] {
+            assert_eq!(parse_absolute_uri(uri), None, "{}", uri);
+            assert_eq!(forward("GET", uri, &[]).err(), Some(EstablishTunnelResult::BadRequest), "{}", uri);
+        }
+    }
+
+    #[test]
+    fn forwarded_request() {
+        let (target, request) = forward(
+            "GET",
+            "http://www.example.com:8080/search?q=a",
+            &[("Host", "www.example.com:8080"), ("Accept", "*/*"), ("Proxy-Authorization", "Basic dG9rZW4=")],
+        )
+        .unwrap();
+        assert_eq!(target, "www.example.com:8080");
+        assert_eq!(
+            request,
+            "GET /search?q=a HTTP/1.1\r\nHost: www.example.com:8080\r\nAccept: */*\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn host_fallback() {
+        let (_, request) = forward("GET", "http://www.example.com/", &[("Accept", "*/*")]).unwrap();
+        assert_eq!(
+            request,
+            "GET / HTTP/1.1\r\nAccept: */*\r\nHost: www.example.com\r\nConnection: close\r\n\r\n"
+        );
+        // The authority as sent, without the default port.
+        let (_, request) = forward("GET", "http://[::1]:8080", &[]).unwrap();
+        assert_eq!(request, "GET / HTTP/1.1\r\nHost: [::1]:8080\r\nConnection: close\r\n\r\n");
+        // `host` is a Host as well.
+        let (_, request) = forward("GET", "http://www.example.com/", &[("host", "www.example.com")]).unwrap();
+        assert_eq!(request, "GET / HTTP/1.1\r\nhost: www.example.com\r\nConnection: close\r\n\r\n");
+    }
+
+    #[test]
+    fn connection_options_are_dropped() {
+        let (_, request) = forward(
+            "POST",
+            "http://www.example.com/",
+            &[
+                ("Host", "www.example.com"),
+                ("Connection", "keep-alive, X-Session ,x-trace"),
+                ("connection", "Upgrade"),
+                ("X-Session", "secret"),
+                ("X-Trace", "1"),
+                ("Upgrade", "websocket"),
+                ("Keep-Alive", "timeout=5"),
+                ("Content-Length", "2"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            request,
+            "POST / HTTP/1.1\r\nHost: www.example.com\r\nContent-Length: 2\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[cfg(feature = "plain_text")]
+    #[test]
+    fn decode_plain_text_request() {
+        let mut codec = new_codec(None);
+        let mut buf = BytesMut::from(&b"POST http://www.example.com/form HTTP/1.1\r\nContent-Length: 2\r\n\r\nab"[..]);
+        let target = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(target.target, "www.example.com:80");
+        // The body goes along.
+        assert_eq!(
+            target.nugget.unwrap().data().as_slice(),
+            &b"POST /form HTTP/1.1\r\nContent-Length: 2\r\nHost: www.example.com\r\nConnection: close\r\n\r\nab"[..]
+        );
+        assert!(buf.is_empty());
+    }
+
+    fn new_codec(authentication: Option<AuthenticationConfig>) -> HttpTunnelCodec {
+        HttpTunnelCodecBuilder::default()
+            .tunnel_ctx(TunnelCtx::default())
+            .enabled_targets(Regex::new(".*").unwrap())
+            .authentication(authentication)
+            .build()
+            .unwrap()
+    }
+
+    fn authentication() -> AuthenticationConfig {
+        let entry = format!("alice:{{SHA}}{}", base64::encode(Sha1::digest(b"secret")));
+        AuthenticationConfig {
+            realm: "corp".to_string(),
+            credentials: Arc::new(Credentials::parse(&entry).unwrap()),
+        }
+    }
+
+    fn encode(codec: &mut HttpTunnelCodec, result: EstablishTunnelResult) -> String {
+        let mut buf = BytesMut::new();
+        codec.encode(result, &mut buf).unwrap();
+        String::from_utf8(buf.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn proxy_authentication_required_has_challenge() {
+        let mut codec = new_codec(Some(authentication()));
+        assert_eq!(
+            encode(&mut codec, EstablishTunnelResult::ProxyAuthenticationRequired),
+            "HTTP/1.1 407 PROXY_AUTHENTICATION_REQUIRED\r\n\
+             Proxy-Authenticate: Basic realm=\"corp\"\r\n\r\n"
+        );
+        // The challenge goes only with a 407.
+        assert_eq!(encode(&mut codec, EstablishTunnelResult::Forbidden), "HTTP/1.1 403 FORBIDDEN\r\n\r\n");
+    }
+
+    #[test]
+    fn decode_checks_proxy_authorization() {
+        let request = |authorization: Option<&str>| {
+            let mut request = "CONNECT www.example.com:443 HTTP/1.1\r\nHost: www.example.com:443\r\n".to_string();
+            if let Some(authorization) = authorization {
+                request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", base64::encode(authorization)));
+            }
+            request.push_str("\r\n");
+            BytesMut::from(request.as_str())
+        };
+
+        let mut codec = new_codec(Some(authentication()));
+        for authorization in [None, Some("alice:wrong"), Some("bob:secret")] {
+            assert_eq!(
+                codec.decode(&mut request(authorization)),
+                Err(EstablishTunnelResult::ProxyAuthenticationRequired)
+            );
+        }
+
+        let target = codec.decode(&mut request(Some("alice:secret"))).unwrap().unwrap();
+        assert_eq!(target.target, "www.example.com:443");
+        assert_eq!(target.user, Some("alice".to_string()));
+    }
+}
@@ -0,0 +1,263 @@
+use log::debug;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Caps on concurrent tunnels, a missing value means no limit.
+/// Enforced after the handshake (the target is known by then) and before connecting upstream.
+#[derive(Deserialize, Clone, Default, Debug)]
+pub struct ConcurrencyLimits {
+    #[serde(default)]
+    pub max_tunnels: Option<usize>,
+    #[serde(default)]
+    pub max_tunnels_per_client: Option<usize>,
+    #[serde(default)]
+    pub max_tunnels_per_target: Option<usize>,
+}
+
+#[derive(Default)]
+struct ActiveTunnels {
+    total: usize,
+    per_client: HashMap<IpAddr, usize>,
+    per_target: HashMap<String, usize>,
+}
+
+/// Counts live tunnels, shared by all of them.
+/// The limits are passed on every call, so they can change while the proxy is running.
+#[derive(Default)]
+pub struct ConcurrencyLimiter {
+    active: Mutex<ActiveTunnels>,
+    rejected_total: AtomicU64,
+    rejected_per_client: AtomicU64,
+    rejected_per_target: AtomicU64,
+}
+
+/// Live counts for monitoring.
+#[derive(Serialize, Clone, Debug)]
+pub struct ConcurrencySnapshot {
+    pub tunnels: usize,
+    pub clients: usize,
+    pub targets: usize,
+    pub rejected_total: u64,
+    pub rejected_per_client: u64,
+    pub rejected_per_target: u64,
+}
+
+/// Holds a slot until dropped, i.e. until the tunnel is closed.
+pub struct TunnelPermit {
+    limiter: Arc<ConcurrencyLimiter>,
+    client: IpAddr,
+    target: String,
+}
+
+impl ConcurrencyLimiter {
+    /// Returns `None` if any of the limits is reached.
+    pub fn try_acquire(
+        self: &Arc<Self>,
+        limits: &ConcurrencyLimits,
+        client: IpAddr,
+        target: &str,
+    ) -> Option<TunnelPermit> {
+        let mut active = self.active.lock().expect("Poisoned lock");
+
+        let over_limit = |limit: Option<usize>, current: usize| match limit {
+            Some(limit) => current >= limit,
+            None => false,
+        };
+
+        let rejected = if over_limit(limits.max_tunnels, active.total) {
+            Some(&self.rejected_total)
+        } else if over_limit(
+            limits.max_tunnels_per_client,
+            *active.per_client.get(&client).unwrap_or(&0),
+        ) {
+            Some(&self.rejected_per_client)
+        } else if over_limit(
+            limits.max_tunnels_per_target,
+            *active.per_target.get(target).unwrap_or(&0),
+        ) {
+            Some(&self.rejected_per_target)
+        } else {
+            None
+        };
+
+        if let Some(counter) = rejected {
+            counter.fetch_add(1, Ordering::Relaxed);
+            debug!(
+                "Too many tunnels: total={}, client={}, target={}",
+                active.total, client, target
+            );
+            return None;
+        }
+
+        active.total += 1;
+        // entry API: Gets the given key's corresponding entry in the map for in-place manipulation.
+        // https://doc.rust-lang.org/std/collections/struct.HashMap.html#method.entry
+        *active.per_client.entry(client).or_insert(0) += 1;
+        *active.per_target.entry(target.to_string()).or_insert(0) += 1;
+
+        Some(TunnelPermit {
+            limiter: self.clone(),
+            client,
+            target: target.to_string(),
+        })
+    }
+
+    pub fn snapshot(&self) -> ConcurrencySnapshot {
+        let active = self.active.lock().expect("Poisoned lock");
+        ConcurrencySnapshot {
+            tunnels: active.total,
+            clients: active.per_client.len(),
+            targets: active.per_target.len(),
+            rejected_total: self.rejected_total.load(Ordering::Relaxed),
+            rejected_per_client: self.rejected_per_client.load(Ordering::Relaxed),
+            rejected_per_target: self.rejected_per_target.load(Ordering::Relaxed),
+        }
+    }
+
+    fn release(&self, client: &IpAddr, target: &str) {
+        let mut active = self.active.lock().expect("Poisoned lock");
+        active.total -= 1;
+        decrement(&mut active.per_client, client);
+        decrement(&mut active.per_target, target);
+    }
+}
+
+/// Removes the key once the count drops to zero, so the maps don't grow with every client ever seen.
+fn decrement<K, Q>(counts: &mut HashMap<K, usize>, key: &Q)
+where
+    K: std::borrow::Borrow<Q> + std::hash::Hash + Eq,
+    Q: std::hash::Hash + Eq + ?Sized,
+{
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+/// Drop: Custom code within the destructor.
+/// https://doc.rust-lang.org/std/ops/trait.Drop.html
+impl Drop for TunnelPermit {
+    fn drop(&mut self) {
+        self.limiter.release(&self.client, &self.target);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(yaml: &str) -> ConcurrencyLimits {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn client(n: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, n])
+    }
+
+    /// tunnels, clients, targets and the rejections (total, per client, per target)
+    fn counts(limiter: &ConcurrencyLimiter) -> (usize, usize, usize, u64, u64, u64) {
+        let s = limiter.snapshot();
+        (s.tunnels, s.clients, s.targets, s.rejected_total, s.rejected_per_client, s.rejected_per_target)
+    }
+
+    #[test]
+    fn no_limits() {
+        let limiter = Arc::new(ConcurrencyLimiter::default());
+        let permits: Vec<_> = (0..100)
+            .map(|n| limiter.try_acquire(&ConcurrencyLimits::default(), client(n % 3), "a:443"))
+            .collect();
+        assert!(permits.iter().all(Option::is_some));
+        assert_eq!(counts(&limiter), (100, 3, 1, 0, 0, 0));
+    }
+
+    #[test]
+    fn max_tunnels() {
+        let limiter = Arc::new(ConcurrencyLimiter::default());
+        let limits = limits("max_tunnels: 2");
+
+        let _a = limiter.try_acquire(&limits, client(1), "a:443").unwrap();
+        let _b = limiter.try_acquire(&limits, client(2), "b:443").unwrap();
+        assert!(limiter.try_acquire(&limits, client(3), "c:443").is_none());
+        assert_eq!(counts(&limiter), (2, 2, 2, 1, 0, 0));
+    }
+
+    #[test]
+    fn max_tunnels_per_client() {
+        let limiter = Arc::new(ConcurrencyLimiter::default());
+        let limits = limits("max_tunnels_per_client: 2");
+
+        let _a = limiter.try_acquire(&limits, client(1), "a:443").unwrap();
+        let _b = limiter.try_acquire(&limits, client(1), "b:443").unwrap();
+        assert!(limiter.try_acquire(&limits, client(1), "c:443").is_none());
+        let _c = limiter.try_acquire(&limits, client(2), "c:443").unwrap();
+        assert_eq!(counts(&limiter), (3, 2, 3, 0, 1, 0));
+    }
+
+    #[test]
+    fn max_tunnels_per_target() {
+        let limiter = Arc::new(ConcurrencyLimiter::default());
+        let limits = limits("max_tunnels_per_target: 1");
+
+        let _a = limiter.try_acquire(&limits, client(1), "a:443").unwrap();
+        assert!(limiter.try_acquire(&limits, client(2), "a:443").is_none());
+        let _b = limiter.try_acquire(&limits, client(2), "a:80").unwrap();
+        assert_eq!(counts(&limiter), (2, 2, 2, 0, 0, 1));
+    }
+
+    #[test]
+    fn first_limit_reached_is_counted() {
+        let limiter = Arc::new(ConcurrencyLimiter::default());
+        let limits = limits("{max_tunnels: 1, max_tunnels_per_client: 1, max_tunnels_per_target: 1}");
+
+        let _a = limiter.try_acquire(&limits, client(1), "a:443").unwrap();
+        assert!(limiter.try_acquire(&limits, client(1), "a:443").is_none());
+        assert_eq!(counts(&limiter), (1, 1, 1, 1, 0, 0));
+    }
+
+    #[test]
+    fn dropped_permit_frees_its_slot() {
+        let limiter = Arc::new(ConcurrencyLimiter::default());
+        let limits = limits("{max_tunnels: 1, max_tunnels_per_client: 1, max_tunnels_per_target: 1}");
+
+        let permit = limiter.try_acquire(&limits, client(1), "a:443").unwrap();
+        assert!(limiter.try_acquire(&limits, client(2), "b:443").is_none());
+        drop(permit);
+        assert_eq!(counts(&limiter), (0, 0, 0, 1, 0, 0));
+
+        let _permit = limiter.try_acquire(&limits, client(2), "b:443").unwrap();
+        assert_eq!(counts(&limiter), (1, 1, 1, 1, 0, 0));
+    }
+
+    #[test]
+    fn rejection_leaves_no_counts() {
+        let limiter = Arc::new(ConcurrencyLimiter::default());
+        let limits = limits("{max_tunnels_per_client: 5, max_tunnels_per_target: 1}");
+
+        let permit = limiter.try_acquire(&limits, client(1), "a:443").unwrap();
+        // Within the per client limit, the per target one rejects it.
+        for _ in 0..3 {
+            assert!(limiter.try_acquire(&limits, client(1), "a:443").is_none());
+            assert!(limiter.try_acquire(&limits, client(2), "a:443").is_none());
+        }
+        assert_eq!(counts(&limiter), (1, 1, 1, 0, 0, 6));
+
+        drop(permit);
+        assert_eq!(counts(&limiter), (0, 0, 0, 0, 0, 6));
+    }
+
+    #[test]
+    fn limits_can_change() {
+        let limiter = Arc::new(ConcurrencyLimiter::default());
+
+        let _a = limiter.try_acquire(&limits("max_tunnels: 2"), client(1), "a:443").unwrap();
+        let _b = limiter.try_acquire(&limits("max_tunnels: 2"), client(1), "a:443").unwrap();
+        // Lowered below the live count, open tunnels stay and new ones wait for them.
+        assert!(limiter.try_acquire(&limits("max_tunnels: 1"), client(1), "a:443").is_none());
+        let _c = limiter.try_acquire(&limits("max_tunnels: 3"), client(1), "a:443").unwrap();
+        assert_eq!(counts(&limiter), (3, 1, 1, 1, 0, 0));
+    }
+}
@@ -5,29 +5,76 @@ extern crate serde_derive;
 
 /// > そして lib.rs の中で以下のようにmodで参照してあげれば使えます。
 /// https://keens.github.io/blog/2018/12/08/rustnomoju_runotsukaikata_2018_editionhan/
+mod access_control;
+mod admin;
+mod authentication;
+mod bandwidth;
 mod configuration;
+mod dns_cache;
+mod http_endpoint;
+mod limits;
+mod metrics;
+mod nameserver;
+mod original_dst;
+mod proxy_protocol;
+mod registry;
 mod relay;
+mod reload;
+mod shutdown;
+mod sni_codec;
+mod socks5_codec;
 mod proxy_target;
 mod tunnel;
+mod upstream_proxy;
 mod http_tunnel_codec;
 
 /// tokio: Tokio is an asynchronous runtime for the Rust programming language. It provides the building blocks needed for writing networking applications
 /// https://tokio.rs/tokio/tutorial/hello-tokio
 use tokio::io;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+/// tokio-native-tls: An implementation of TLS/SSL streams for Tokio built on top of the native-tls crate
+/// https://docs.rs/tokio-native-tls/0.3.0/tokio_native_tls/
+use native_tls::Identity;
+use tokio_native_tls::TlsAcceptor;
+use tokio::time::timeout;
+use tokio_util::codec::{Decoder, Encoder};
 
 /// Without `mod {filename}`, we got an error: could not find `configuration` in the crate root
 use crate::configuration::{ProxyConfiguration, ProxyMode};
-use crate::proxy_target::{SimpleCachingDnsResolver, SimpleTcpConnector};
+use crate::configuration::TunnelConfig;
+use crate::bandwidth::BandwidthLimiter;
+use crate::limits::ConcurrencyLimiter;
+use crate::admin::handle_admin_request;
+use crate::http_endpoint::{serve_endpoint, Response};
+use crate::metrics::{Metrics, METRICS_PATH};
+use crate::registry::TunnelRegistry;
+use crate::reload::{watch_config_file, SharedTunnelConfig};
+use crate::shutdown::{shutdown_signal, Shutdown, ShutdownListener};
+use crate::socks5_codec::{negotiate, Socks5Codec, Socks5CodecBuilder};
+use crate::sni_codec::{SniCodec, SniCodecBuilder};
+use crate::original_dst::{original_destination, set_transparent};
+use crate::proxy_protocol::read_header;
+use crate::access_control::DestinationPolicy;
+use crate::proxy_target::{
+    ConnectStats, SimpleCachingDnsResolver, SimpleTcpConnector, TargetConnector,
+};
+use crate::upstream_proxy::UpstreamProxyConnector;
+use crate::nameserver::NameserverResolver;
 use crate::tunnel::{
-    TunnelCtxBuilder, ConnectionTunnel, TunnelCtx, TunnelStats,
+    relay_connections, TunnelCtxBuilder, ConnectionTunnel, EstablishTunnelResult, TunnelCtx,
+    TunnelStats, TunnelStatsBuilder,
+};
+use crate::http_tunnel_codec::{
+    HttpTunnelCodec, HttpTunnelCodecBuilder, HttpTunnelTarget, HttpTunnelTargetBuilder,
 };
-use crate::http_tunnel_codec::{HttpTunnelCodec, HttpTunnelCodecBuilder, HttpTunnelTarget};
 
 /// log: A lightweight logging facade for Rust
 /// https://crates.io/crates/log
-use log::{error, info, LevelFilter};
+use log::{debug, error, info, warn, LevelFilter};
 use log4rs::append::console::ConsoleAppender;
 use log4rs::config::{Appender, Root};
 use log4rs::Config;
@@ -38,6 +85,23 @@ use rand::{thread_rng, Rng};
 
 type DnsResolver = SimpleCachingDnsResolver;
 
+/// Everything the tunnels share, cloned into each of them.
+/// The tunnel configuration is taken from `tunnel_config` on accept, so it may differ between tunnels.
+#[derive(Clone)]
+struct ProxyContext {
+    tunnel_config: SharedTunnelConfig,
+    dns_resolver: DnsResolver,
+    concurrency_limiter: Arc<ConcurrencyLimiter>,
+    bandwidth_limiter: Arc<BandwidthLimiter>,
+    metrics: Arc<Metrics>,
+    registry: Arc<TunnelRegistry>,
+    // Keeps the proxy from exiting until the tunnel is over.
+    shutdown_listener: ShutdownListener,
+}
+
+/// How often the live tunnel counts are written to the metrics log.
+const CONCURRENCY_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
 #[tokio::main]
 pub async fn main() -> io::Result<()> {
     init_logger();
@@ -64,27 +128,116 @@ pub async fn main() -> io::Result<()> {
             e
         })?;
 
+    let target_connection = &proxy_configuration.tunnel_config.target_connection;
     let dns_resolver = SimpleCachingDnsResolver::new(
-        proxy_configuration
-            .tunnel_config
-            .target_connection
-            .dns_cache_ttl,
-    );
+        target_connection.dns_cache_ttl,
+        target_connection.dns_cache_size,
+        target_connection.dns_negative_cache_ttl,
+    )
+    .with_nameservers(NameserverResolver::new(
+        target_connection.nameservers.clone(),
+        target_connection.dns_overrides.clone(),
+    ));
 
-    match &proxy_configuration.mode {
-        ProxyMode::HTTP => {
-            // about .await https://rust-lang.github.io/async-book/01_getting_started/04_async_await_primer.html
-            serve_plain_text(proxy_configuration, &mut tcp_listener, dns_resolver).await?;
-        }
-        // TODO: HOW to bind value to tls_identity?
-        ProxyMode::HTTPS(tls_identity) => {
-            // TODO: HTTPS        
+    // Shared by all tunnels, the limits themselves are read from the configuration.
+    let concurrency_limiter = Arc::new(ConcurrencyLimiter::default());
+    tokio::spawn(report_concurrency(concurrency_limiter.clone()));
+
+    let shared_config = SharedTunnelConfig::new(proxy_configuration.tunnel_config.clone());
+    if let Some(config_file) = &proxy_configuration.config_file {
+        tokio::spawn(watch_config_file(config_file.clone(), shared_config.clone()));
+    }
+
+    let metrics = Arc::new(Metrics::default());
+    if let Some(metrics_bind_address) = &proxy_configuration.metrics_bind_address {
+        let metrics_listener = TcpListener::bind(metrics_bind_address).await.map_err(|e| {
+            error!("Error binding metrics address {} {}", metrics_bind_address, e);
+            e
+        })?;
+        info!("Serving metrics on: http://{}{}", metrics_bind_address, METRICS_PATH);
+
+        let metrics = metrics.clone();
+        let concurrency_limiter = concurrency_limiter.clone();
+        let dns_resolver = dns_resolver.clone();
+        tokio::spawn(serve_endpoint(metrics_listener, move |method, path| {
+            if method == "GET" && path == METRICS_PATH {
+                Response::new(
+                    "200 OK",
+                    "text/plain; version=0.0.4",
+                    metrics.render(&concurrency_limiter.snapshot(), &dns_resolver.cache_snapshot()),
+                )
+            } else {
+                Response::not_found()
+            }
+        }));
+    }
+
+    let registry = Arc::new(TunnelRegistry::default());
+    if let Some(admin_bind_address) = &proxy_configuration.admin_bind_address {
+        let admin_listener = TcpListener::bind(admin_bind_address).await.map_err(|e| {
+            error!("Error binding admin address {} {}", admin_bind_address, e);
+            e
+        })?;
+        // There is no authentication, anyone who can connect can kill tunnels.
+        if !admin_listener.local_addr()?.ip().is_loopback() {
+            warn!("The admin API is reachable from other hosts: {}", admin_bind_address);
         }
-        ProxyMode::TCP(d) => {
-            // TODO: TCP
+        info!("Serving the admin API on: http://{}/tunnels", admin_bind_address);
+
+        let registry = registry.clone();
+        tokio::spawn(serve_endpoint(admin_listener, move |method, path| {
+            handle_admin_request(&registry, method, path)
+        }));
+    }
+
+    let shutdown = Shutdown::new();
+    let proxy_ctx = ProxyContext {
+        tunnel_config: shared_config.clone(),
+        dns_resolver,
+        concurrency_limiter,
+        bandwidth_limiter: Arc::new(BandwidthLimiter::default()),
+        metrics,
+        registry,
+        shutdown_listener: shutdown.listener(),
+    };
+
+    let serving = async move {
+        match &proxy_configuration.mode {
+            ProxyMode::HTTP(tls_identity) => {
+                let tls_acceptor = match tls_identity {
+                    Some(tls_identity) => Some(new_tls_acceptor(tls_identity)?),
+                    None => None,
+                };
+                // about .await https://rust-lang.github.io/async-book/01_getting_started/04_async_await_primer.html
+                serve_plain_text(&proxy_configuration, &mut tcp_listener, tls_acceptor, proxy_ctx).await
+            }
+            ProxyMode::HTTPS(tls_identity) => {
+                let tls_acceptor = new_tls_acceptor(tls_identity)?;
+                serve_tls(&proxy_configuration, &mut tcp_listener, tls_acceptor, proxy_ctx).await
+            }
+            ProxyMode::TCP(destination) => {
+                serve_tcp(&proxy_configuration, &mut tcp_listener, destination.clone(), proxy_ctx).await
+            }
+            ProxyMode::SOCKS5 => {
+                serve_socks5(&proxy_configuration, &mut tcp_listener, proxy_ctx).await
+            }
+            ProxyMode::SNI => {
+                serve_sni(&proxy_configuration, &mut tcp_listener, proxy_ctx).await
+            }
+            ProxyMode::Transparent => {
+                serve_transparent(&proxy_configuration, &mut tcp_listener, proxy_ctx).await
+            }
         }
+    };
+
+    // Dropping the accept loop closes the listening socket, spawned tunnels keep running.
+    tokio::select! {
+        result = serving => result?,
+        _ = shutdown_signal() => info!("Stopped accepting connections"),
     }
 
+    shutdown.drain(shared_config.current().drain_timeout).await;
+
     info!("Proxy stopped");
 
     // Contains the success value
@@ -130,13 +283,54 @@ fn init_logger() {
     }
 }
 
+fn new_tls_acceptor(tls_identity: &Identity) -> io::Result<TlsAcceptor> {
+    // native_tls::TlsAcceptor: A builder for server-side TLS connections.
+    // https://docs.rs/native-tls/0.2.8/native_tls/struct.TlsAcceptor.html
+    let acceptor = native_tls::TlsAcceptor::new(tls_identity.clone()).map_err(|e| {
+        error!("Error setting up TLS {}", e);
+        io::Error::from(io::ErrorKind::InvalidInput)
+    })?;
+
+    // Wraps the native-tls acceptor to accept connections asynchronously.
+    Ok(TlsAcceptor::from(acceptor))
+}
+
+/// The handshake a client starts with, told by the first byte it sends.
+enum Handshake {
+    /// An HTTP method, e.g. `CONNECT`
+    Http,
+    /// The SOCKS version
+    Socks5,
+    /// A TLS record of the handshake content type
+    /// https://datatracker.ietf.org/doc/html/rfc8446#section-5.1
+    Tls,
+}
+
+/// Peeks at the first byte without taking it, so the chosen handler reads the stream from the start.
+/// Returns `None` if the client sends nothing within `initiation_timeout`.
+async fn sniff_handshake(stream: &TcpStream, initiation_timeout: Duration) -> Option<Handshake> {
+    let mut first_byte = [0; 1];
+    // peek: Receives data on the socket from the remote address to which it is connected, without removing that data from the queue.
+    // https://docs.rs/tokio/1.10.1/tokio/net/struct.TcpStream.html#method.peek
+    match timeout(initiation_timeout, stream.peek(&mut first_byte)).await {
+        Err(_) => None,
+        Ok(Ok(1)) if first_byte[0] == 0x05 => Some(Handshake::Socks5),
+        Ok(Ok(1)) if first_byte[0] == 0x16 => Some(Handshake::Tls),
+        // Closed connections and errors too, the HTTP codec reports them as usual.
+        Ok(_) => Some(Handshake::Http),
+    }
+}
+
 /// The () type called unit.
 /// > The () type has exactly one value (), and is used when there is no other meaningful value that could be returned. 
 /// https://doc.rust-lang.org/std/primitive.unit.html
+///
+/// Serves HTTP, SOCKS5 and, with a `tls_acceptor`, TLS clients on the same port.
 async fn serve_plain_text(
-    config: ProxyConfiguration,
+    config: &ProxyConfiguration,
     listener: &mut TcpListener,
-    dns_resolver: DnsResolver,
+    tls_acceptor: Option<TlsAcceptor>,
+    proxy_ctx: ProxyContext,
 ) -> io::Result<()> {
     info!("Serving requests on: {}", config.bind_address);
     loop {
@@ -149,10 +343,10 @@ async fn serve_plain_text(
         // Clone trait defines clone().
         // A common trait for the ability to explicitly duplicate an object
         // https://doc.rust-lang.org/std/clone/trait.Clone.html
-        let dns_resolver_ref = dns_resolver.clone();
+        let proxy_ctx_ref = proxy_ctx.clone();
 
         match socket {
-            Ok((stream, _)) => {
+            Ok((stream, client_addr)) => {
                 // pub fn nodelay(&self) -> Result<bool>
                 // Gets the value of the TCP_NODELAY option on this socket.
                 // nodelay => disables the Nagle algorithm.
@@ -162,7 +356,8 @@ async fn serve_plain_text(
                 // unwrap_or_default: Returns the contained Some value or a default
                 // https://doc.rust-lang.org/std/option/enum.Option.html#method.unwrap_or_default
                 stream.nodelay().unwrap_or_default();
-                let config = config.clone();
+                // The tunnel keeps this configuration even if it's reloaded meanwhile.
+                let config = proxy_ctx_ref.tunnel_config.current();
                 // handle accepted connnections asynchronously
                 //
                 // Function tokio::spawn: Spawns a new asynchronous task, returning a JoinHandle for it
@@ -172,13 +367,403 @@ async fn serve_plain_text(
                 // Keyword `move` 
                 // https://doc.rust-lang.org/std/keyword.move.html
                 // > move converts any variables captured by reference or mutable reference to variables captured by value.
-                tokio::spawn(async move { tunnel_stream(&config, stream, dns_resolver_ref).await });
+                let tls_acceptor = tls_acceptor.clone();
+                tokio::spawn(async move {
+                    let mut stream = stream;
+                    let client_addr = match accept_proxy_protocol(&config, &mut stream, client_addr, &proxy_ctx_ref).await {
+                        Some(client_addr) => client_addr,
+                        None => return Ok(()),
+                    };
+                    match sniff_handshake(&stream, config.client_connection.initiation_timeout).await {
+                        Some(Handshake::Http) => {
+                            tunnel_stream(&config, stream, client_addr, proxy_ctx_ref).await
+                        }
+                        Some(Handshake::Socks5) => {
+                            socks5_stream(&config, stream, client_addr, proxy_ctx_ref).await
+                        }
+                        Some(Handshake::Tls) => match tls_acceptor {
+                            Some(tls_acceptor) => {
+                                accept_tls(&config, tls_acceptor, stream, client_addr, proxy_ctx_ref).await
+                            }
+                            None => {
+                                let ctx = new_tunnel_ctx(client_addr);
+                                error!("TLS client, but TLS is not enabled, CTX={}", ctx);
+                                report_establish_failure(&proxy_ctx_ref.metrics, ctx, EstablishTunnelResult::TlsHandshakeFailed, None, None);
+                                Ok(())
+                            }
+                        },
+                        None => {
+                            let ctx = new_tunnel_ctx(client_addr);
+                            error!(
+                                "Client sent nothing within {:?}, CTX={}",
+                                config.client_connection.initiation_timeout, ctx
+                            );
+                            report_establish_failure(&proxy_ctx_ref.metrics, ctx, EstablishTunnelResult::RequestTimeout, None, None);
+                            Ok(())
+                        }
+                    }
+                });
+            }
+            Err(e) => error!("Failed TCP handshake{}", e)
+        }
+    }
+}
+
+/// Same as `serve_plain_text`, but the clients speak SOCKS5 instead of `HTTP CONNECT`.
+async fn serve_socks5(
+    config: &ProxyConfiguration,
+    listener: &mut TcpListener,
+    proxy_ctx: ProxyContext,
+) -> io::Result<()> {
+    info!("Serving SOCKS5 requests on: {}", config.bind_address);
+    loop {
+        let socket = listener.accept().await;
+
+        let proxy_ctx_ref = proxy_ctx.clone();
+
+        match socket {
+            Ok((stream, client_addr)) => {
+                stream.nodelay().unwrap_or_default();
+                // The tunnel keeps this configuration even if it's reloaded meanwhile.
+                let config = proxy_ctx_ref.tunnel_config.current();
+                tokio::spawn(async move {
+                    let mut stream = stream;
+                    match accept_proxy_protocol(&config, &mut stream, client_addr, &proxy_ctx_ref).await {
+                        Some(client_addr) => socks5_stream(&config, stream, client_addr, proxy_ctx_ref).await,
+                        None => Ok(()),
+                    }
+                });
+            }
+            Err(e) => error!("Failed TCP handshake{}", e)
+        }
+    }
+}
+
+/// Same as `serve_plain_text`, but the clients send a TLS `ClientHello` instead of `HTTP CONNECT`.
+async fn serve_sni(
+    config: &ProxyConfiguration,
+    listener: &mut TcpListener,
+    proxy_ctx: ProxyContext,
+) -> io::Result<()> {
+    info!("Serving SNI requests on: {}", config.bind_address);
+    loop {
+        let socket = listener.accept().await;
+
+        let proxy_ctx_ref = proxy_ctx.clone();
+
+        match socket {
+            Ok((stream, client_addr)) => {
+                stream.nodelay().unwrap_or_default();
+                // The tunnel keeps this configuration even if it's reloaded meanwhile.
+                let config = proxy_ctx_ref.tunnel_config.current();
+                tokio::spawn(async move {
+                    let mut stream = stream;
+                    match accept_proxy_protocol(&config, &mut stream, client_addr, &proxy_ctx_ref).await {
+                        Some(client_addr) => sni_stream(&config, stream, client_addr, proxy_ctx_ref).await,
+                        None => Ok(()),
+                    }
+                });
+            }
+            Err(e) => error!("Failed TCP handshake{}", e)
+        }
+    }
+}
+
+/// Same as `serve_plain_text`, but the client connection is wrapped into TLS first.
+/// The tunnel request (e.g. `HTTP CONNECT`) is sent over the encrypted connection,
+/// so the target isn't visible on the wire.
+async fn serve_tls(
+    config: &ProxyConfiguration,
+    listener: &mut TcpListener,
+    tls_acceptor: TlsAcceptor,
+    proxy_ctx: ProxyContext,
+) -> io::Result<()> {
+    info!("Serving TLS requests on: {}", config.bind_address);
+    loop {
+        let socket = listener.accept().await;
+
+        let proxy_ctx_ref = proxy_ctx.clone();
+
+        match socket {
+            Ok((stream, client_addr)) => {
+                stream.nodelay().unwrap_or_default();
+                // TlsAcceptor is a cheap handle around `Arc`, each task takes its own clone.
+                let stream_acceptor = tls_acceptor.clone();
+                // The tunnel keeps this configuration even if it's reloaded meanwhile.
+                let config = proxy_ctx_ref.tunnel_config.current();
+                tokio::spawn(async move {
+                    // The load balancer doesn't terminate TLS, the header comes before the ClientHello.
+                    let mut stream = stream;
+                    match accept_proxy_protocol(&config, &mut stream, client_addr, &proxy_ctx_ref).await {
+                        Some(client_addr) => accept_tls(&config, stream_acceptor, stream, client_addr, proxy_ctx_ref).await,
+                        None => Ok(()),
+                    }
+                });
+            }
+            Err(e) => error!("Failed TCP handshake{}", e)
+        }
+    }
+}
+
+/// Terminates TLS and runs the HTTP tunnel over the encrypted connection.
+async fn accept_tls(
+    config: &TunnelConfig,
+    tls_acceptor: TlsAcceptor,
+    stream: TcpStream,
+    client_addr: SocketAddr,
+    proxy_ctx: ProxyContext,
+) -> io::Result<()> {
+    // The client has to complete the TLS handshake within the same timeout
+    // as the tunnel request itself.
+    let tls_handshake = timeout(
+        config.client_connection.initiation_timeout,
+        tls_acceptor.accept(stream),
+    )
+    .await;
+
+    match tls_handshake {
+        Ok(Ok(tls_stream)) => tunnel_stream(config, tls_stream, client_addr, proxy_ctx).await,
+        Ok(Err(e)) => {
+            let ctx = new_tunnel_ctx(client_addr);
+            error!("Client failed TLS handshake: {}, CTX={}", e, ctx);
+            report_establish_failure(&proxy_ctx.metrics, ctx, EstablishTunnelResult::TlsHandshakeFailed, None, None);
+            Ok(())
+        }
+        Err(_) => {
+            let ctx = new_tunnel_ctx(client_addr);
+            error!(
+                "Client failed to complete TLS handshake within {:?}, CTX={}",
+                config.client_connection.initiation_timeout, ctx
+            );
+            report_establish_failure(&proxy_ctx.metrics, ctx, EstablishTunnelResult::RequestTimeout, None, None);
+            Ok(())
+        }
+    }
+}
+
+/// With `proxy_protocol` configured, the connection comes from a load balancer,
+/// which sends the address of the actual client in the PROXY protocol header.
+/// That address is used from here on (access rules, limits, stats), as if the client connected directly.
+/// Returns `None` if the connection is rejected: it's not from a trusted load balancer, or the header is bad.
+async fn accept_proxy_protocol(
+    config: &TunnelConfig,
+    stream: &mut TcpStream,
+    peer_addr: SocketAddr,
+    proxy_ctx: &ProxyContext,
+) -> Option<SocketAddr> {
+    let proxy_protocol = match &config.client_connection.proxy_protocol {
+        Some(proxy_protocol) => proxy_protocol,
+        None => return Some(peer_addr),
+    };
+
+    if !proxy_protocol.is_trusted(&peer_addr.ip()) {
+        let ctx = new_tunnel_ctx(peer_addr);
+        error!("Connection from {} isn't from a trusted load balancer, CTX={}", peer_addr, ctx);
+        report_establish_failure(&proxy_ctx.metrics, ctx, EstablishTunnelResult::Forbidden, None, None);
+        return None;
+    }
+
+    match timeout(config.client_connection.initiation_timeout, read_header(stream)).await {
+        Ok(Ok(Some(client_addr))) => {
+            debug!("Load balancer {} relays client {}", peer_addr, client_addr);
+            Some(client_addr)
+        }
+        // e.g. a health check of the load balancer itself
+        Ok(Ok(None)) => Some(peer_addr),
+        Ok(Err(e)) => {
+            let ctx = new_tunnel_ctx(peer_addr);
+            error!("Bad PROXY protocol header from {}: {}, CTX={}", peer_addr, e, ctx);
+            report_establish_failure(&proxy_ctx.metrics, ctx, EstablishTunnelResult::BadRequest, None, None);
+            None
+        }
+        Err(_) => {
+            let ctx = new_tunnel_ctx(peer_addr);
+            error!(
+                "Load balancer {} sent no PROXY protocol header within {:?}, CTX={}",
+                peer_addr, config.client_connection.initiation_timeout, ctx
+            );
+            report_establish_failure(&proxy_ctx.metrics, ctx, EstablishTunnelResult::RequestTimeout, None, None);
+            None
+        }
+    }
+}
+
+/// TCP port-forwarding: there is no handshake, every accepted connection is relayed
+/// to the same `destination`.
+async fn serve_tcp(
+    config: &ProxyConfiguration,
+    listener: &mut TcpListener,
+    destination: String,
+    proxy_ctx: ProxyContext,
+) -> io::Result<()> {
+    info!(
+        "Serving requests on: {}, forwarding to: {}",
+        config.bind_address, destination
+    );
+    loop {
+        let socket = listener.accept().await;
+
+        let proxy_ctx_ref = proxy_ctx.clone();
+
+        match socket {
+            Ok((stream, client_addr)) => {
+                stream.nodelay().unwrap_or_default();
+                // The tunnel keeps this configuration even if it's reloaded meanwhile.
+                let config = proxy_ctx_ref.tunnel_config.current();
+                let destination = destination.clone();
+                tokio::spawn(async move {
+                    let mut stream = stream;
+                    let client_addr = match accept_proxy_protocol(&config, &mut stream, client_addr, &proxy_ctx_ref).await {
+                        Some(client_addr) => client_addr,
+                        None => return Ok(()),
+                    };
+                    // The destination is chosen by the operator, not by the client, so the destination policy doesn't apply.
+                    forward_stream(&config, stream, client_addr, destination, DestinationPolicy::allow_all(), proxy_ctx_ref).await
+                });
+            }
+            Err(e) => error!("Failed TCP handshake{}", e)
+        }
+    }
+}
+
+/// Same as `serve_tcp`, but every connection is relayed to the destination it had before the firewall redirected it.
+async fn serve_transparent(
+    config: &ProxyConfiguration,
+    listener: &mut TcpListener,
+    proxy_ctx: ProxyContext,
+) -> io::Result<()> {
+    let listener_addr = listener.local_addr()?;
+    // Only needed for TPROXY, REDIRECT works without it.
+    if let Err(e) = set_transparent(listener) {
+        warn!("Cannot set IP_TRANSPARENT, TPROXY redirections won't be accepted: {}", e);
+    }
+
+    info!("Serving transparent requests on: {}", config.bind_address);
+    loop {
+        let socket = listener.accept().await;
+
+        let proxy_ctx_ref = proxy_ctx.clone();
+
+        match socket {
+            Ok((stream, client_addr)) => {
+                stream.nodelay().unwrap_or_default();
+                let destination = match original_destination(&stream, listener_addr) {
+                    Ok(Some(destination)) => destination,
+                    Ok(None) => {
+                        let ctx = new_tunnel_ctx(client_addr);
+                        error!("Connection from {} wasn't redirected, CTX={}", client_addr, ctx);
+                        report_establish_failure(&proxy_ctx.metrics, ctx, EstablishTunnelResult::BadRequest, None, None);
+                        continue;
+                    }
+                    Err(e) => {
+                        let ctx = new_tunnel_ctx(client_addr);
+                        error!("Cannot get the original destination of {}: {}, CTX={}", client_addr, e, ctx);
+                        report_establish_failure(&proxy_ctx.metrics, ctx, EstablishTunnelResult::ServerError, None, None);
+                        continue;
+                    }
+                };
+                // The tunnel keeps this configuration even if it's reloaded meanwhile.
+                let config = proxy_ctx_ref.tunnel_config.current();
+                tokio::spawn(async move {
+                    let mut stream = stream;
+                    let client_addr = match accept_proxy_protocol(&config, &mut stream, client_addr, &proxy_ctx_ref).await {
+                        Some(client_addr) => client_addr,
+                        None => return Ok(()),
+                    };
+                    // The client chose the destination, the same as with `CONNECT`.
+                    let destination_policy = config.target_connection.destination_policy.clone();
+                    forward_stream(&config, stream, client_addr, destination.to_string(), destination_policy, proxy_ctx_ref).await
+                });
             }
             Err(e) => error!("Failed TCP handshake{}", e)
         }
     }
 }
 
+/// Connects to `destination` right away and relays data.
+/// The handshake codec isn't involved here: a codec is polled only after the client sent something,
+/// which would never happen for protocols where the server speaks first (e.g. SSH, SMTP).
+async fn forward_stream<C: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+    config: &TunnelConfig,
+    client: C,
+    client_addr: SocketAddr,
+    destination: String,
+    destination_policy: DestinationPolicy,
+    proxy_ctx: ProxyContext,
+) -> io::Result<()> {
+    let ctx = new_tunnel_ctx(client_addr);
+
+    // There is no handshake to answer with 429, so the connection is just closed.
+    let _permit = match proxy_ctx.concurrency_limiter.try_acquire(
+        &config.limits,
+        client_addr.ip(),
+        &destination,
+    ) {
+        Some(permit) => permit,
+        None => {
+            error!("Too many tunnels, rejected {}, CTX={}", client_addr, ctx);
+            report_establish_failure(&proxy_ctx.metrics, ctx, EstablishTunnelResult::TooManyRequests, Some(destination), None);
+            return Ok(());
+        }
+    };
+
+    // Listed by the admin API until the tunnel is closed.
+    let registration = proxy_ctx.registry.register(ctx, client_addr);
+    let live_tunnel = registration.tunnel();
+    live_tunnel.set_target(destination.clone());
+
+    // The fixed destination is a target without a nugget, the same as a `CONNECT` one.
+    let target: HttpTunnelTarget = HttpTunnelTargetBuilder::default()
+        .target(destination)
+        .nugget(None)
+        .build()
+        .expect("HttpTunnelTargetBuilder failed");
+
+    let connect_timeout = config.target_connection.connect_timeout;
+    let mut connector: UpstreamProxyConnector<HttpTunnelTarget, DnsResolver> =
+        new_target_connector(config, client_addr, proxy_ctx.dns_resolver, ctx)
+            .with_destination_policy(destination_policy);
+
+    let connection = timeout(connect_timeout, connector.connect(&target)).await;
+    let connect_stats = connector.connect_stats();
+
+    match connection {
+        Ok(Ok(upstream)) => {
+            let stats = relay_connections(
+                client,
+                upstream,
+                ctx,
+                config.client_connection.relay_policy.clone(),
+                config.target_connection.relay_policy.clone(),
+                Some(proxy_ctx.shutdown_listener.force_close()),
+                Some(live_tunnel),
+                // There is no user in TCP and transparent modes.
+                proxy_ctx
+                    .bandwidth_limiter
+                    .shared_buckets(&config.bandwidth, None, &target.target),
+            )
+            .await
+            .map(|mut stats| {
+                stats.target = Some(target.target.clone());
+                stats.set_connect_stats(connect_stats);
+                stats
+            });
+
+            report_tunnel_metrics(&proxy_ctx.metrics, ctx, stats);
+        }
+        Ok(Err(e)) => {
+            error!("Failed to connect to {}: {}, CTX={}", target, e, ctx);
+            report_establish_failure(&proxy_ctx.metrics, ctx, EstablishTunnelResult::from(e), Some(target.target), connect_stats);
+        }
+        Err(_) => {
+            error!("Timeout connecting to {}, CTX={}", target, ctx);
+            report_establish_failure(&proxy_ctx.metrics, ctx, EstablishTunnelResult::GatewayTimeout, Some(target.target), connect_stats);
+        }
+    }
+
+    Ok(())
+}
+
 /// tokio::AsyncRead/AsyncWrite https://docs.rs/tokio/1.10.1/tokio/io/trait.AsyncWrite.html
 /// Writes bytes asynchronously.
 /// > The trait inherits from std::io::Write and indicates that an I/O object is nonblocking. 
@@ -225,56 +810,362 @@ async fn serve_plain_text(
 /// ex. Trait, Trait + Send, Trait + Send + Sync, Trait + 'static ...etc
 /// http://web.mit.edu/rust-lang_v1.25/arch/amd64_ubuntu1404/share/doc/rust/html/reference/types.html#trait-objects
 async fn tunnel_stream<C: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
-    config: &ProxyConfiguration,
+    config: &TunnelConfig,
     client: C,
-    dns_resolver: DnsResolver,
+    client_addr: SocketAddr,
+    proxy_ctx: ProxyContext,
 ) -> io::Result<()> {
-    let ctx = TunnelCtxBuilder::default()
-        // thread_rng https://docs.rs/rand/0.6.2/rand/fn.thread_rng.html
-        // > Retrieve the lazily-initialized thread-local random number generator, seeded by the system
-        // performance benchmark https://qiita.com/hhatto/items/c1f311eb80280c26b7e8
-        // We got an error withdout Rng trait, because Rng trait defined get()
-        // > https://docs.rs/rand/0.5.0/rand/trait.Rng.html
-        .id(thread_rng().gen::<u128>())
-        .build()
-        .expect("TunnelCtxBuilder failed");
+    let ctx = new_tunnel_ctx(client_addr);
 
     let codec: HttpTunnelCodec = HttpTunnelCodecBuilder::default()
         .tunnel_ctx(ctx)
         .enabled_targets(
-            config 
-                .tunnel_config
+            config
                 .target_connection
                 .allowed_targets
                 .clone(),
         )
+        .authentication(
+            config
+                .client_connection
+                .authentication
+                .clone(),
+        )
+        .access_control(
+            config
+                .target_connection
+                .access_control
+                .clone(),
+        )
+        .client_addr(Some(client_addr))
         .build()
         .expect("HttpTunnelCodecBuilder failed");
-    
-    let connector: SimpleTcpConnector<HttpTunnelTarget, DnsResolver> = SimpleTcpConnector::new(
-        dns_resolver,
-        config.tunnel_config.target_connection.connect_timeout,
-        ctx,
+
+    run_tunnel(config, codec, client, client_addr, ctx, proxy_ctx).await
+}
+
+/// SOCKS5 counterpart of `tunnel_stream`: the method negotiation comes first, then the codec takes over.
+async fn socks5_stream<C: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+    config: &TunnelConfig,
+    mut client: C,
+    client_addr: SocketAddr,
+    proxy_ctx: ProxyContext,
+) -> io::Result<()> {
+    let ctx = new_tunnel_ctx(client_addr);
+
+    let negotiation = timeout(
+        config.client_connection.initiation_timeout,
+        negotiate(&mut client, config.client_connection.authentication.as_ref(), ctx),
+    )
+    .await;
+
+    let user = match negotiation {
+        Ok(Ok(user)) => user,
+        Ok(Err(result)) => {
+            error!("SOCKS5 negotiation failed: {:?}, CTX={}", result, ctx);
+            report_establish_failure(&proxy_ctx.metrics, ctx, result, None, None);
+            return Ok(());
+        }
+        Err(_) => {
+            error!(
+                "Client failed SOCKS5 negotiation within {:?}, CTX={}",
+                config.client_connection.initiation_timeout, ctx
+            );
+            report_establish_failure(&proxy_ctx.metrics, ctx, EstablishTunnelResult::RequestTimeout, None, None);
+            return Ok(());
+        }
+    };
+
+    let codec: Socks5Codec = Socks5CodecBuilder::default()
+        .tunnel_ctx(ctx)
+        .enabled_targets(config.target_connection.allowed_targets.clone())
+        .access_control(config.target_connection.access_control.clone())
+        .client_addr(Some(client_addr))
+        .user(user)
+        .build()
+        .expect("Socks5CodecBuilder failed");
+
+    run_tunnel(config, codec, client, client_addr, ctx, proxy_ctx).await
+}
+
+/// SNI counterpart of `tunnel_stream`, the `ClientHello` is passed on to the target.
+async fn sni_stream<C: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+    config: &TunnelConfig,
+    client: C,
+    client_addr: SocketAddr,
+    proxy_ctx: ProxyContext,
+) -> io::Result<()> {
+    let ctx = new_tunnel_ctx(client_addr);
+
+    let codec: SniCodec = SniCodecBuilder::default()
+        .tunnel_ctx(ctx)
+        .enabled_targets(config.target_connection.allowed_targets.clone())
+        .access_control(config.target_connection.access_control.clone())
+        .client_addr(Some(client_addr))
+        .build()
+        .expect("SniCodecBuilder failed");
+
+    run_tunnel(config, codec, client, client_addr, ctx, proxy_ctx).await
+}
+
+/// Runs a `ConnectionTunnel` with any handshake codec, from the tunnel request until the relays are closed.
+async fn run_tunnel<H, C>(
+    config: &TunnelConfig,
+    codec: H,
+    client: C,
+    client_addr: SocketAddr,
+    ctx: TunnelCtx,
+    proxy_ctx: ProxyContext,
+) -> io::Result<()>
+where
+    H: Decoder<Item = HttpTunnelTarget, Error = EstablishTunnelResult>
+        + Encoder<EstablishTunnelResult>
+        + Send
+        + 'static,
+    C: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let connector = new_target_connector(config, client_addr, proxy_ctx.dns_resolver, ctx).with_destination_policy(
+        config
+            .target_connection
+            .destination_policy
+            .clone(),
     );
 
-    let stats = ConnectionTunnel::new(codec, connector, client, config.tunnel_config.clone(), ctx)
+    let registration = proxy_ctx.registry.register(ctx, client_addr);
+
+    let stats = ConnectionTunnel::new(codec, connector, client, config.clone(), ctx)
+        .with_concurrency_limiter(proxy_ctx.concurrency_limiter, client_addr.ip())
+        .with_force_close(proxy_ctx.shutdown_listener.force_close())
+        .with_live_tunnel(registration.tunnel())
+        .with_bandwidth_limiter(proxy_ctx.bandwidth_limiter)
         .start()
         .await;
 
-    report_tunnel_metrics(ctx, stats);
+    report_tunnel_metrics(&proxy_ctx.metrics, ctx, stats);
 
     Ok(())
 }
 
+/// The connector checks the access rules once more, with the resolved target address.
+/// It denies internal networks, set the configured destination policy with `with_destination_policy`.
+/// Targets matching `upstream_proxies` are connected through a parent proxy instead.
+fn new_target_connector(
+    config: &TunnelConfig,
+    client_addr: SocketAddr,
+    dns_resolver: DnsResolver,
+    ctx: TunnelCtx,
+) -> UpstreamProxyConnector<HttpTunnelTarget, DnsResolver> {
+    let direct = SimpleTcpConnector::new(
+        dns_resolver.clone(),
+        config.target_connection.connect_timeout,
+        ctx,
+    )
+    .with_access_control(
+        config.target_connection.access_control.clone(),
+        client_addr.ip(),
+    );
+
+    UpstreamProxyConnector::new(
+        direct,
+        config.target_connection.upstream_proxies.clone(),
+        dns_resolver,
+        config.target_connection.connect_timeout,
+        ctx,
+    )
+}
+
+fn new_tunnel_ctx(client_addr: SocketAddr) -> TunnelCtx {
+    TunnelCtxBuilder::default()
+        // thread_rng https://docs.rs/rand/0.6.2/rand/fn.thread_rng.html
+        // > Retrieve the lazily-initialized thread-local random number generator, seeded by the system
+        // performance benchmark https://qiita.com/hhatto/items/c1f311eb80280c26b7e8
+        // We got an error withdout Rng trait, because Rng trait defined get()
+        // > https://docs.rs/rand/0.5.0/rand/trait.Rng.html
+        .id(thread_rng().gen::<u128>())
+        .client_addr(Some(client_addr))
+        .build()
+        .expect("TunnelCtxBuilder failed")
+}
+
+/// Reports a tunnel that failed without relaying any data, e.g. on a TLS handshake error.
+fn report_establish_failure(
+    metrics: &Metrics,
+    ctx: TunnelCtx,
+    result: EstablishTunnelResult,
+    target: Option<String>,
+    connect_stats: Option<ConnectStats>,
+) {
+    let mut stats = TunnelStatsBuilder::default()
+        .tunnel_ctx(ctx)
+        .result(result)
+        .upstream_stats(None)
+        .downstream_stats(None)
+        .target(target)
+        .build()
+        .expect("TunnelStatsBuilder failed");
+    stats.set_connect_stats(connect_stats);
+
+    report_tunnel_metrics(metrics, ctx, Ok(stats));
+}
+
+/// Periodically writes the live tunnel counts to the metrics log.
+async fn report_concurrency(concurrency_limiter: Arc<ConcurrencyLimiter>) {
+    let mut interval = tokio::time::interval(CONCURRENCY_REPORT_INTERVAL);
+    loop {
+        interval.tick().await;
+        info!(
+            target: "metrics",
+            "{{\"concurrency\":{}}}",
+            serde_json::to_string(&concurrency_limiter.snapshot()).expect("JSON serializtion failed")
+        );
+    }
+}
+
 /// (Original comments)
 /// Placeholder for proper metrics emission.
 /// Here we just write to a file without any aggregation.
-fn report_tunnel_metrics(ctx: TunnelCtx, stats: io::Result<TunnelStats>) {
+///
+/// The stats are also aggregated into `metrics`, served by the metrics endpoint.
+fn report_tunnel_metrics(metrics: &Metrics, ctx: TunnelCtx, stats: io::Result<TunnelStats>) {
     match stats {
         Ok(s) => {
+            metrics.record(&s);
             info!(target: "metrics", "{}", serde_json::to_string(&s).expect("JSON serializtion failed"))
         }
         // What's TID
         Err(_) => error!("Failed to get stats for TID={}", ctx),
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::ProxyConfigurationBuilder;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Self-signed for `localhost`, generated with
+    /// `openssl req -x509 -newkey rsa:2048 -nodes -days 3650 -subj "/CN=localhost"`
+    /// and exported with `openssl pkcs12 -export -certpbe PBE-SHA1-3DES -keypbe PBE-SHA1-3DES -macalg sha1`,
+    /// which OpenSSL 1.1 and 3 both read.
+    const TEST_IDENTITY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/config/test/identity.p12");
+    const TEST_IDENTITY_PASSWORD: &str = "copying";
+
+    const CONFIG: &str = "
+client_connection:
+  initiation_timeout: 10s
+  relay_policy: {idle_timeout: 30s, min_rate_bpm: 0, max_rate_bpm: 10000000}
+target_connection:
+  dns_cache_ttl: 60s
+  allowed_targets: \".*\"
+  connect_timeout: 5s
+  relay_policy: {idle_timeout: 30s, min_rate_bpm: 0, max_rate_bpm: 10000000}
+  destination_policy: {allowed_networks: [127.0.0.0/8]}
+";
+
+    fn proxy_ctx(tunnel_config: &TunnelConfig, shutdown: &Shutdown) -> ProxyContext {
+        let target_connection = &tunnel_config.target_connection;
+        ProxyContext {
+            tunnel_config: SharedTunnelConfig::new(tunnel_config.clone()),
+            dns_resolver: SimpleCachingDnsResolver::new(
+                target_connection.dns_cache_ttl,
+                target_connection.dns_cache_size,
+                target_connection.dns_negative_cache_ttl,
+            ),
+            concurrency_limiter: Arc::new(ConcurrencyLimiter::default()),
+            bandwidth_limiter: Arc::new(BandwidthLimiter::default()),
+            metrics: Arc::new(Metrics::default()),
+            registry: Arc::new(TunnelRegistry::default()),
+            shutdown_listener: shutdown.listener(),
+        }
+    }
+
+    /// Echoes one connection back.
+    async fn echo_target() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut read, mut write) = stream.split();
+            io::copy(&mut read, &mut write).await.unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn connect_over_tls() {
+        let identity = std::fs::read(TEST_IDENTITY).unwrap();
+        let identity = Identity::from_pkcs12(&identity, TEST_IDENTITY_PASSWORD).unwrap();
+        let tls_acceptor = new_tls_acceptor(&identity).unwrap();
+
+        let tunnel_config = TunnelConfig::from_yaml(CONFIG.as_bytes()).unwrap();
+        let shutdown = Shutdown::new();
+        let proxy_ctx = proxy_ctx(&tunnel_config, &shutdown);
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let config = ProxyConfigurationBuilder::default()
+            .mode(ProxyMode::HTTPS(identity))
+            .bind_address(proxy_addr.to_string())
+            .tunnel_config(tunnel_config)
+            .build()
+            .unwrap();
+        tokio::spawn(async move { serve_tls(&config, &mut listener, tls_acceptor, proxy_ctx).await });
+
+        let target_addr = echo_target().await;
+
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        let connector = tokio_native_tls::TlsConnector::from(connector);
+        let stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let mut stream = connector.connect("localhost", stream).await.unwrap();
+
+        let request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target_addr);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = [0u8; 19];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200 OK\r\n\r\n");
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut echo = [0u8; 4];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+    }
+
+    /// The proxy's end of a loopback connection, the header is already sent.
+    async fn accepted(header: &[u8]) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        client.write_all(header).await.unwrap();
+        // Closed once the header is sent, so a read past it fails rather than hangs.
+        client.shutdown().await.unwrap();
+        listener.accept().await.unwrap().0
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol_only_from_trusted_networks() {
+        let yaml = CONFIG.replacen(
+            "client_connection:\n",
+            "client_connection:\n  proxy_protocol: {trusted_networks: [10.0.0.0/8]}\n",
+            1,
+        );
+        let tunnel_config = TunnelConfig::from_yaml(yaml.as_bytes()).unwrap();
+        let shutdown = Shutdown::new();
+        let proxy_ctx = proxy_ctx(&tunnel_config, &shutdown);
+        let header = b"PROXY TCP4 192.0.2.1 10.0.0.1 51234 443\r\n";
+
+        let mut stream = accepted(header).await;
+        let peer_addr = "127.0.0.1:40000".parse().unwrap();
+        assert_eq!(accept_proxy_protocol(&tunnel_config, &mut stream, peer_addr, &proxy_ctx).await, None);
+
+        let mut stream = accepted(header).await;
+        let peer_addr = "10.0.0.2:40000".parse().unwrap();
+        assert_eq!(
+            accept_proxy_protocol(&tunnel_config, &mut stream, peer_addr, &proxy_ctx).await,
+            Some("192.0.2.1:51234".parse().unwrap())
+        );
+
+        let mut stream = accepted(b"PROXY TCP4 192.0.2.1").await;
+        assert_eq!(accept_proxy_protocol(&tunnel_config, &mut stream, peer_addr, &proxy_ctx).await, None);
+    }
+}
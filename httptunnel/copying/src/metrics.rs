@@ -0,0 +1,184 @@
+use crate::dns_cache::DnsCacheSnapshot;
+use crate::limits::ConcurrencySnapshot;
+use crate::relay::RelayStats;
+use crate::tunnel::TunnelStats;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Upper bounds in seconds, connections are expected to be quick.
+const CONNECT_LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 10.,
+];
+/// Upper bounds in seconds, tunnels may stay open for hours.
+const RELAY_DURATION_BUCKETS: [f64; 12] = [
+    0.1, 0.5, 1., 5., 10., 30., 60., 300., 600., 1800., 3600., 14400.,
+];
+
+/// Aggregates `TunnelStats` of closed tunnels.
+/// Served in the Prometheus text format.
+/// https://prometheus.io/docs/instrumenting/exposition_formats/#text-based-format
+#[derive(Default)]
+pub struct Metrics {
+    registry: Mutex<Registry>,
+}
+
+#[derive(Default)]
+struct Registry {
+    tunnels: BTreeMap<String, u64>,
+    relay_bytes: BTreeMap<&'static str, u64>,
+    relay_shutdowns: BTreeMap<(&'static str, String), u64>,
+    relay_duration: BTreeMap<&'static str, Histogram>,
+    connect_latency: Option<Histogram>,
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    // cumulative counts are computed on render
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter()) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, separator, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, separator, self.count);
+        let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+impl Metrics {
+    pub fn record(&self, stats: &TunnelStats) {
+        let mut registry = self.registry.lock().expect("Poisoned lock");
+
+        *registry.tunnels.entry(format!("{:?}", stats.result)).or_insert(0) += 1;
+
+        for (direction, relay_stats) in [
+            ("upstream", &stats.upstream_stats),
+            ("downstream", &stats.downstream_stats),
+        ] {
+            if let Some(relay_stats) = relay_stats {
+                registry.record_relay(direction, relay_stats);
+            }
+        }
+
+        if let Some(connect_stats) = &stats.connect_stats {
+            if let Some(latency) = connect_stats.connect_latency() {
+                registry
+                    .connect_latency
+                    .get_or_insert_with(|| Histogram::new(&CONNECT_LATENCY_BUCKETS))
+                    .observe(latency.as_secs_f64());
+            }
+        }
+    }
+
+    pub fn render(&self, concurrency: &ConcurrencySnapshot, dns_cache: &DnsCacheSnapshot) -> String {
+        let registry = self.registry.lock().expect("Poisoned lock");
+        let mut out = String::new();
+
+        header(&mut out, "copying_tunnels_total", "counter", "Closed tunnels by result");
+        for (result, count) in registry.tunnels.iter() {
+            let _ = writeln!(out, "copying_tunnels_total{{result=\"{}\"}} {}", result, count);
+        }
+
+        header(&mut out, "copying_relay_bytes_total", "counter", "Relayed bytes by direction, upstream is client to target");
+        for (direction, bytes) in registry.relay_bytes.iter() {
+            let _ = writeln!(out, "copying_relay_bytes_total{{direction=\"{}\"}} {}", direction, bytes);
+        }
+
+        header(&mut out, "copying_relay_shutdowns_total", "counter", "Closed relays by direction and shutdown reason");
+        for ((direction, reason), count) in registry.relay_shutdowns.iter() {
+            let _ = writeln!(
+                out,
+                "copying_relay_shutdowns_total{{direction=\"{}\",reason=\"{}\"}} {}",
+                direction, reason, count
+            );
+        }
+
+        header(&mut out, "copying_relay_duration_seconds", "histogram", "Relay durations by direction");
+        for (direction, histogram) in registry.relay_duration.iter() {
+            histogram.render(&mut out, "copying_relay_duration_seconds", &format!("direction=\"{}\"", direction));
+        }
+
+        header(&mut out, "copying_dns_cache_lookups_total", "counter", "DNS look-ups by cache result, coalesced ones waited for a look-up in progress");
+        for (result, count) in [
+            ("hit", dns_cache.hits),
+            ("negative_hit", dns_cache.negative_hits),
+            ("miss", dns_cache.misses),
+            ("coalesced", dns_cache.coalesced),
+        ] {
+            let _ = writeln!(out, "copying_dns_cache_lookups_total{{result=\"{}\"}} {}", result, count);
+        }
+        header(&mut out, "copying_dns_cache_evictions_total", "counter", "DNS cache entries evicted to make room");
+        let _ = writeln!(out, "copying_dns_cache_evictions_total {}", dns_cache.evictions);
+        header(&mut out, "copying_dns_cache_entries", "gauge", "Names in the DNS cache, including expired ones not evicted yet");
+        let _ = writeln!(out, "copying_dns_cache_entries {}", dns_cache.entries);
+
+        header(&mut out, "copying_connect_latency_seconds", "histogram", "Time to connect to a target, since the first attempt");
+        if let Some(histogram) = &registry.connect_latency {
+            histogram.render(&mut out, "copying_connect_latency_seconds", "");
+        }
+
+        header(&mut out, "copying_active_tunnels", "gauge", "Tunnels open right now");
+        let _ = writeln!(out, "copying_active_tunnels {}", concurrency.tunnels);
+        header(&mut out, "copying_active_clients", "gauge", "Distinct client addresses with open tunnels");
+        let _ = writeln!(out, "copying_active_clients {}", concurrency.clients);
+        header(&mut out, "copying_concurrency_rejections_total", "counter", "Tunnels rejected by a concurrency limit");
+        for (limit, count) in [
+            ("total", concurrency.rejected_total),
+            ("client", concurrency.rejected_per_client),
+            ("target", concurrency.rejected_per_target),
+        ] {
+            let _ = writeln!(out, "copying_concurrency_rejections_total{{limit=\"{}\"}} {}", limit, count);
+        }
+
+        out
+    }
+}
+
+impl Registry {
+    fn record_relay(&mut self, direction: &'static str, relay_stats: &RelayStats) {
+        *self.relay_bytes.entry(direction).or_insert(0) += relay_stats.total_bytes as u64;
+        *self
+            .relay_shutdowns
+            .entry((direction, format!("{:?}", relay_stats.shutdown_reason)))
+            .or_insert(0) += 1;
+        self.relay_duration
+            .entry(direction)
+            .or_insert_with(|| Histogram::new(&RELAY_DURATION_BUCKETS))
+            .observe(relay_stats.duration.as_secs_f64());
+    }
+}
+
+fn header(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, metric_type);
+}
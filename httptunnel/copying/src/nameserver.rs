@@ -0,0 +1,540 @@
+use crate::dns_cache::Resolved;
+use crate::proxy_target::DnsResolver;
+
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, BytesMut};
+use log::{debug, info, warn};
+use rand::{thread_rng, Rng};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Error, ErrorKind};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{timeout, Duration};
+
+/// Each nameserver gets this long to answer before the next one is asked.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A UDP reply can't be larger without EDNS, longer ones are truncated.
+/// https://datatracker.ietf.org/doc/html/rfc1035#section-4.2.1
+const MAX_UDP_MESSAGE_SIZE: usize = 512;
+
+const HEADER_SIZE: usize = 12;
+
+/// https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.1
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_MASK: u16 = 0x000F;
+const RCODE_NO_ERROR: u16 = 0;
+const RCODE_NAME_ERROR: u16 = 3;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+/// Host names pinned to addresses, like `/etc/hosts`.
+pub type DnsOverrides = HashMap<String, Vec<IpAddr>>;
+
+/// Looks up host names by asking the configured nameservers (recursive resolvers) directly,
+/// over UDP and over TCP if the reply is truncated. The first nameserver that answers wins.
+/// https://datatracker.ietf.org/doc/html/rfc1035
+///
+/// Pinned names are answered from `overrides` without a query.
+#[derive(Clone)]
+pub struct NameserverResolver {
+    nameservers: Arc<Vec<SocketAddr>>,
+    overrides: Arc<DnsOverrides>,
+}
+
+impl NameserverResolver {
+    pub fn new(nameservers: Vec<SocketAddr>, overrides: DnsOverrides) -> Self {
+        Self {
+            nameservers: Arc::new(nameservers),
+            overrides: Arc::new(lowercase_keys(overrides)),
+        }
+    }
+
+    /// The pinned addresses of `host`, names are case-insensitive.
+    pub fn find_override(&self, host: &str) -> Option<&Vec<IpAddr>> {
+        self.overrides.get(&host.to_ascii_lowercase())
+    }
+
+    pub fn has_nameservers(&self) -> bool {
+        !self.nameservers.is_empty()
+    }
+
+    /// Queries A and AAAA records at once.
+    /// The TTL is the shortest of the records, so the cached result is valid as long as all of them are.
+    pub async fn lookup(&self, host: &str, port: u16) -> io::Result<Resolved> {
+        let (ipv4, ipv6) = tokio::join!(self.query(host, TYPE_A), self.query(host, TYPE_AAAA));
+
+        let mut addrs = vec![];
+        let mut ttl: Option<Duration> = None;
+        let mut last_error = None;
+        for answer in [ipv6, ipv4] {
+            match answer {
+                Ok(answer) => {
+                    addrs.extend(answer.addrs.into_iter().map(|ip| SocketAddr::new(ip, port)));
+                    ttl = match (ttl, answer.ttl) {
+                        (Some(ttl), Some(answer_ttl)) => Some(ttl.min(answer_ttl)),
+                        (ttl, answer_ttl) => ttl.or(answer_ttl),
+                    };
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        if addrs.is_empty() {
+            warn!("Cannot resolve DNS {}", host);
+            return Err(last_error.unwrap_or_else(|| Error::from(ErrorKind::AddrNotAvailable)));
+        }
+        info!("Resolved DNS {} to {:?}", host, addrs);
+
+        Ok(Resolved { addrs, ttl })
+    }
+
+    /// Asks the nameservers in turn until one of them answers.
+    async fn query(&self, host: &str, record_type: u16) -> io::Result<Answer> {
+        let mut last_error = Error::from(ErrorKind::AddrNotAvailable);
+        for nameserver in self.nameservers.iter() {
+            let id = thread_rng().gen::<u16>();
+            let query = encode_query(id, host, record_type)?;
+
+            match timeout(QUERY_TIMEOUT, exchange(*nameserver, id, &query)).await {
+                Ok(Ok(Reply::Answer(answer))) => return Ok(answer),
+                // An authoritative "no such name", another nameserver won't know better.
+                Ok(Ok(Reply::NameError)) => {
+                    debug!("Nameserver {}: no such name {}", nameserver, host);
+                    return Err(Error::new(ErrorKind::AddrNotAvailable, format!("NXDOMAIN {}", host)));
+                }
+                Ok(Ok(Reply::Failure(rcode))) => {
+                    debug!("Nameserver {} failed to resolve {}: rcode {}", nameserver, host, rcode);
+                    last_error = Error::other(format!("Nameserver {} replied rcode {}", nameserver, rcode));
+                }
+                Ok(Err(e)) => {
+                    debug!("Nameserver {} failed to resolve {}: {}", nameserver, host, e);
+                    last_error = e;
+                }
+                Err(_) => {
+                    debug!("Nameserver {} timed out resolving {}", nameserver, host);
+                    last_error = Error::from(ErrorKind::TimedOut);
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
+
+fn lowercase_keys(overrides: DnsOverrides) -> DnsOverrides {
+    overrides
+        .into_iter()
+        .map(|(host, addrs)| (host.to_ascii_lowercase(), addrs))
+        .collect()
+}
+
+/// Splits `host:port`, the host of an IPv6 literal is in brackets.
+pub fn split_target(target: &str) -> io::Result<(&str, u16)> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid target {}", target));
+    let colon = target.rfind(':').ok_or_else(invalid)?;
+    let port = target[colon + 1..].parse::<u16>().map_err(|_| invalid())?;
+    let host = target[..colon].trim_start_matches('[').trim_end_matches(']');
+    Ok((host, port))
+}
+
+/// Without a cache, see `SimpleCachingDnsResolver::with_nameservers` for the cached one.
+#[async_trait]
+impl DnsResolver for NameserverResolver {
+    async fn resolve(&mut self, target: &str) -> io::Result<Vec<SocketAddr>> {
+        let (host, port) = split_target(target)?;
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        if let Some(ips) = self.find_override(host) {
+            return Ok(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect());
+        }
+        Ok(self.lookup(host, port).await?.addrs)
+    }
+}
+
+struct Answer {
+    addrs: Vec<IpAddr>,
+    // `None` if there are no records, e.g. no AAAA records for an IPv4-only host
+    ttl: Option<Duration>,
+}
+
+enum Reply {
+    Answer(Answer),
+    NameError,
+    Failure(u16),
+}
+
+/// Sends the query over UDP, and again over TCP if the reply doesn't fit into a datagram.
+async fn exchange(nameserver: SocketAddr, id: u16, query: &[u8]) -> io::Result<Reply> {
+    let local_addr: SocketAddr = match nameserver {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local_addr).await?;
+    // A connected socket receives datagrams only from the nameserver.
+    socket.connect(nameserver).await?;
+    socket.send(query).await?;
+
+    let mut buffer = vec![0; MAX_UDP_MESSAGE_SIZE];
+    loop {
+        let size = socket.recv(&mut buffer).await?;
+        let message = &buffer[..size];
+        // A late reply to an earlier query, or a spoofed one.
+        if size < HEADER_SIZE || u16::from_be_bytes([message[0], message[1]]) != id {
+            continue;
+        }
+        if u16::from_be_bytes([message[2], message[3]]) & FLAG_TRUNCATED == 0 {
+            return decode_reply(message);
+        }
+        break;
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc7766#section-8
+    // > DNS clients and servers SHOULD pass the two-octet length field, and the message described by that length field, to the TCP layer at the same time
+    debug!("Truncated reply from {}, retrying over TCP", nameserver);
+    let mut stream = TcpStream::connect(nameserver).await?;
+    let mut message = BytesMut::with_capacity(2 + query.len());
+    message.put_u16(query.len() as u16);
+    message.put_slice(query);
+    stream.write_all(&message).await?;
+
+    let size = stream.read_u16().await? as usize;
+    let mut reply = vec![0; size];
+    stream.read_exact(&mut reply).await?;
+    if size < HEADER_SIZE || u16::from_be_bytes([reply[0], reply[1]]) != id {
+        return Err(Error::new(ErrorKind::InvalidData, "Mismatched DNS reply"));
+    }
+    decode_reply(&reply)
+}
+
+/// https://datatracker.ietf.org/doc/html/rfc1035#section-4.1
+fn encode_query(id: u16, host: &str, record_type: u16) -> io::Result<Vec<u8>> {
+    let mut query = BytesMut::new();
+    query.put_u16(id);
+    query.put_u16(FLAG_RECURSION_DESIRED);
+    // QDCOUNT, ANCOUNT, NSCOUNT, ARCOUNT
+    query.put_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    // > a domain name represented as a sequence of labels, where each label consists of a length octet followed by that number of octets.
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid host name {}", host)));
+        }
+        query.put_u8(label.len() as u8);
+        query.put_slice(label.as_bytes());
+    }
+    query.put_u8(0);
+    query.put_u16(record_type);
+    query.put_u16(CLASS_IN);
+    Ok(query.to_vec())
+}
+
+/// Collects the addresses of all A and AAAA records in the answer section.
+/// A recursive resolver answers with the whole CNAME chain, the addresses are those of its end.
+fn decode_reply(message: &[u8]) -> io::Result<Reply> {
+    let malformed = || Error::new(ErrorKind::InvalidData, "Malformed DNS reply");
+
+    let mut reader = message;
+    reader.advance(2);
+    let flags = reader.get_u16();
+    let questions = reader.get_u16();
+    let answers = reader.get_u16();
+    reader.advance(4);
+
+    if flags & FLAG_RESPONSE == 0 {
+        return Err(malformed());
+    }
+    match flags & RCODE_MASK {
+        RCODE_NO_ERROR => {}
+        RCODE_NAME_ERROR => return Ok(Reply::NameError),
+        rcode => return Ok(Reply::Failure(rcode)),
+    }
+
+    for _ in 0..questions {
+        skip_name(&mut reader).ok_or_else(malformed)?;
+        if reader.remaining() < 4 {
+            return Err(malformed());
+        }
+        // QTYPE, QCLASS
+        reader.advance(4);
+    }
+
+    let mut addrs = vec![];
+    let mut ttl: Option<u32> = None;
+    for _ in 0..answers {
+        skip_name(&mut reader).ok_or_else(malformed)?;
+        if reader.remaining() < 10 {
+            return Err(malformed());
+        }
+        let record_type = reader.get_u16();
+        let class = reader.get_u16();
+        let record_ttl = reader.get_u32();
+        let size = reader.get_u16() as usize;
+        if reader.remaining() < size {
+            return Err(malformed());
+        }
+        let data = &reader[..size];
+        reader.advance(size);
+
+        let addr = match (record_type, class, size) {
+            (TYPE_A, CLASS_IN, 4) => IpAddr::from([data[0], data[1], data[2], data[3]]),
+            (TYPE_AAAA, CLASS_IN, 16) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(data);
+                IpAddr::from(octets)
+            }
+            _ => continue,
+        };
+        addrs.push(addr);
+        ttl = Some(ttl.map_or(record_ttl, |ttl| ttl.min(record_ttl)));
+    }
+
+    Ok(Reply::Answer(Answer {
+        addrs,
+        ttl: ttl.map(|ttl| Duration::from_secs(ttl as u64)),
+    }))
+}
+
+/// Skips a (possibly compressed) name, returns `None` if it runs past the message.
+/// https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.4
+fn skip_name(reader: &mut &[u8]) -> Option<()> {
+    loop {
+        let length = *reader.first()?;
+        match length {
+            0 => {
+                reader.advance(1);
+                return Some(());
+            }
+            // A pointer to a name elsewhere in the message ends this one.
+            _ if length & 0xC0 == 0xC0 => {
+                if reader.len() < 2 {
+                    return None;
+                }
+                reader.advance(2);
+                return Some(());
+            }
+            _ => {
+                if reader.len() < 1 + length as usize {
+                    return None;
+                }
+                reader.advance(1 + length as usize);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_target::SimpleCachingDnsResolver;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+
+    const TYPE_CNAME: u16 = 5;
+    const RCODE_SERVER_FAILURE: u16 = 2;
+
+    /// A reply to `query`, the names of the records point to the question.
+    fn reply(query: &[u8], flags: u16, records: &[(u16, Vec<u8>, u32)]) -> Vec<u8> {
+        let mut reply = BytesMut::new();
+        reply.put_slice(&query[..2]);
+        reply.put_u16(FLAG_RESPONSE | flags);
+        reply.put_u16(1);
+        reply.put_u16(records.len() as u16);
+        reply.put_u32(0);
+        reply.put_slice(&query[HEADER_SIZE..]);
+        for (record_type, data, ttl) in records {
+            reply.put_u16(0xC000 | HEADER_SIZE as u16);
+            reply.put_u16(*record_type);
+            reply.put_u16(CLASS_IN);
+            reply.put_u32(*ttl);
+            reply.put_u16(data.len() as u16);
+            reply.put_slice(data);
+        }
+        reply.to_vec()
+    }
+
+    fn record_type(query: &[u8]) -> u16 {
+        u16::from_be_bytes([query[query.len() - 4], query[query.len() - 3]])
+    }
+
+    /// Answers 192.0.2.1 and 2001:db8::1, and NXDOMAIN for `missing.test`.
+    fn answer(query: &[u8]) -> Vec<u8> {
+        if query.windows(7).any(|label| label == b"missing") {
+            return reply(query, RCODE_NAME_ERROR, &[]);
+        }
+        match record_type(query) {
+            TYPE_A => reply(query, 0, &[(TYPE_A, vec![192, 0, 2, 1], 300)]),
+            _ => reply(
+                query,
+                0,
+                &[(TYPE_AAAA, "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec(), 60)],
+            ),
+        }
+    }
+
+    struct StubNameserver {
+        addr: SocketAddr,
+        udp_queries: Arc<AtomicUsize>,
+        tcp_queries: Arc<AtomicUsize>,
+    }
+
+    /// Serves `answer` over UDP and TCP on the same port, UDP replies are truncated if `truncate`.
+    async fn stub_nameserver(truncate: bool) -> StubNameserver {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = UdpSocket::bind(addr).await.unwrap();
+        let udp_queries = Arc::new(AtomicUsize::new(0));
+        let tcp_queries = Arc::new(AtomicUsize::new(0));
+
+        let queries = udp_queries.clone();
+        tokio::spawn(async move {
+            let mut buffer = vec![0; MAX_UDP_MESSAGE_SIZE];
+            loop {
+                let (size, peer) = socket.recv_from(&mut buffer).await.unwrap();
+                queries.fetch_add(1, Ordering::SeqCst);
+                let query = &buffer[..size];
+                let message = if truncate { reply(query, FLAG_TRUNCATED, &[]) } else { answer(query) };
+                socket.send_to(&message, peer).await.unwrap();
+            }
+        });
+
+        let queries = tcp_queries.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                queries.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let size = stream.read_u16().await.unwrap() as usize;
+                    let mut query = vec![0; size];
+                    stream.read_exact(&mut query).await.unwrap();
+                    let message = answer(&query);
+                    stream.write_u16(message.len() as u16).await.unwrap();
+                    stream.write_all(&message).await.unwrap();
+                });
+            }
+        });
+
+        StubNameserver { addr, udp_queries, tcp_queries }
+    }
+
+    fn decoded(message: &[u8]) -> Answer {
+        match decode_reply(message).unwrap() {
+            Reply::Answer(answer) => answer,
+            _ => panic!("Not an answer"),
+        }
+    }
+
+    #[test]
+    fn decode_reply_skips_cnames_and_takes_the_shortest_ttl() {
+        let query = encode_query(7, "www.example.test", TYPE_A).unwrap();
+        let cname = vec![3, b'w', b'e', b'b', 0xC0, HEADER_SIZE as u8];
+        let message = reply(
+            &query,
+            0,
+            &[(TYPE_CNAME, cname, 10), (TYPE_A, vec![192, 0, 2, 1], 300), (TYPE_A, vec![192, 0, 2, 2], 30)],
+        );
+
+        let answer = decoded(&message);
+        assert_eq!(answer.addrs, vec![IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2])]);
+        assert_eq!(answer.ttl, Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn decode_reply_without_records_has_no_ttl() {
+        let query = encode_query(7, "example.test", TYPE_AAAA).unwrap();
+        let answer = decoded(&reply(&query, 0, &[]));
+        assert!(answer.addrs.is_empty());
+        assert_eq!(answer.ttl, None);
+    }
+
+    #[test]
+    fn decode_reply_rcodes() {
+        let query = encode_query(7, "example.test", TYPE_A).unwrap();
+        assert!(matches!(decode_reply(&reply(&query, RCODE_NAME_ERROR, &[])), Ok(Reply::NameError)));
+        assert!(matches!(
+            decode_reply(&reply(&query, RCODE_SERVER_FAILURE, &[])),
+            Ok(Reply::Failure(RCODE_SERVER_FAILURE))
+        ));
+    }
+
+    #[test]
+    fn decode_reply_rejects_malformed_messages() {
+        let query = encode_query(7, "example.test", TYPE_A).unwrap();
+        // A query isn't a reply.
+        assert!(decode_reply(&query).is_err());
+
+        let message = reply(&query, 0, &[(TYPE_A, vec![192, 0, 2, 1], 300)]);
+        for size in [HEADER_SIZE + 3, message.len() - 10, message.len() - 1] {
+            assert_eq!(
+                decode_reply(&message[..size]).err().map(|e| e.kind()),
+                Some(ErrorKind::InvalidData),
+                "truncated to {} bytes",
+                size
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn lookup_joins_a_and_aaaa_records() {
+        let stub = stub_nameserver(false).await;
+        let resolver = NameserverResolver::new(vec![stub.addr], DnsOverrides::default());
+
+        let resolved = resolver.lookup("example.test", 443).await.unwrap();
+        assert_eq!(
+            resolved.addrs,
+            vec!["[2001:db8::1]:443".parse().unwrap(), "192.0.2.1:443".parse().unwrap()]
+        );
+        assert_eq!(resolved.ttl, Some(Duration::from_secs(60)));
+        assert_eq!(stub.udp_queries.load(Ordering::SeqCst), 2);
+        assert_eq!(stub.tcp_queries.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn lookup_retries_truncated_replies_over_tcp() {
+        let stub = stub_nameserver(true).await;
+        let resolver = NameserverResolver::new(vec![stub.addr], DnsOverrides::default());
+
+        let resolved = resolver.lookup("example.test", 443).await.unwrap();
+        assert_eq!(resolved.addrs.len(), 2);
+        assert_eq!(stub.udp_queries.load(Ordering::SeqCst), 2);
+        assert_eq!(stub.tcp_queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn lookup_of_a_missing_name_fails() {
+        let stub = stub_nameserver(false).await;
+        let resolver = NameserverResolver::new(vec![stub.addr], DnsOverrides::default());
+
+        let error = resolver.lookup("missing.test", 443).await.err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::AddrNotAvailable);
+    }
+
+    #[tokio::test]
+    async fn overrides_are_pinned_without_a_query() {
+        let stub = stub_nameserver(false).await;
+        let mut overrides = DnsOverrides::default();
+        overrides.insert("Pinned.Test".to_string(), vec![IpAddr::from([10, 1, 2, 3])]);
+        overrides.insert("empty.test".to_string(), vec![]);
+        let mut resolver = SimpleCachingDnsResolver::new(Duration::from_secs(60), 10, Duration::from_secs(5))
+            .with_nameservers(NameserverResolver::new(vec![stub.addr], overrides));
+
+        let pinned = resolver.resolve("pinned.test:8080").await.unwrap();
+        assert_eq!(pinned, vec!["10.1.2.3:8080".parse().unwrap()]);
+        assert_eq!(resolver.cache_hit(), None);
+        assert_eq!(stub.udp_queries.load(Ordering::SeqCst), 0);
+
+        // Rejected by the configuration, but it must not panic either.
+        assert!(resolver.resolve("empty.test:80").await.unwrap().is_empty());
+        assert_eq!(stub.udp_queries.load(Ordering::SeqCst), 0);
+
+        resolver.resolve("example.test:80").await.unwrap();
+        assert_eq!(resolver.cache_hit(), Some(false));
+        assert_eq!(stub.udp_queries.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,293 @@
+use std::net::SocketAddr;
+use tokio::io;
+use tokio::net::TcpListener;
+
+/// The destination a client connected to before the firewall redirected it to the proxy,
+/// e.g. with `iptables -t nat -A PREROUTING -p tcp --dport 443 -j REDIRECT --to-ports 8443`.
+/// https://www.kernel.org/doc/Documentation/networking/tproxy.txt
+///
+/// NAT (`REDIRECT`) keeps it in conntrack, where `SO_ORIGINAL_DST` reads it from.
+/// `TPROXY` doesn't rewrite the connection, so it's the local address of the socket.
+/// Returns `None` if the connection wasn't redirected, i.e. the client connected to the proxy itself,
+/// relaying it would loop back to the proxy.
+pub fn original_destination<S: RedirectedSocket>(
+    stream: &S,
+    listener_addr: SocketAddr,
+) -> io::Result<Option<SocketAddr>> {
+    let local_addr = unmapped(stream.local_addr()?);
+    let destination = match sys::original_destination(stream) {
+        Ok(destination) => unmapped(destination),
+        // ENOENT: no NAT entry, e.g. TPROXY without conntrack.
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => local_addr,
+        Err(e) => return Err(e),
+    };
+
+    if destination == local_addr && local_addr.port() == listener_addr.port() {
+        Ok(None)
+    } else {
+        Ok(Some(destination))
+    }
+}
+
+/// What `original_destination` needs from the accepted socket, `TcpStream` outside of tests.
+pub trait RedirectedSocket {
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    /// getsockopt(2) into `value`, returns the length the kernel wrote.
+    /// https://man7.org/linux/man-pages/man2/getsockopt.2.html
+    fn getsockopt(&self, level: libc::c_int, name: libc::c_int, value: &mut [u8]) -> io::Result<usize>;
+}
+
+/// IPv4 clients of a dual-stack listener have IPv4-mapped addresses (::ffff:10.0.0.1).
+/// https://doc.rust-lang.org/std/net/struct.Ipv6Addr.html#method.to_ipv4_mapped
+fn unmapped(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(v4.into(), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// `TPROXY` delivers only to listeners with `IP_TRANSPARENT`, setting it needs `CAP_NET_ADMIN`.
+/// https://man7.org/linux/man-pages/man7/ip.7.html
+pub fn set_transparent(listener: &TcpListener) -> io::Result<()> {
+    sys::set_transparent(listener)
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::RedirectedSocket;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::os::unix::io::AsRawFd;
+    use tokio::io;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// linux/netfilter_ipv4.h
+    const SO_ORIGINAL_DST: libc::c_int = 80;
+    /// linux/netfilter_ipv6/ip6_tables.h
+    const IP6T_SO_ORIGINAL_DST: libc::c_int = 80;
+
+    impl RedirectedSocket for TcpStream {
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            TcpStream::local_addr(self)
+        }
+
+        fn getsockopt(&self, level: libc::c_int, name: libc::c_int, value: &mut [u8]) -> io::Result<usize> {
+            let mut len = value.len() as libc::socklen_t;
+            let result = unsafe {
+                libc::getsockopt(
+                    self.as_raw_fd(),
+                    level,
+                    name,
+                    value.as_mut_ptr() as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            if result != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(len as usize)
+        }
+    }
+
+    pub fn original_destination<S: RedirectedSocket>(socket: &S) -> io::Result<SocketAddr> {
+        match socket.local_addr()? {
+            SocketAddr::V4(_) => original_destination_v4(socket),
+            // IPv4 clients of a dual-stack listener have IPv4 conntrack entries.
+            SocketAddr::V6(local) if local.ip().to_ipv4_mapped().is_some() => original_destination_v4(socket),
+            SocketAddr::V6(_) => original_destination_v6(socket),
+        }
+    }
+
+    fn original_destination_v4<S: RedirectedSocket>(socket: &S) -> io::Result<SocketAddr> {
+        let addr: libc::sockaddr_in = read_sockaddr(socket, libc::SOL_IP, SO_ORIGINAL_DST)?;
+        // Both are in network byte order.
+        Ok(SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+            u16::from_be(addr.sin_port),
+        )))
+    }
+
+    fn original_destination_v6<S: RedirectedSocket>(socket: &S) -> io::Result<SocketAddr> {
+        let addr: libc::sockaddr_in6 = read_sockaddr(socket, libc::SOL_IPV6, IP6T_SO_ORIGINAL_DST)?;
+        Ok(SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::from(addr.sin6_addr.s6_addr),
+            u16::from_be(addr.sin6_port),
+            addr.sin6_flowinfo,
+            addr.sin6_scope_id,
+        )))
+    }
+
+    /// `T` is a plain C struct (`sockaddr_in` or `sockaddr_in6`), any bytes make a valid one.
+    fn read_sockaddr<S: RedirectedSocket, T: Copy>(
+        socket: &S,
+        level: libc::c_int,
+        name: libc::c_int,
+    ) -> io::Result<T> {
+        let mut value = vec![0u8; mem::size_of::<T>()];
+        let len = socket.getsockopt(level, name, &mut value)?;
+        if len < value.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Truncated original destination"));
+        }
+        // The buffer isn't aligned for `T`.
+        Ok(unsafe { std::ptr::read_unaligned(value.as_ptr() as *const T) })
+    }
+
+    pub fn set_transparent(listener: &TcpListener) -> io::Result<()> {
+        let (level, name) = match listener.local_addr()? {
+            SocketAddr::V4(_) => (libc::SOL_IP, libc::IP_TRANSPARENT),
+            SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+        };
+        let enabled: libc::c_int = 1;
+        let result = unsafe {
+            libc::setsockopt(
+                listener.as_raw_fd(),
+                level,
+                name,
+                &enabled as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Redirection is a netfilter feature.
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use super::RedirectedSocket;
+    use std::net::SocketAddr;
+    use tokio::io;
+    use tokio::net::{TcpListener, TcpStream};
+
+    impl RedirectedSocket for TcpStream {
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            TcpStream::local_addr(self)
+        }
+
+        fn getsockopt(&self, _level: libc::c_int, _name: libc::c_int, _value: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::from(io::ErrorKind::Unsupported))
+        }
+    }
+
+    pub fn original_destination<S: RedirectedSocket>(_socket: &S) -> io::Result<SocketAddr> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    pub fn set_transparent(_listener: &TcpListener) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    /// An accepted socket, `original` is what `getsockopt` returns.
+    struct FakeSocket {
+        local: SocketAddr,
+        original: Result<SocketAddr, i32>,
+    }
+
+    impl RedirectedSocket for FakeSocket {
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            Ok(self.local)
+        }
+
+        fn getsockopt(&self, level: libc::c_int, _name: libc::c_int, value: &mut [u8]) -> io::Result<usize> {
+            let bytes = match self.original.map_err(io::Error::from_raw_os_error)? {
+                SocketAddr::V4(addr) => {
+                    assert_eq!(level, libc::SOL_IP);
+                    let mut raw: libc::sockaddr_in = unsafe { mem::zeroed() };
+                    raw.sin_family = libc::AF_INET as libc::sa_family_t;
+                    raw.sin_port = addr.port().to_be();
+                    raw.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                    as_bytes(&raw)
+                }
+                SocketAddr::V6(addr) => {
+                    assert_eq!(level, libc::SOL_IPV6);
+                    let mut raw: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+                    raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                    raw.sin6_port = addr.port().to_be();
+                    raw.sin6_addr.s6_addr = addr.ip().octets();
+                    as_bytes(&raw)
+                }
+            };
+            let len = bytes.len().min(value.len());
+            value[..len].copy_from_slice(&bytes[..len]);
+            Ok(len)
+        }
+    }
+
+    fn as_bytes<T>(value: &T) -> Vec<u8> {
+        unsafe { std::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }.to_vec()
+    }
+
+    fn listener() -> SocketAddr {
+        "0.0.0.0:8443".parse().unwrap()
+    }
+
+    fn check(local: &str, original: Result<&str, i32>) -> io::Result<Option<SocketAddr>> {
+        let socket = FakeSocket {
+            local: local.parse().unwrap(),
+            original: original.map(|addr| addr.parse().unwrap()),
+        };
+        original_destination(&socket, listener())
+    }
+
+    #[test]
+    fn redirected_v4() {
+        assert_eq!(
+            check("10.0.0.5:8443", Ok("93.184.216.34:443")).unwrap(),
+            Some(SocketAddr::new(Ipv4Addr::new(93, 184, 216, 34).into(), 443))
+        );
+    }
+
+    #[test]
+    fn redirected_v6() {
+        assert_eq!(
+            check("[2001:db8::5]:8443", Ok("[2001:db8::1]:443")).unwrap(),
+            Some(SocketAddr::new("2001:db8::1".parse::<Ipv6Addr>().unwrap().into(), 443))
+        );
+    }
+
+    #[test]
+    fn dual_stack_client_has_v4_entry() {
+        assert_eq!(
+            check("[::ffff:10.0.0.5]:8443", Ok("93.184.216.34:443")).unwrap(),
+            Some("93.184.216.34:443".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn tproxy_uses_the_local_address() {
+        assert_eq!(
+            check("93.184.216.34:443", Err(libc::ENOENT)).unwrap(),
+            Some("93.184.216.34:443".parse().unwrap())
+        );
+        assert_eq!(
+            check("[::ffff:93.184.216.34]:443", Err(libc::ENOENT)).unwrap(),
+            Some("93.184.216.34:443".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn connection_to_the_proxy_itself_is_not_relayed() {
+        assert_eq!(check("10.0.0.5:8443", Err(libc::ENOENT)).unwrap(), None);
+        assert_eq!(check("10.0.0.5:8443", Ok("10.0.0.5:8443")).unwrap(), None);
+        assert_eq!(check("[::ffff:10.0.0.5]:8443", Ok("10.0.0.5:8443")).unwrap(), None);
+    }
+
+    #[test]
+    fn other_errors_are_returned() {
+        let error = check("10.0.0.5:8443", Err(libc::ENOPROTOOPT)).unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::ENOPROTOOPT));
+    }
+}
@@ -0,0 +1,322 @@
+use ipnet::IpNet;
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io;
+use tokio::io::{AsyncRead, AsyncReadExt, Error, ErrorKind};
+
+/// The PROXY protocol
+/// https://www.haproxy.org/download/2.4/doc/proxy-protocol.txt
+/// > The receiver MUST be configured to only receive the protocol described in this
+/// > specification and MUST not try to guess whether the protocol header is present or not.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// > - worst case (optional fields set to 0xff) :
+/// >   "PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\n"
+/// >   => 5 + 1 + 7 + 1 + 39 + 1 + 39 + 1 + 5 + 1 + 5 + 2 = 107 chars
+const V1_MAX_SIZE: usize = 107;
+
+/// > \x0 : LOCAL : the connection was established on purpose by the proxy
+/// > without being relayed. The connection endpoints are the sender and the receiver.
+const V2_COMMAND_LOCAL: u8 = 0x0;
+/// > \x1 : PROXY : the connection was established on behalf of another node,
+/// > and reflects the original connection endpoints.
+const V2_COMMAND_PROXY: u8 = 0x1;
+/// Address family and transport protocol, TCP and UDP carry the same addresses.
+const V2_FAMILY_INET: u8 = 0x1;
+const V2_FAMILY_INET6: u8 = 0x2;
+
+/// Load balancers sending the PROXY protocol header, connections from other sources are rejected.
+///
+/// ```yaml
+/// proxy_protocol:
+///   trusted_networks: ["10.0.0.0/24"]
+/// ```
+#[derive(Deserialize, Clone, Debug)]
+pub struct ProxyProtocolConfig {
+    pub trusted_networks: Vec<IpNet>,
+}
+
+impl ProxyProtocolConfig {
+    pub fn is_trusted(&self, address: &IpAddr) -> bool {
+        self.trusted_networks.iter().any(|net| net.contains(address))
+    }
+}
+
+/// Reads the header, v1 (text) or v2 (binary), the load balancer sends ahead of the client's data.
+/// Returns the source address of the client, `None` if the header doesn't carry one,
+/// i.e. the load balancer connected on its own (e.g. a health check).
+///
+/// Nothing past the header is read, the rest belongs to the handshake.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut signature = [0u8; 12];
+    stream.read_exact(&mut signature).await?;
+
+    if signature == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if signature.starts_with(b"PROXY ") {
+        read_v1(stream, signature.to_vec()).await
+    } else {
+        Err(invalid("No PROXY protocol header"))
+    }
+}
+
+/// > "PROXY TCP4 255.255.255.255 255.255.255.255 65535 65535\r\n"
+///
+/// The line is read byte by byte, the same as a reply of an upstream proxy.
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S, mut line: Vec<u8>) -> io::Result<Option<SocketAddr>> {
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_SIZE {
+            return Err(invalid("PROXY protocol header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("Malformed PROXY protocol header"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        // > the receiver must ignore anything presented before the CRLF is found.
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, _destination, source_port, _destination_port] => {
+            let ip = source
+                .parse::<IpAddr>()
+                .map_err(|_| invalid("Malformed source address in the PROXY protocol header"))?;
+            if ip.is_ipv4() != (*protocol == "TCP4") {
+                return Err(invalid("Source address doesn't match the protocol in the PROXY protocol header"));
+            }
+            let port = source_port
+                .parse::<u16>()
+                .map_err(|_| invalid("Malformed source port in the PROXY protocol header"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("Malformed PROXY protocol header")),
+    }
+}
+
+/// > struct proxy_hdr_v2 {
+/// >     uint8_t sig[12];  /* hex 0D 0A 0D 0A 00 0D 0A 51 55 49 54 0A */
+/// >     uint8_t ver_cmd;  /* protocol version and command */
+/// >     uint8_t fam;      /* protocol family and address */
+/// >     uint16_t len;     /* number of following bytes part of the header */
+/// > };
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let size = stream.read_u16().await? as usize;
+    // The addresses are followed by optional TLVs, which are skipped.
+    let mut addresses = vec![0u8; size];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // > the receiver must accept this connection as valid and must use the
+        // > real connection endpoints and discard the protocol block including the family
+        V2_COMMAND_LOCAL => return Ok(None),
+        V2_COMMAND_PROXY => {}
+        _ => return Err(invalid("Unsupported PROXY protocol command")),
+    }
+
+    // > struct {        /* for TCP/UDP over IPv4, len = 12 */
+    // >     uint32_t src_addr;
+    // >     uint32_t dst_addr;
+    // >     uint16_t src_port;
+    // >     uint16_t dst_port;
+    // > } ipv4_addr;
+    // > struct {        /* for TCP/UDP over IPv6, len = 36 */
+    // >     uint8_t  src_addr[16];
+    // >     uint8_t  dst_addr[16];
+    // >     uint16_t src_port;
+    // >     uint16_t dst_port;
+    // > } ipv6_addr;
+    let (ip, port_offset) = match family >> 4 {
+        V2_FAMILY_INET if size >= 12 => {
+            let octets: [u8; 4] = addresses[0..4].try_into().expect("Checked size");
+            (IpAddr::V4(Ipv4Addr::from(octets)), 8)
+        }
+        V2_FAMILY_INET6 if size >= 36 => {
+            let octets: [u8; 16] = addresses[0..16].try_into().expect("Checked size");
+            (IpAddr::V6(Ipv6Addr::from(octets)), 32)
+        }
+        // > - \x00 : AF_UNSPEC : the connection is forwarded for an unknown, unspecified
+        // >   or unsupported protocol. The sender should use this family when sending
+        // >   LOCAL commands or when dealing with unsupported protocol families.
+        0x0 | 0x3 => return Ok(None),
+        _ => return Err(invalid("Malformed addresses in the PROXY protocol header")),
+    };
+    let port = u16::from_be_bytes([addresses[port_offset], addresses[port_offset + 1]]);
+
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut header: &[u8]) -> io::Result<Option<SocketAddr>> {
+        read_header(&mut header).await
+    }
+
+    fn v2(version_command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(version_command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    fn inet_addresses() -> Vec<u8> {
+        let mut addresses = vec![192, 0, 2, 1, 10, 0, 0, 1];
+        addresses.extend_from_slice(&51234u16.to_be_bytes());
+        addresses.extend_from_slice(&443u16.to_be_bytes());
+        addresses
+    }
+
+    #[tokio::test]
+    async fn v1_addresses() {
+        assert_eq!(
+            read(b"PROXY TCP4 192.0.2.1 10.0.0.1 51234 443\r\n").await.unwrap(),
+            Some("192.0.2.1:51234".parse().unwrap())
+        );
+        assert_eq!(
+            read(b"PROXY TCP6 2001:db8::1 2001:db8::2 51234 443\r\n").await.unwrap(),
+            Some("[2001:db8::1]:51234".parse().unwrap())
+        );
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v1_leaves_the_handshake() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 10.0.0.1 51234 443\r\nCONNECT";
+        read_header(&mut stream).await.unwrap();
+        assert_eq!(stream, b"CONNECT");
+    }
+
+    #[tokio::test]
+    async fn v1_size_limit() {
+        let longest = format!(
+            "PROXY UNKNOWN {0} {0} 65535 65535\r\n",
+            "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"
+        );
+        assert_eq!(longest.len(), V1_MAX_SIZE);
+        assert_eq!(read(longest.as_bytes()).await.unwrap(), None);
+
+        let oversized = format!("PROXY UNKNOWN {}\r\n", "f".repeat(V1_MAX_SIZE));
+        let error = read(oversized.as_bytes()).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn v1_malformed() {
+        for header in [
+            &b"PROXY TCP4 192.0.2.1 10.0.0.1 51234\r\n"[..],
+            b"PROXY TCP4 2001:db8::1 2001:db8::2 51234 443\r\n",
+            b"PROXY TCP6 192.0.2.1 10.0.0.1 51234 443\r\n",
+            b"PROXY TCP4 192.0.2.300 10.0.0.1 51234 443\r\n",
+            b"PROXY TCP4 192.0.2.1 10.0.0.1 65536 443\r\n",
+            b"PROXY UDP4 192.0.2.1 10.0.0.1 51234 443\r\n",
+        ] {
+            let error = read(header).await.unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData, "{:?}", String::from_utf8_lossy(header));
+        }
+    }
+
+    #[tokio::test]
+    async fn v2_addresses() {
+        assert_eq!(
+            read(&v2(0x21, 0x11, &inet_addresses())).await.unwrap(),
+            Some("192.0.2.1:51234".parse().unwrap())
+        );
+
+        let mut addresses = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addresses.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend_from_slice(&51234u16.to_be_bytes());
+        addresses.extend_from_slice(&443u16.to_be_bytes());
+        assert_eq!(
+            read(&v2(0x21, 0x21, &addresses)).await.unwrap(),
+            Some("[2001:db8::1]:51234".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn v2_skips_tlvs() {
+        let mut addresses = inet_addresses();
+        // PP2_TYPE_AUTHORITY
+        addresses.extend_from_slice(&[0x02, 0x00, 0x03]);
+        addresses.extend_from_slice(b"a.b");
+        let mut header = v2(0x21, 0x11, &addresses);
+        header.extend_from_slice(b"\x16\x03\x01");
+
+        let mut stream = &header[..];
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("192.0.2.1:51234".parse().unwrap())
+        );
+        assert_eq!(stream, b"\x16\x03\x01");
+    }
+
+    #[tokio::test]
+    async fn v2_local_and_unspec() {
+        // LOCAL, e.g. a health check, even with addresses
+        assert_eq!(read(&v2(0x20, 0x00, &[])).await.unwrap(), None);
+        assert_eq!(read(&v2(0x20, 0x11, &inet_addresses())).await.unwrap(), None);
+        // AF_UNSPEC and AF_UNIX
+        assert_eq!(read(&v2(0x21, 0x00, &[])).await.unwrap(), None);
+        assert_eq!(read(&v2(0x21, 0x31, &[0; 216])).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v2_malformed() {
+        for header in [
+            // version 1
+            v2(0x11, 0x11, &inet_addresses()),
+            // unknown command
+            v2(0x22, 0x11, &inet_addresses()),
+            // too short for the family
+            v2(0x21, 0x11, &inet_addresses()[..11]),
+            v2(0x21, 0x21, &inet_addresses()),
+            // unknown family
+            v2(0x21, 0x41, &inet_addresses()),
+        ] {
+            let error = read(&header).await.unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData, "{:?}", header);
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated() {
+        let v2_header = v2(0x21, 0x11, &inet_addresses());
+        for header in [
+            &b"PROXY TCP4 192.0.2.1 10.0.0.1 51234 443\r"[..],
+            b"PROXY",
+            &v2_header[..12],
+            &v2_header[..15],
+            &v2_header[..v2_header.len() - 1],
+        ] {
+            let error = read(header).await.unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::UnexpectedEof, "{:?}", header);
+        }
+    }
+
+    #[tokio::test]
+    async fn no_header() {
+        let error = read(b"CONNECT a.com:443 HTTP/1.1\r\n\r\n").await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn trusted_networks() {
+        let config = ProxyProtocolConfig {
+            trusted_networks: vec!["10.0.0.0/24".parse().unwrap(), "2001:db8::/32".parse().unwrap()],
+        };
+        assert!(config.is_trusted(&"10.0.0.7".parse().unwrap()));
+        assert!(config.is_trusted(&"2001:db8::7".parse().unwrap()));
+        assert!(!config.is_trusted(&"10.0.1.7".parse().unwrap()));
+        assert!(!config.is_trusted(&"192.0.2.1".parse().unwrap()));
+    }
+}
@@ -1,40 +1,36 @@
 /// About Comments -> INNER_LINE_DOC -> //! ~[\n IsolatedCR]*
 /// https://doc.rust-lang.org/reference/comments.html
 
+use crate::access_control::{AccessControlList, AccessDecision, AccessRequest, DestinationPolicy};
+use crate::dns_cache::{DnsCache, DnsCacheSnapshot, Resolved};
+use crate::nameserver::{split_target, NameserverResolver};
 use crate::tunnel::{TunnelCtx, TunnelTarget};
 
 use async_trait::async_trait;
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
 use log::{debug, error, info};
 use rand::prelude::thread_rng;
 use rand::Rng;
-use std::collections::HashMap;
+use std::future::Future;
 use std::marker::PhantomData;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::time::Instant;
 use tokio::io;
 use tokio::io::{Error, ErrorKind, AsyncRead, AsyncWriteExt, AsyncWrite};
 use tokio::net::TcpStream;
-use tokio::time::{Duration, timeout};
-use tokio::sync::RwLock;
+use tokio::time::{Duration, Instant, sleep, timeout};
 
-/// Vec https://doc.rust-lang.org/std/vec/struct.Vec.html
-/// A measurement of a monotonically increasing clock.
-/// u128: The 128-bit unsigned integer type. https://doc.rust-lang.org/std/primitive.u128.html
-//
-/// SocketAddr is a enum which represents an internal socket address, either IPv4 or IPv6.
-/// https://doc.rust-lang.org/std/net/enum.SocketAddr.html
-type CachedSocketAddrs = (Vec<SocketAddr>, u128);
+/// RFC 8305 recommends 250ms between connection attempts.
+/// https://datatracker.ietf.org/doc/html/rfc8305#section-8
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
 
 /// (Original comment)
 /// Caching DNS resolution to minimize DNS look-ups.
-/// The cache has relaxed consistency, it allows concurrncy DNS look-ups of the same key,
-/// without any guarantees which result is going to be cached.
-//
-/// Given it's used for DNS look-ups this trade-off seems to be reasonable.
-/// 
+///
 /// (My comment)
-/// TODO: I didn't understand it.
+/// The cache is shared by all clones, see `DnsCache` for its eviction and expiration.
 #[derive(Clone)]
 pub struct SimpleCachingDnsResolver {
     // Arc: A thread-safe reference-counting pointer. ‘Arc’ stands for ‘Atomically Reference Counted’.
@@ -42,84 +38,71 @@ pub struct SimpleCachingDnsResolver {
     // > Arc/Rc は参照カウントを使ったスマートポインタであり、データや状態を共有できる。
     // > よりコストの低い Rc で実装を開始して、必要になったら Arc に切り替えるというので問題ないでしょう。ただし、不特定多数が使うライブラリの場合ははじめから Arc でもいいかもしれません。
     // https://qiita.com/qnighy/items/4bbbb20e71cf4ae527b9
-    //
-    // RwLock: An asynchronous reader-writer lock
-    // https://docs.rs/tokio/0.2.22/tokio/sync/struct.RwLock.html
-    // professional comment about tokio::sync::RwLock https://github.com/ynaka81/in_memory_db_comparison/issues/1
-    // 
-    // HashMap: A hash map implemented with quadratic probing and SIMD lookup.
-    // JP: quadratic probing 2次プロービング
-    // - https://www.geeksforgeeks.org/quadratic-probing-in-hashing/
-    // - > Quadratic probing is an open-addressing scheme where we look for i2‘th slot in i’th iteration 
-    // - > if the given hash value x collides in the hash table. 
-    //
-    // JP: SIMD lookup SIMDルックアップ
-    // https://doc.rust-lang.org/std/collections/struct.HashMap.html
-    // > By default, HashMap uses a hashing algorithm selected to provide resistance against HashDoS attacks. 
-    //
-    // HashDoS attacks: https://www.f5.com/services/resources/glossary/hash-dos-attack
-    // > By sending a single POST message filled with thousands of variables,
-    // > the hashing function would overload and a server could be tied up processing this single request for as long as an hour. 
-    // > This is a hash denial-of-service (DoS) attack.
-    //
-    // Rustのコレクション型まとめ (VecやHashMapなど) https://qiita.com/garkimasera/items/a6df4d1cd99bc5010a5e
-    // - Vec 可変長配列
-    // - VecDeque リングバッファによる両端キューです。
-    // - LinkedList: 各要素が前後の要素へのポインタを持つ連結リストです。
-    // - HashMap: キーと値をペアで記録してくれるもので、他の言語では連想配列や辞書型と呼ばれたりします。
-    cache: Arc<RwLock<HashMap<String, CachedSocketAddrs>>>,
-    ttl: Duration,
-    // Instant
-    // https://doc.rust-jp.rs/the-rust-programming-language-ja/1.6/std/time/struct.Instant.html
-    start_time: Instant,
+    cache: Arc<DnsCache>,
+    // Asked instead of the system resolver if it has nameservers, its overrides apply either way.
+    nameserver_resolver: Option<NameserverResolver>,
+    // Each tunnel has its own clone, so it's the last look-up of the tunnel.
+    last_cache_hit: Option<bool>,
 }
 
 impl SimpleCachingDnsResolver {
-    pub fn new(ttl: Duration) -> Self {
+    /// `ttl` caps the TTL of the records, failures are cached for `negative_ttl`.
+    pub fn new(ttl: Duration, capacity: usize, negative_ttl: Duration) -> Self {
         Self {
-            cache: Arc::new(RwLock::new(HashMap::new())),
-            ttl,
-            start_time: Instant::now(),
+            cache: Arc::new(DnsCache::new(capacity, ttl, negative_ttl)),
+            nameserver_resolver: None,
+            last_cache_hit: None,
         }
     }
 
-    fn pick(&self, addrs: &[SocketAddr]) -> SocketAddr {
-        addrs[thread_rng().gen::<usize>() % addrs.len()]
+    /// Looks up names with the configured nameservers and overrides, see `NameserverResolver`.
+    pub fn with_nameservers(mut self, nameserver_resolver: NameserverResolver) -> Self {
+        self.nameserver_resolver = Some(nameserver_resolver);
+        self
     }
 
-    async fn try_find(&mut self, target: &str) -> Option<SocketAddr> {
-        let map = self.cache.read().await;
+    pub fn cache_snapshot(&self) -> DnsCacheSnapshot {
+        self.cache.snapshot()
+    }
 
-        let addr = match map.get(target) {
-            None => None,
-            Some((cached, expiration)) => {
-                // gen_range: Generate a random value in the range [low, high)
-                // https://docs.rs/rand/0.5.0/rand/trait.Rng.html#method.gen_range
-                let expiration_jitter = *expiration + thread_rng().gen_range(0..5_000);
-                if Instant::now().duration_since(self.start_time).as_millis() < expiration_jitter {
-                    Some(self.pick(cached))
-                } else {
-                    None
+    /// Starts the list at a random address, so the load is spread across all of them.
+    /// The connector tries them in this order (within an address family).
+    fn rotate(&self, addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+        if addrs.is_empty() {
+            return vec![];
+        }
+        let mut rotated = addrs.to_vec();
+        rotated.rotate_left(thread_rng().gen::<usize>() % addrs.len());
+        rotated
+    }
+
+    /// The system resolver (getaddrinfo) doesn't tell the TTL, so the configured one applies.
+    fn lookup(&self, target: String) -> BoxFuture<'static, io::Result<Resolved>> {
+        let nameserver_resolver = self
+            .nameserver_resolver
+            .clone()
+            .filter(NameserverResolver::has_nameservers);
+        async move {
+            let (host, port) = split_target(&target)?;
+            match nameserver_resolver {
+                // IP literals are looked up by the system resolver, it returns them as they are.
+                Some(nameserver_resolver) if host.parse::<IpAddr>().is_err() => {
+                    nameserver_resolver.lookup(host, port).await
+                }
+                _ => {
+                    let addrs = SimpleCachingDnsResolver::resolve(&target).await?;
+                    Ok(Resolved { addrs, ttl: None })
                 }
             }
-        };
-
-        addr
+        }
+        .boxed()
     }
 
-    async fn resolve_and_cache(&mut self, target: &str) -> io::Result<SocketAddr> {
-        let resolved = SimpleCachingDnsResolver::resolve(target).await?;
-
-        let mut map = self.cache.write().await;
-        map.insert(
-            target.to_string(),
-            (
-                resolved.clone(),
-                Instant::now().duration_since(self.start_time).as_millis() + self.ttl.as_millis(),
-            ),
-        );
-
-        Ok(self.pick(&resolved))
+    /// Pinned addresses aren't cached, there is nothing to save.
+    fn find_override(&self, target: &str) -> Option<Vec<SocketAddr>> {
+        let (host, port) = split_target(target).ok()?;
+        let ips = self.nameserver_resolver.as_ref()?.find_override(host)?;
+        Some(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect())
     }
 
     async fn resolve(target: &str) -> io::Result<Vec<SocketAddr>> {
@@ -143,18 +126,35 @@ impl SimpleCachingDnsResolver {
 /// Without this trait, we may got an error: error[E0706]: trait fns cannot be declared `async`
 #[async_trait]
 pub trait DnsResolver {
-    async fn resolve(&mut self, target: &str) -> io::Result<SocketAddr>;
+    /// Returns all addresses of the target, so the connector can fall back to another one.
+    async fn resolve(&mut self, target: &str) -> io::Result<Vec<SocketAddr>>;
+    /// Whether the last `resolve` was served from a cache, `None` for resolvers without one.
+    fn cache_hit(&self) -> Option<bool> {
+        None
+    }
 }
 
 /// Without this definition, we got an error:
 /// > error[E0277]: the trait bound `SimpleCachingDnsResolver: proxy_target::DnsResolver` is not satisfied
 #[async_trait]
 impl DnsResolver for SimpleCachingDnsResolver {
-    async fn  resolve(&mut self, target: &str) -> io::Result<SocketAddr> {
-        match self.try_find(target).await {
-            Some(a) => Ok(a), // if it found
-            _ => Ok(self.resolve_and_cache(target).await?), // if it not found
+    async fn  resolve(&mut self, target: &str) -> io::Result<Vec<SocketAddr>> {
+        if let Some(pinned) = self.find_override(target) {
+            debug!("Resolved DNS {} to {:?} by an override", target, pinned);
+            self.last_cache_hit = None;
+            return Ok(self.rotate(&pinned));
         }
+
+        let (resolved, cache_hit) = self
+            .cache
+            .get_or_resolve(target, || self.lookup(target.to_string()))
+            .await;
+        self.last_cache_hit = Some(cache_hit);
+        Ok(self.rotate(&resolved?))
+    }
+
+    fn cache_hit(&self) -> Option<bool> {
+        self.last_cache_hit
     }
 }
 
@@ -164,6 +164,14 @@ pub struct SimpleTcpConnector<D, R: DnsResolver> {
     connect_timeout: Duration,
     tunnel_ctx: TunnelCtx,
     dns_resolver: R,
+    #[builder(default)]
+    access_control: AccessControlList,
+    #[builder(default)]
+    client_addr: Option<IpAddr>,
+    #[builder(default)]
+    destination_policy: DestinationPolicy,
+    #[builder(setter(skip))]
+    connect_stats: Option<ConnectStats>,
     #[builder(setter(skip))]
     // Struct std::marker::PhantomData
     // https://doc.rust-lang.org/std/marker/struct.PhantomData.html
@@ -178,14 +186,234 @@ impl<D, R> SimpleTcpConnector<D, R>
 where
     R: DnsResolver,
 {
+    /// Internal networks are denied, the same as with the builder and the configuration.
     pub fn new(dns_resolver: R, connect_timeout: Duration, tunnel_ctx: TunnelCtx) -> Self {
         Self {
             dns_resolver,
             connect_timeout,
             tunnel_ctx,
+            access_control: AccessControlList::default(),
+            client_addr: None,
+            destination_policy: DestinationPolicy::default(),
+            connect_stats: None,
             _phantom_target: PhantomData,
         }
     }
+
+    /// Target addresses to refuse, `DestinationPolicy::allow_all` for targets chosen by the operator.
+    pub fn with_destination_policy(mut self, destination_policy: DestinationPolicy) -> Self {
+        self.destination_policy = destination_policy;
+        self
+    }
+
+    /// Access rules to check once the target is resolved, `client_addr` is the source of the tunnel.
+    pub fn with_access_control(
+        mut self,
+        access_control: AccessControlList,
+        client_addr: IpAddr,
+    ) -> Self {
+        self.access_control = access_control;
+        self.client_addr = Some(client_addr);
+        self
+    }
+}
+
+impl<D, R> SimpleTcpConnector<D, R>
+where
+    D: TunnelTarget<Addr = String>,
+    R: DnsResolver,
+{
+    /// Checks the access rules against the address we are about to connect to.
+    /// A host name resolving to a forbidden network is refused even if the host itself is allowed.
+    fn check_access(&self, target: &D, target_addr: &str, addr: &SocketAddr) -> io::Result<()> {
+        if !self.destination_policy.is_allowed(&addr.ip()) {
+            info!(
+                "Target {} resolved to {} is denied by the destination policy, CTX={}",
+                target_addr, addr, self.tunnel_ctx
+            );
+            return Err(Error::from(ErrorKind::PermissionDenied));
+        }
+
+        let mut request = AccessRequest::from_target(target_addr)
+            .ok_or_else(|| Error::from(ErrorKind::InvalidInput))?;
+        request.address = Some(addr.ip());
+        request.user = target.user();
+        request.client = self.client_addr;
+
+        match self.access_control.check(&request) {
+            AccessDecision::Allow => Ok(()),
+            AccessDecision::Deny | AccessDecision::Undecided => {
+                info!(
+                    "Target {} resolved to {} is denied by the access rules, CTX={}",
+                    target_addr, addr, self.tunnel_ctx
+                );
+                // PermissionDenied: The operation lacked the necessary privileges to complete.
+                // https://doc.rust-lang.org/std/io/enum.ErrorKind.html#variant.PermissionDenied
+                Err(Error::from(ErrorKind::PermissionDenied))
+            }
+        }
+    }
+
+    /// Happy Eyeballs, connects to the first address that answers.
+    /// https://datatracker.ietf.org/doc/html/rfc8305#section-5
+    /// > Starting a new connection attempt does not affect previous attempts, as multiple connection attempts may occur in parallel.
+    /// > Once one of the connection attempts succeeds, all other connections attempts that have not yet succeeded SHOULD be canceled.
+    ///
+    /// Addresses are interleaved by family (IPv6 first), a new attempt starts every `CONNECTION_ATTEMPT_DELAY`
+    /// or as soon as the previous one fails.
+    pub async fn race_connections(
+        addrs: Vec<SocketAddr>,
+        stats: &mut ConnectStats,
+    ) -> io::Result<TcpStream> {
+        race_connections_with(addrs, stats, TcpStream::connect).await
+    }
+}
+
+/// `race_connections` with the connect function passed in, e.g. a fake one in the tests.
+async fn race_connections_with<S, F, Fut>(
+    addrs: Vec<SocketAddr>,
+    stats: &mut ConnectStats,
+    mut connect: F,
+) -> io::Result<S>
+where
+    F: FnMut(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<S>>,
+{
+    let start = Instant::now();
+    let mut pending = interleave_address_families(addrs).into_iter();
+    // futures::stream::FuturesUnordered: A set of futures which may complete in any order.
+    // https://docs.rs/futures/0.3.17/futures/stream/struct.FuturesUnordered.html
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.push(stats.start_attempt(addr, start, connect(addr))),
+                None => break,
+            }
+        }
+
+        // tokio::select! waits on multiple concurrent branches, returning when the first branch completes.
+        // https://docs.rs/tokio/1.10.1/tokio/macro.select.html
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(stream) => {
+                    stats.finish_attempt(addr, ConnectOutcome::Connected, start);
+                    stats.connected = Some(addr);
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    debug!("Connection attempt to {} failed: {}", addr, e);
+                    stats.finish_attempt(addr, ConnectOutcome::Failed, start);
+                    last_error = Some(e);
+                    if let Some(addr) = pending.next() {
+                        attempts.push(stats.start_attempt(addr, start, connect(addr)));
+                    }
+                }
+            },
+            _ = sleep(CONNECTION_ATTEMPT_DELAY), if pending.len() > 0 => {
+                if let Some(addr) = pending.next() {
+                    attempts.push(stats.start_attempt(addr, start, connect(addr)));
+                }
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| Error::from(ErrorKind::AddrNotAvailable)))
+}
+
+/// RFC 8305 Section 4
+/// https://datatracker.ietf.org/doc/html/rfc8305#section-4
+/// > the client SHOULD modify the ordered list to interleave address families.
+fn interleave_address_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.into_iter().partition(|a| a.is_ipv6());
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+
+    let mut interleaved = vec![];
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// The outcome of a single connection attempt.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub enum ConnectOutcome {
+    Connected,
+    Failed,
+    /// Another address connected first, or the connect timeout expired.
+    Cancelled,
+    /// Not attempted, denied by the destination policy or the access rules.
+    Denied,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ConnectAttempt {
+    pub address: SocketAddr,
+    pub outcome: Option<ConnectOutcome>,
+    /// Since the first attempt started.
+    pub started_after: Duration,
+    pub finished_after: Option<Duration>,
+}
+
+/// Per-attempt outcomes of a connection to a target, shows e.g. which address family won.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ConnectStats {
+    pub attempts: Vec<ConnectAttempt>,
+    pub connected: Option<SocketAddr>,
+    pub dns_cache_hit: Option<bool>,
+    /// Resolving the target (or the parent proxy), including waiting for a look-up of the same name in progress.
+    pub dns_duration: Option<Duration>,
+    /// The parent proxy the target was connected through, the attempts are to its addresses.
+    pub upstream_proxy: Option<String>,
+}
+
+impl ConnectStats {
+    /// From the first attempt until a connection was established.
+    pub fn connect_latency(&self) -> Option<Duration> {
+        let connected = self.connected?;
+        self.attempts
+            .iter()
+            .find(|a| a.address == connected && a.outcome == Some(ConnectOutcome::Connected))
+            .and_then(|a| a.finished_after)
+    }
+
+    fn start_attempt<S>(
+        &mut self,
+        addr: SocketAddr,
+        start: Instant,
+        connect: impl Future<Output = io::Result<S>>,
+    ) -> impl Future<Output = (SocketAddr, io::Result<S>)> {
+        self.attempts.push(ConnectAttempt {
+            address: addr,
+            outcome: None,
+            started_after: start.elapsed(),
+            finished_after: None,
+        });
+        async move { (addr, connect.await) }
+    }
+
+    fn finish_attempt(&mut self, addr: SocketAddr, outcome: ConnectOutcome, start: Instant) {
+        if let Some(attempt) = self
+            .attempts
+            .iter_mut()
+            .find(|a| a.address == addr && a.outcome.is_none())
+        {
+            attempt.outcome = Some(outcome);
+            attempt.finished_after = Some(start.elapsed());
+        }
+    }
+
+    /// Attempts still in flight won't finish, either something else connected, or we gave up.
+    pub fn cancel_pending(&mut self) {
+        for attempt in self.attempts.iter_mut().filter(|a| a.outcome.is_none()) {
+            attempt.outcome = Some(ConnectOutcome::Cancelled);
+        }
+    }
 }
 
 #[async_trait]
@@ -202,12 +430,62 @@ where
     async fn connect(&mut self, target: &Self::Target) -> io::Result<Self::Stream> {
         let target_addr = &target.target_addr();
 
-        let addr = self.dns_resolver.resolve(target_addr).await?;
+        let dns_start = Instant::now();
+        let resolved = self.dns_resolver.resolve(target_addr).await;
+
+        let mut stats = ConnectStats {
+            dns_cache_hit: self.dns_resolver.cache_hit(),
+            dns_duration: Some(dns_start.elapsed()),
+            ..ConnectStats::default()
+        };
+
+        let resolved = match resolved {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                self.connect_stats = Some(stats);
+                return Err(e);
+            }
+        };
+
+        // Every address is checked, we never dial an address that is denied.
+        let mut addrs = vec![];
+        let mut denied = None;
+        for addr in resolved {
+            match self.check_access(target, target_addr, &addr) {
+                Ok(()) => addrs.push(addr),
+                Err(e) => {
+                    stats.attempts.push(ConnectAttempt {
+                        address: addr,
+                        outcome: Some(ConnectOutcome::Denied),
+                        started_after: Duration::from_secs(0),
+                        finished_after: None,
+                    });
+                    denied = Some(e);
+                }
+            }
+        }
+
+        if addrs.is_empty() {
+            self.connect_stats = Some(stats);
+            return Err(denied.unwrap_or_else(|| Error::from(ErrorKind::AddrNotAvailable)));
+        }
 
+        // Kept in `self` while racing, so the attempts are there even if the caller gives up on `connect` first.
+        let stats = self.connect_stats.insert(stats);
         // tokio::time::timeout 
         // https://docs.rs/tokio/0.2.6/tokio/time/fn.timeout.html
-        if let Ok(tcp_stream) = timeout(self.connect_timeout, TcpStream::connect(addr)).await {
+        let connection = timeout(
+            self.connect_timeout,
+            SimpleTcpConnector::<D, R>::race_connections(addrs, stats),
+        )
+        .await;
+        stats.cancel_pending();
+        let connected = stats.connected;
+
+        if let Ok(tcp_stream) = connection {
             let mut stream = tcp_stream?;
+            let addr = connected.expect("Connected stream has an address");
+            info!("Connected to {} at {}, CTX={}", target_addr, addr, self.tunnel_ctx);
             // Gets the value of the TCP_NODELAY option on this socket.
             // https://docs.rs/tokio/0.2.6/tokio/net/struct.TcpStream.html#method.nodelay
             stream.nodelay()?;
@@ -236,12 +514,16 @@ where
             Ok(stream)
         } else {
             error!(
-                "Timeout connection to {}, {}, CTX={}",
-                addr, target_addr, self.tunnel_ctx
+                "Timeout connection to {}, CTX={}",
+                target_addr, self.tunnel_ctx
             );
             Err(Error::from(ErrorKind::TimedOut))
         }
     }
+
+    fn connect_stats(&self) -> Option<ConnectStats> {
+        self.connect_stats.clone()
+    }
 }
 
 // TODO: What's nugget?
@@ -267,7 +549,225 @@ impl Nugget {
 #[async_trait]
 pub trait TargetConnector {
     type Target: TunnelTarget + Send + Sync + Sized;
-    type Stream: AsyncRead + AsyncWrite + Send + Sized + 'static;
+    type Stream: AsyncRead + AsyncWrite + Send + Sized + Unpin + 'static;
 
     async fn connect(&mut self, target: &Self::Target) -> io::Result<Self::Stream>;
-}
\ No newline at end of file
+
+    /// Details of the last `connect`, if the connector keeps track of them.
+    fn connect_stats(&self) -> Option<ConnectStats> {
+        None
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_tunnel_codec::{HttpTunnelTarget, HttpTunnelTargetBuilder};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+
+    /// Resolves every target to the same addresses.
+    struct StaticResolver {
+        addrs: Vec<SocketAddr>,
+    }
+
+    #[async_trait]
+    impl DnsResolver for StaticResolver {
+        async fn resolve(&mut self, _target: &str) -> io::Result<Vec<SocketAddr>> {
+            Ok(self.addrs.clone())
+        }
+    }
+
+    fn target(target: &str) -> HttpTunnelTarget {
+        HttpTunnelTargetBuilder::default()
+            .target(target.to_string())
+            .nugget(None)
+            .build()
+            .unwrap()
+    }
+
+    fn connector(addrs: Vec<SocketAddr>) -> SimpleTcpConnector<HttpTunnelTarget, StaticResolver> {
+        SimpleTcpConnector::new(StaticResolver { addrs }, Duration::from_secs(5), TunnelCtx::default())
+    }
+
+    #[tokio::test]
+    async fn internal_addresses_are_not_dialed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mapped: SocketAddr = format!("[::ffff:127.0.0.1]:{}", addr.port()).parse().unwrap();
+
+        let mut connector = connector(vec![addr, mapped]);
+        let error = connector.connect(&target("rebound.example.com:443")).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+
+        let stats = connector.connect_stats().unwrap();
+        assert_eq!(stats.attempts.len(), 2);
+        assert!(stats.attempts.iter().all(|a| a.outcome == Some(ConnectOutcome::Denied)));
+        assert_eq!(stats.connected, None);
+    }
+
+    #[tokio::test]
+    async fn allowed_networks_are_dialed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let policy: DestinationPolicy = serde_yaml::from_str("allowed_networks: [127.0.0.0/8]").unwrap();
+
+        let mut connector = connector(vec![addr]).with_destination_policy(policy);
+        connector.connect(&target("internal.example.com:443")).await.unwrap();
+        assert_eq!(connector.connect_stats().unwrap().connected, Some(addr));
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    /// What the fake connector does with an address, after `delay` if there's one.
+    #[derive(Clone, Copy)]
+    enum Dial {
+        Connect(u64),
+        Fail(u64, ErrorKind),
+        Hang,
+    }
+
+    /// Counts the attempts that were dropped before they finished.
+    struct DropCounter(Arc<AtomicUsize>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn fake_connect(
+        plan: Vec<(SocketAddr, Dial)>,
+        cancelled: Arc<AtomicUsize>,
+    ) -> impl FnMut(SocketAddr) -> BoxFuture<'static, io::Result<SocketAddr>> {
+        move |addr| {
+            let dial = plan.iter().find(|(a, _)| *a == addr).map(|(_, dial)| *dial).unwrap();
+            let counter = DropCounter(cancelled.clone());
+            async move {
+                let result = match dial {
+                    Dial::Connect(delay) => {
+                        sleep(Duration::from_millis(delay)).await;
+                        Ok(addr)
+                    }
+                    Dial::Fail(delay, kind) => {
+                        sleep(Duration::from_millis(delay)).await;
+                        Err(Error::from(kind))
+                    }
+                    Dial::Hang => futures::future::pending().await,
+                };
+                std::mem::forget(counter);
+                result
+            }
+            .boxed()
+        }
+    }
+
+    fn started_after(stats: &ConnectStats) -> Vec<(SocketAddr, u128)> {
+        stats.attempts.iter().map(|a| (a.address, a.started_after.as_millis())).collect()
+    }
+
+    #[test]
+    fn address_families_are_interleaved() {
+        let (v4a, v4b, v4c) = (addr("192.0.2.1:443"), addr("192.0.2.2:443"), addr("192.0.2.3:443"));
+        let (v6a, v6b) = (addr("[2001:db8::1]:443"), addr("[2001:db8::2]:443"));
+
+        assert_eq!(
+            interleave_address_families(vec![v4a, v4b, v4c, v6a, v6b]),
+            vec![v6a, v4a, v6b, v4b, v4c]
+        );
+        assert_eq!(interleave_address_families(vec![v4a, v4b]), vec![v4a, v4b]);
+        assert_eq!(interleave_address_families(vec![v6b, v4a, v6a]), vec![v6b, v4a, v6a]);
+        assert_eq!(interleave_address_families(vec![]), vec![]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn attempts_are_staggered() {
+        let (v4, v6a, v6b) = (addr("192.0.2.1:443"), addr("[2001:db8::1]:443"), addr("[2001:db8::2]:443"));
+        let plan = vec![(v4, Dial::Hang), (v6a, Dial::Hang), (v6b, Dial::Hang)];
+        let cancelled = Arc::new(AtomicUsize::new(0));
+
+        let mut stats = ConnectStats::default();
+        let raced = timeout(
+            Duration::from_millis(600),
+            race_connections_with(vec![v4, v6a, v6b], &mut stats, fake_connect(plan, cancelled.clone())),
+        )
+        .await;
+        assert!(raced.is_err());
+
+        // The attempts made before the timeout are recorded, none of them finished.
+        assert_eq!(started_after(&stats), vec![(v6a, 0), (v4, 250), (v6b, 500)]);
+        assert_eq!(cancelled.load(Ordering::SeqCst), 3);
+        stats.cancel_pending();
+        assert!(stats.attempts.iter().all(|a| a.outcome == Some(ConnectOutcome::Cancelled)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_attempt_starts_the_next_one() {
+        let (v4a, v4b, v4c) = (addr("192.0.2.1:443"), addr("192.0.2.2:443"), addr("192.0.2.3:443"));
+        let plan = vec![
+            (v4a, Dial::Fail(100, ErrorKind::ConnectionRefused)),
+            (v4b, Dial::Fail(100, ErrorKind::ConnectionRefused)),
+            (v4c, Dial::Connect(10)),
+        ];
+
+        let mut stats = ConnectStats::default();
+        let connected = race_connections_with(vec![v4a, v4b, v4c], &mut stats, fake_connect(plan, Default::default()))
+            .await
+            .unwrap();
+        assert_eq!(connected, v4c);
+        assert_eq!(started_after(&stats), vec![(v4a, 0), (v4b, 100), (v4c, 200)]);
+        assert_eq!(stats.connect_latency(), Some(Duration::from_millis(210)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn first_connection_wins() {
+        let (v4, v6a, v6b) = (addr("192.0.2.1:443"), addr("[2001:db8::1]:443"), addr("[2001:db8::2]:443"));
+        // v6a is tried first and hangs, v4 answers before v6b does.
+        let plan = vec![(v6a, Dial::Hang), (v4, Dial::Connect(400)), (v6b, Dial::Connect(300))];
+        let cancelled = Arc::new(AtomicUsize::new(0));
+
+        let mut stats = ConnectStats::default();
+        let connected = race_connections_with(vec![v4, v6a, v6b], &mut stats, fake_connect(plan, cancelled.clone()))
+            .await
+            .unwrap();
+        assert_eq!(connected, v4);
+        assert_eq!(stats.connected, Some(v4));
+        assert_eq!(stats.connect_latency(), Some(Duration::from_millis(650)));
+
+        // The attempts still in flight are dropped with the race.
+        assert_eq!(cancelled.load(Ordering::SeqCst), 2);
+        stats.cancel_pending();
+        let outcomes: Vec<_> = stats.attempts.iter().map(|a| (a.address, a.outcome.clone().unwrap())).collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                (v6a, ConnectOutcome::Cancelled),
+                (v4, ConnectOutcome::Connected),
+                (v6b, ConnectOutcome::Cancelled),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn last_error_is_returned() {
+        let (v4a, v4b) = (addr("192.0.2.1:443"), addr("192.0.2.2:443"));
+        let plan = vec![
+            (v4a, Dial::Fail(300, ErrorKind::ConnectionRefused)),
+            (v4b, Dial::Fail(300, ErrorKind::TimedOut)),
+        ];
+
+        let mut stats = ConnectStats::default();
+        let error = race_connections_with(vec![v4a, v4b], &mut stats, fake_connect(plan, Default::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert!(stats.attempts.iter().all(|a| a.outcome == Some(ConnectOutcome::Failed)));
+        assert_eq!(stats.connected, None);
+
+        let error = race_connections_with(vec![], &mut ConnectStats::default(), fake_connect(vec![], Default::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::AddrNotAvailable);
+    }
+}
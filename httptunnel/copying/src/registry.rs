@@ -0,0 +1,176 @@
+use crate::tunnel::TunnelCtx;
+
+use log::info;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+
+/// Active tunnels by `TunnelCtx` id, for the admin API.
+#[derive(Default)]
+pub struct TunnelRegistry {
+    tunnels: Mutex<HashMap<u128, Arc<LiveTunnel>>>,
+}
+
+/// A tunnel as seen while it's open.
+pub struct LiveTunnel {
+    id: u128,
+    client_addr: SocketAddr,
+    // Known once the handshake is decoded.
+    target: Mutex<Option<String>>,
+    started_at: SystemTime,
+    start: Instant,
+    /// Updated by the relays after every write.
+    pub upstream_bytes: Arc<AtomicU64>,
+    pub downstream_bytes: Arc<AtomicU64>,
+    terminate: watch::Sender<bool>,
+    termination: watch::Receiver<bool>,
+}
+
+/// Completes once the tunnel is terminated via the admin API.
+#[derive(Clone)]
+pub struct Termination {
+    terminated: watch::Receiver<bool>,
+}
+
+/// Removes the tunnel from the registry when dropped.
+pub struct TunnelRegistration {
+    registry: Arc<TunnelRegistry>,
+    tunnel: Arc<LiveTunnel>,
+}
+
+/// A snapshot of a `LiveTunnel`.
+#[derive(Serialize)]
+pub struct TunnelInfo {
+    pub id: u128,
+    pub client_addr: SocketAddr,
+    pub target: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub started_at: u64,
+    pub duration_secs: f64,
+    pub upstream_bytes: u64,
+    pub downstream_bytes: u64,
+    pub terminated: bool,
+}
+
+impl TunnelRegistry {
+    pub fn register(self: &Arc<Self>, tunnel_ctx: TunnelCtx, client_addr: SocketAddr) -> TunnelRegistration {
+        let (terminate, termination) = watch::channel(false);
+        let tunnel = Arc::new(LiveTunnel {
+            id: tunnel_ctx.id(),
+            client_addr,
+            target: Mutex::new(None),
+            started_at: SystemTime::now(),
+            start: Instant::now(),
+            upstream_bytes: Arc::new(AtomicU64::new(0)),
+            downstream_bytes: Arc::new(AtomicU64::new(0)),
+            terminate,
+            termination,
+        });
+
+        self.tunnels
+            .lock()
+            .expect("Poisoned lock")
+            .insert(tunnel.id, tunnel.clone());
+
+        TunnelRegistration {
+            registry: self.clone(),
+            tunnel,
+        }
+    }
+
+    /// The oldest first.
+    pub fn list(&self) -> Vec<TunnelInfo> {
+        let mut tunnels: Vec<TunnelInfo> = self
+            .tunnels
+            .lock()
+            .expect("Poisoned lock")
+            .values()
+            .map(|tunnel| tunnel.info())
+            .collect();
+        tunnels.sort_by(|a, b| b.duration_secs.total_cmp(&a.duration_secs));
+        tunnels
+    }
+
+    pub fn get(&self, id: u128) -> Option<TunnelInfo> {
+        self.find(id).map(|tunnel| tunnel.info())
+    }
+
+    /// Closes the tunnel's relays, returns `false` if there is no such tunnel.
+    pub fn terminate(&self, id: u128) -> bool {
+        match self.find(id) {
+            Some(tunnel) => {
+                info!("Terminating tunnel {} on request, CTX={}", tunnel.client_addr, id);
+                // Can't fail, `tunnel.termination` is a receiver.
+                let _ = tunnel.terminate.send(true);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn find(&self, id: u128) -> Option<Arc<LiveTunnel>> {
+        self.tunnels.lock().expect("Poisoned lock").get(&id).cloned()
+    }
+}
+
+impl LiveTunnel {
+    pub fn set_target(&self, target: String) {
+        *self.target.lock().expect("Poisoned lock") = Some(target);
+    }
+
+    pub fn termination(&self) -> Termination {
+        Termination {
+            terminated: self.termination.clone(),
+        }
+    }
+
+    fn info(&self) -> TunnelInfo {
+        TunnelInfo {
+            id: self.id,
+            client_addr: self.client_addr,
+            target: self.target.lock().expect("Poisoned lock").clone(),
+            started_at: self
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            duration_secs: self.start.elapsed().as_secs_f64(),
+            upstream_bytes: self.upstream_bytes.load(Ordering::Relaxed),
+            downstream_bytes: self.downstream_bytes.load(Ordering::Relaxed),
+            terminated: *self.termination.borrow(),
+        }
+    }
+}
+
+impl TunnelRegistration {
+    pub fn tunnel(&self) -> Arc<LiveTunnel> {
+        self.tunnel.clone()
+    }
+}
+
+impl Drop for TunnelRegistration {
+    fn drop(&mut self) {
+        self.registry
+            .tunnels
+            .lock()
+            .expect("Poisoned lock")
+            .remove(&self.tunnel.id);
+    }
+}
+
+impl Termination {
+    pub async fn wait(&mut self) {
+        loop {
+            if *self.terminated.borrow() {
+                return;
+            }
+            // The tunnel is gone, nobody can terminate it anymore.
+            if self.terminated.changed().await.is_err() {
+                futures::future::pending::<()>().await;
+            }
+        }
+    }
+}
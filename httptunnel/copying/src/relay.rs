@@ -1,10 +1,16 @@
 use core::fmt;
+use std::collections::VecDeque;
 use std::time::{Duration, Instant};
 
+use crate::bandwidth::{BandwidthLimit, TokenBucket};
+use crate::registry::Termination;
+use crate::shutdown::ForceClose;
 use crate::tunnel::TunnelCtx;
 
 use log::{error, info, debug};
 use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use tokio::io;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::time::timeout;
@@ -15,6 +21,10 @@ use tokio::time::timeout;
 pub const NO_TIMEOUT: Duration = Duration::from_secs(300);
 pub const NO_BANDWIDTH_LIMIT: u64 = 1_000_000_000_000_u64;
 const BUFFER_SIZE: usize = 16 * 1024;
+/// Rates aren't checked this long after a relay starts, so a slow start isn't mistaken for slowloris.
+const DEFAULT_RATE_GRACE_PERIOD: Duration = Duration::from_secs(30);
+/// Rates are measured over this much recent traffic.
+const DEFAULT_RATE_WINDOW: Duration = Duration::from_secs(60);
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub enum RelayShutdownReasons {
@@ -27,6 +37,21 @@ pub enum RelayShutdownReasons {
     WriterTimeout,
     TooSlow,
     TooFast,
+    /// The proxy was shutting down and the drain timeout expired
+    ProxyShutdown,
+    /// Terminated via the admin API
+    Terminated,
+}
+
+/// What to do with a relay faster than `max_rate_bpm`.
+#[derive(Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MaxRateAction {
+    /// Delay writes to hold the rate
+    #[default]
+    Throttle,
+    /// Close the relay with `TooFast`
+    Close,
 }
 
 #[derive(Builder, Deserialize, Clone)]
@@ -37,6 +62,28 @@ pub struct RelayPolicy {
     // https://doc.rust-lang.org/book/ch03-02-data-types.html
     pub min_rate_bpm: u64, // bpm = bytes per minute
     pub max_rate_bpm: u64,
+    #[serde(default)]
+    #[builder(default)]
+    pub max_rate_action: MaxRateAction,
+    /// Bytes that may be sent at once when throttling, one second worth of `max_rate_bpm` by default.
+    #[serde(default)]
+    #[builder(default)]
+    pub burst_bytes: Option<u64>,
+    #[serde(with = "humantime_serde", default = "default_rate_grace_period")]
+    #[builder(default = "DEFAULT_RATE_GRACE_PERIOD")]
+    pub rate_grace_period: Duration,
+    /// Rounded down to whole seconds, at least one.
+    #[serde(with = "humantime_serde", default = "default_rate_window")]
+    #[builder(default = "DEFAULT_RATE_WINDOW")]
+    pub rate_window: Duration,
+}
+
+pub fn default_rate_grace_period() -> Duration {
+    DEFAULT_RATE_GRACE_PERIOD
+}
+
+pub fn default_rate_window() -> Duration {
+    DEFAULT_RATE_WINDOW
 }
 
 impl RelayPolicy {
@@ -57,28 +104,45 @@ impl RelayPolicy {
         }
     }
 
+    /// The per-relay bucket, if the relay is throttled.
+    fn token_bucket(&self) -> Option<TokenBucket> {
+        if self.max_rate_action != MaxRateAction::Throttle || self.max_rate_bpm >= NO_BANDWIDTH_LIMIT {
+            return None;
+        }
+        Some(TokenBucket::new(BandwidthLimit {
+            rate_bpm: self.max_rate_bpm,
+            burst_bytes: self.burst_bytes,
+        }))
+    }
+
     /// (Original comments)
     /// Basic rate limiting. Placeholder for more sophisticated policy handling.
     /// e.g. sliding windows, detecting heavy hitters, etc.
+    ///
+    /// The rate is the one over the last `rate_window` (see `RateMeter`), not since the start,
+    /// so a relay that was fast early can't trickle afterwards. Nothing is checked during `rate_grace_period`.
+    /// `now` is passed in rather than read, so the checks don't depend on the wall clock.
     pub fn check_transimission_rates(
         &self,
-        start: &Instant,
-        total_bytes: usize,
+        meter: &mut RateMeter,
+        now: Instant,
     ) -> Result<(), RelayShutdownReasons> {
-        if self.min_rate_bpm == 0 && self.max_rate_bpm >= NO_BANDWIDTH_LIMIT {
+        let close_too_fast =
+            self.max_rate_action == MaxRateAction::Close && self.max_rate_bpm < NO_BANDWIDTH_LIMIT;
+        if self.min_rate_bpm == 0 && !close_too_fast {
+            return Ok(());
+        }
+
+        if meter.elapsed(now) < self.rate_grace_period {
             return Ok(());
         }
 
-        let elapsed = Instant::now().duration_since(*start);
-        if elapsed.as_secs_f32() > 5.
-            && total_bytes as u64 / elapsed.as_secs() as u64 > self.max_rate_bpm
-        {
+        let rate_bpm = meter.rate_bpm(now);
+        if close_too_fast && rate_bpm > self.max_rate_bpm as f64 {
             // prevent bandwidth abuse
             // https://patents.google.com/patent/US20140010082A1/en
             Err(RelayShutdownReasons::TooFast)
-        } else if elapsed.as_secs_f32() >= 30.
-            && total_bytes as f64 / elapsed.as_secs_f64() / 60. < self.min_rate_bpm as f64
-        {
+        } else if rate_bpm < self.min_rate_bpm as f64 {
             // prevent slowloris https://en.wikipedia.org/wiki/Slowloris_(computer_security)
             Err(RelayShutdownReasons::TooSlow)
         } else {
@@ -97,6 +161,17 @@ pub struct Relay {
     name: &'static str,
     relay_policy: RelayPolicy,
     tunnel_ctx: TunnelCtx,
+    /// Interrupts relaying on proxy shutdown, even if the connection is idle.
+    #[builder(default)]
+    force_close: Option<ForceClose>,
+    #[builder(default)]
+    termination: Option<Termination>,
+    /// Relayed bytes so far, for the tunnel registry.
+    #[builder(default)]
+    live_bytes: Option<Arc<AtomicU64>>,
+    /// Shared with other tunnels, e.g. of the same user, see `BandwidthLimiter`.
+    #[builder(default)]
+    shared_buckets: Vec<Arc<TokenBucket>>,
 }
 
 impl Relay {
@@ -120,64 +195,93 @@ impl Relay {
         let mut total_bytes = 0;
         let mut event_count = 0;
         let start_time = Instant::now();
+        let mut rate_meter = RateMeter::new(start_time, self.relay_policy.rate_window);
         let shutdown_reason;
+        let mut force_close = self.force_close.clone();
+        let mut termination = self.termination.clone();
+        let mut token_buckets = self.shared_buckets.clone();
+        token_buckets.extend(self.relay_policy.token_bucket().map(Arc::new));
 
         loop {
-            let read_result = self
-                .relay_policy
-                .timed_operation(source.read(&mut buffer))
-                .await;
-            
-                if read_result.is_err() {
-                    shutdown_reason = RelayShutdownReasons::ReaderTimeout;
+            // select!: Waits on multiple concurrent branches, returning when the first branch completes.
+            // https://docs.rs/tokio/1.10.1/tokio/macro.select.html
+            let read_result = tokio::select! {
+                result = self.relay_policy.timed_operation(source.read(&mut buffer)) => result,
+                reason = interrupted(&mut force_close, &mut termination) => {
+                    shutdown_reason = reason;
                     break;
                 }
+            };
 
-                let n = match read_result.unwrap() {
-                    Ok(n) if n == 0 => {
-                        shutdown_reason = RelayShutdownReasons::GracefulShutdown;
-                        break;
-                    }
-                    Ok(n) => n,
-                    Err(e) => {
-                        error!(
-                            "{} failed to read, Err = {:?}, CTX={}",
-                            self.name, e, self.tunnel_ctx
-                        );
-                        shutdown_reason = RelayShutdownReasons::ReadError;
-                        break;
-                    }
-                };
-
-                let write_result = self
-                    .relay_policy
-                    .timed_operation(dest.write_all(&buffer[..n]))
-                    .await;
-                
-                if write_result.is_err() {
-                    shutdown_reason = RelayShutdownReasons::WriterTimeout;
+            if read_result.is_err() {
+                shutdown_reason = RelayShutdownReasons::ReaderTimeout;
+                break;
+            }
+
+            let n = match read_result.unwrap() {
+                Ok(n) if n == 0 => {
+                    shutdown_reason = RelayShutdownReasons::GracefulShutdown;
                     break;
                 }
-
-                if let Err(e) = write_result.unwrap() {
+                Ok(n) => n,
+                Err(e) => {
                     error!(
-                        "{} failed to write {} bytes. Err = {:?}, CTX={}",
-                        self.name, n, e, self.tunnel_ctx
+                        "{} failed to read, Err = {:?}, CTX={}",
+                        self.name, e, self.tunnel_ctx
                     );
-                    shutdown_reason = RelayShutdownReasons::WriteError;
+                    shutdown_reason = RelayShutdownReasons::ReadError;
                     break;
                 }
+            };
 
-                total_bytes += n;
-                event_count += 1;
+            // Holding the data back stops reading too, so TCP flow control slows the sender down.
+            let delay = token_buckets.iter().map(|bucket| bucket.take(n)).max();
+            if let Some(delay) = delay.filter(|delay| !delay.is_zero()) {
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    reason = interrupted(&mut force_close, &mut termination) => {
+                        shutdown_reason = reason;
+                        break;
+                    }
+                }
+            }
 
-                if let Err(rate_violation) = self
-                    .relay_policy
-                    .check_transimission_rates(&start_time, total_bytes)
-                {
-                    shutdown_reason = rate_violation;
+            let write_result = tokio::select! {
+                result = self.relay_policy.timed_operation(dest.write_all(&buffer[..n])) => result,
+                reason = interrupted(&mut force_close, &mut termination) => {
+                    shutdown_reason = reason;
                     break;
                 }
+            };
+            
+            if write_result.is_err() {
+                shutdown_reason = RelayShutdownReasons::WriterTimeout;
+                break;
+            }
+
+            if let Err(e) = write_result.unwrap() {
+                error!(
+                    "{} failed to write {} bytes. Err = {:?}, CTX={}",
+                    self.name, n, e, self.tunnel_ctx
+                );
+                shutdown_reason = RelayShutdownReasons::WriteError;
+                break;
+            }
+
+            total_bytes += n;
+            event_count += 1;
+            rate_meter.record(Instant::now(), n);
+            if let Some(live_bytes) = &self.live_bytes {
+                live_bytes.fetch_add(n as u64, Ordering::Relaxed);
+            }
+
+            if let Err(rate_violation) = self
+                .relay_policy
+                .check_transimission_rates(&mut rate_meter, Instant::now())
+            {
+                shutdown_reason = rate_violation;
+                break;
+            }
         }
 
         self.shutdown(&mut dest, &shutdown_reason).await;
@@ -220,6 +324,87 @@ impl Relay {
     }
 }
 
+/// Sliding window of relayed bytes, in one-second slots.
+/// https://en.wikipedia.org/wiki/Sliding_window_protocol
+pub struct RateMeter {
+    start: Instant,
+    window_secs: u64,
+    // (seconds since start, bytes), the oldest first
+    slots: VecDeque<(u64, u64)>,
+    window_bytes: u64,
+}
+
+impl RateMeter {
+    pub fn new(start: Instant, window: Duration) -> Self {
+        Self {
+            start,
+            window_secs: window.as_secs().max(1),
+            slots: VecDeque::new(),
+            window_bytes: 0,
+        }
+    }
+
+    pub fn elapsed(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.start)
+    }
+
+    pub fn record(&mut self, now: Instant, bytes: usize) {
+        let second = self.elapsed(now).as_secs();
+        match self.slots.back_mut() {
+            Some((last, slot_bytes)) if *last == second => *slot_bytes += bytes as u64,
+            _ => self.slots.push_back((second, bytes as u64)),
+        }
+        self.window_bytes += bytes as u64;
+        self.expire(second);
+    }
+
+    /// Bytes per minute over the window, or since the start while it's shorter than the window.
+    pub fn rate_bpm(&mut self, now: Instant) -> f64 {
+        let elapsed = self.elapsed(now);
+        self.expire(elapsed.as_secs());
+
+        let span = elapsed.as_secs_f64().min(self.window_secs as f64);
+        if span <= 0. {
+            return 0.;
+        }
+        self.window_bytes as f64 * 60. / span
+    }
+
+    fn expire(&mut self, second: u64) {
+        while let Some((slot, bytes)) = self.slots.front() {
+            if slot + self.window_secs > second {
+                break;
+            }
+            self.window_bytes -= bytes;
+            self.slots.pop_front();
+        }
+    }
+}
+
+/// Completes if the proxy is closing tunnels or this one is terminated, never without either signal.
+async fn interrupted(
+    force_close: &mut Option<ForceClose>,
+    termination: &mut Option<Termination>,
+) -> RelayShutdownReasons {
+    let force_closed = async {
+        match force_close {
+            Some(force_close) => force_close.wait().await,
+            None => futures::future::pending().await,
+        }
+    };
+    let terminated = async {
+        match termination {
+            Some(termination) => termination.wait().await,
+            None => futures::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        _ = force_closed => RelayShutdownReasons::ProxyShutdown,
+        _ = terminated => RelayShutdownReasons::Terminated,
+    }
+}
+
 /// (Original comments)
 /// Stats after the relay is closed. Can be used for telemetry/monitoring.
 #[derive(Builder, Clone, Debug, Serialize)]
@@ -242,4 +427,112 @@ impl fmt::Display for RelayStats {
             self.total_bytes as f64 / 1024. / self.duration.as_secs_f64()
         )
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(min_rate_bpm: u64, max_rate_bpm: u64, max_rate_action: MaxRateAction) -> RelayPolicy {
+        RelayPolicyBuilder::default()
+            .idle_timeout(Duration::from_secs(30))
+            .min_rate_bpm(min_rate_bpm)
+            .max_rate_bpm(max_rate_bpm)
+            .max_rate_action(max_rate_action)
+            .rate_grace_period(Duration::from_secs(30))
+            .rate_window(Duration::from_secs(60))
+            .build()
+            .unwrap()
+    }
+
+    fn at(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn rate_meter_slides() {
+        let start = Instant::now();
+        let mut meter = RateMeter::new(start, Duration::from_secs(60));
+        assert_eq!(meter.rate_bpm(start), 0.);
+
+        meter.record(at(start, 0), 600);
+        meter.record(at(start, 0), 600);
+        // Shorter than the window, the rate is since the start.
+        assert_eq!(meter.rate_bpm(at(start, 30)), 2400.);
+        assert_eq!(meter.rate_bpm(at(start, 59)), 1200. * 60. / 59.);
+
+        meter.record(at(start, 59), 60);
+        // The first second has left the window.
+        assert_eq!(meter.rate_bpm(at(start, 60)), 60.);
+        assert_eq!(meter.rate_bpm(at(start, 118)), 60.);
+        assert_eq!(meter.rate_bpm(at(start, 119)), 0.);
+    }
+
+    #[test]
+    fn rate_meter_window_is_at_least_a_second() {
+        let start = Instant::now();
+        let mut meter = RateMeter::new(start, Duration::from_millis(500));
+        meter.record(at(start, 0), 10);
+        assert_eq!(meter.rate_bpm(start + Duration::from_millis(500)), 1200.);
+        assert_eq!(meter.rate_bpm(at(start, 1)), 0.);
+    }
+
+    #[test]
+    fn nothing_is_checked_in_the_grace_period() {
+        let policy = policy(1000, NO_BANDWIDTH_LIMIT, MaxRateAction::Throttle);
+        let start = Instant::now();
+        let mut meter = RateMeter::new(start, policy.rate_window);
+
+        assert_eq!(policy.check_transimission_rates(&mut meter, at(start, 29)), Ok(()));
+        assert_eq!(
+            policy.check_transimission_rates(&mut meter, at(start, 30)),
+            Err(RelayShutdownReasons::TooSlow)
+        );
+    }
+
+    #[test]
+    fn slow_after_a_fast_start() {
+        let policy = policy(1000, NO_BANDWIDTH_LIMIT, MaxRateAction::Throttle);
+        let start = Instant::now();
+        let mut meter = RateMeter::new(start, policy.rate_window);
+
+        meter.record(at(start, 1), 1_000_000);
+        assert_eq!(policy.check_transimission_rates(&mut meter, at(start, 60)), Ok(()));
+        // The burst is out of the window, a rate since the start would still be fine.
+        meter.record(at(start, 70), 10);
+        assert_eq!(
+            policy.check_transimission_rates(&mut meter, at(start, 70)),
+            Err(RelayShutdownReasons::TooSlow)
+        );
+    }
+
+    #[test]
+    fn too_fast_closes_only_with_close() {
+        let start = Instant::now();
+
+        let close = policy(0, 1000, MaxRateAction::Close);
+        let mut meter = RateMeter::new(start, close.rate_window);
+        meter.record(at(start, 31), 10_000);
+        assert_eq!(
+            close.check_transimission_rates(&mut meter, at(start, 31)),
+            Err(RelayShutdownReasons::TooFast)
+        );
+        assert!(close.token_bucket().is_none());
+
+        // Throttled by the bucket instead.
+        let throttle = policy(0, 1000, MaxRateAction::Throttle);
+        let mut meter = RateMeter::new(start, throttle.rate_window);
+        meter.record(at(start, 31), 10_000);
+        assert_eq!(throttle.check_transimission_rates(&mut meter, at(start, 31)), Ok(()));
+        assert!(throttle.token_bucket().is_some());
+    }
+
+    #[test]
+    fn unlimited_rates_are_not_checked() {
+        let policy = policy(0, NO_BANDWIDTH_LIMIT, MaxRateAction::Close);
+        let start = Instant::now();
+        let mut meter = RateMeter::new(start, policy.rate_window);
+        meter.record(at(start, 31), 1_000_000_000);
+        assert_eq!(policy.check_transimission_rates(&mut meter, at(start, 31)), Ok(()));
+        assert!(policy.token_bucket().is_none());
+    }
+}
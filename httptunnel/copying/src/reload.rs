@@ -0,0 +1,241 @@
+use crate::configuration::TunnelConfig;
+
+use log::{error, info, warn};
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// How often the configuration file is checked for changes,
+/// the same as `refresh_rate` of log4rs.yaml.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The tunnel configuration that can be swapped while the proxy is running.
+/// A tunnel takes a snapshot (`current`) when it's accepted and keeps it until it's closed,
+/// so a reload affects new tunnels only.
+#[derive(Clone)]
+pub struct SharedTunnelConfig {
+    current: Arc<RwLock<Arc<TunnelConfig>>>,
+}
+
+impl SharedTunnelConfig {
+    pub fn new(tunnel_config: TunnelConfig) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(Arc::new(tunnel_config))),
+        }
+    }
+
+    pub fn current(&self) -> Arc<TunnelConfig> {
+        self.current.read().expect("Poisoned lock").clone()
+    }
+
+    fn replace(&self, tunnel_config: TunnelConfig) {
+        *self.current.write().expect("Poisoned lock") = Arc::new(tunnel_config);
+    }
+}
+
+/// Reloads `filename` when it's modified or on SIGHUP (e.g. to re-read the credentials file).
+/// A file that fails to parse or validate is rejected and the current configuration stays.
+pub async fn watch_config_file(filename: String, shared_config: SharedTunnelConfig) {
+    let mut active_yaml = fs::read_to_string(&filename).unwrap_or_default();
+    let mut last_modified = modified(&filename);
+
+    let mut interval = tokio::time::interval(CONFIG_POLL_INTERVAL);
+    let mut hangup = HangupSignal::new();
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let modified = modified(&filename);
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+                info!("Configuration file {} changed, reloading", filename);
+            }
+            _ = hangup.recv() => info!("Received SIGHUP, reloading {}", filename),
+        }
+
+        reload(&filename, &shared_config, &mut active_yaml);
+    }
+}
+
+fn reload(filename: &str, shared_config: &SharedTunnelConfig, active_yaml: &mut String) {
+    let yaml = match fs::read_to_string(filename) {
+        Ok(yaml) => yaml,
+        Err(e) => {
+            error!("Error reading config file {}: {}, keeping the current configuration", filename, e);
+            return;
+        }
+    };
+
+    let changes = line_diff(active_yaml, &yaml);
+
+    match TunnelConfig::from_yaml(yaml.as_bytes()) {
+        Ok(tunnel_config) => {
+            let (new, current) = (&tunnel_config.target_connection, &shared_config.current().target_connection);
+            if new.dns_cache_ttl != current.dns_cache_ttl
+                || new.dns_cache_size != current.dns_cache_size
+                || new.dns_negative_cache_ttl != current.dns_negative_cache_ttl
+                || new.nameservers != current.nameservers
+                || new.dns_overrides != current.dns_overrides
+            {
+                warn!("The DNS settings can't be changed without a restart, ignored");
+            }
+            shared_config.replace(tunnel_config);
+            info!("Reloaded {}, changes:\n{}", filename, changes);
+            *active_yaml = yaml;
+        }
+        Err(e) => {
+            error!(
+                "Rejected {}: {}, keeping the current configuration. Rejected changes:\n{}",
+                filename, e, changes
+            );
+        }
+    }
+}
+
+fn modified(filename: &str) -> Option<SystemTime> {
+    fs::metadata(filename).and_then(|m| m.modified()).ok()
+}
+
+/// Changed lines, `-` for removed and `+` for added ones, with the secret values masked (see `mask_value`).
+/// Based on the longest common subsequence, config files are small enough for the O(n*m) table.
+/// https://en.wikipedia.org/wiki/Longest_common_subsequence_problem
+fn line_diff(old: &str, new: &str) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    // lcs[i][j]: the LCS length of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff.push_str(&format!("{:>4} - {}\n", i + 1, mask_value(old[i])));
+            i += 1;
+        } else {
+            diff.push_str(&format!("{:>4} + {}\n", j + 1, mask_value(new[j])));
+            j += 1;
+        }
+    }
+
+    if diff.is_empty() {
+        diff.push_str("(none)\n");
+    }
+    diff
+}
+
+/// Keys whose values are secrets, e.g. `upstream_proxies[].credentials.password`.
+const SECRET_KEYS: [&str; 4] = ["password", "credentials", "username", "token"];
+
+/// Replaces the value of a secret key (`SECRET_KEYS`) with `***`, other lines are kept as they are.
+/// The diff goes to the application log, a commented out secret is masked as well.
+fn mask_value(line: &str) -> String {
+    let trimmed = line.trim_start();
+    let mut rest = trimmed.strip_prefix('#').map_or(trimmed, str::trim_start);
+    while let Some(item) = rest.strip_prefix('-').filter(|item| item.is_empty() || item.starts_with(' ')) {
+        rest = item.trim_start();
+    }
+
+    // A flow collection (`credentials: {...}`) is masked as a whole.
+    match rest.find(": ") {
+        Some(end)
+            if !rest[end + 1..].trim().is_empty()
+                && SECRET_KEYS
+                    .iter()
+                    .any(|key| rest[..end].trim_matches(|c| c == '"' || c == '\'').eq_ignore_ascii_case(key)) =>
+        {
+            let value_start = line.len() - rest.len() + end + 1;
+            format!("{} ***", &line[..value_start])
+        }
+        _ => line.to_string(),
+    }
+}
+
+/// SIGHUP on Unix, never fires elsewhere.
+struct HangupSignal {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl HangupSignal {
+    fn new() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let signal = signal(SignalKind::hangup())
+                .map_err(|e| warn!("Cannot listen for SIGHUP: {}", e))
+                .ok();
+            Self { signal }
+        }
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = self.signal.as_mut() {
+            signal.recv().await;
+            return;
+        }
+        futures::future::pending::<()>().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mask_value_hides_secrets_only() {
+        for (line, masked) in [
+            ("      password: secret", "      password: ***"),
+            ("      username: copying", "      username: ***"),
+            ("      Token: \"abc\"", "      Token: ***"),
+            ("    \"password\": secret", "    \"password\": ***"),
+            ("    credentials: {username: a, password: b}", "    credentials: ***"),
+            ("  - password: secret", "  - password: ***"),
+            ("  # password: secret", "  # password: ***"),
+            ("    credentials:", "    credentials:"),
+            ("      password:", "      password:"),
+            ("  - targets: \"\\\\.corp\\\\.example\\\\.com:443$\"", "  - targets: \"\\\\.corp\\\\.example\\\\.com:443$\""),
+            ("  relay_policy: {idle_timeout: 30s, min_rate_bpm: 0}", "  relay_policy: {idle_timeout: 30s, min_rate_bpm: 0}"),
+            ("    - \"10.0.0.0/8\"", "    - \"10.0.0.0/8\""),
+            ("  credentials_file: ./config/htpasswd", "  credentials_file: ./config/htpasswd"),
+            ("  # Allow the office network", "  # Allow the office network"),
+            ("", ""),
+        ] {
+            assert_eq!(mask_value(line), masked, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn line_diff_doesnt_show_secrets() {
+        let old = "upstream_proxies:\n  - address: proxy:3128\n    credentials:\n      username: copying\n      password: old-secret\n";
+        let new = "upstream_proxies:\n  - address: proxy:3128\n    credentials:\n      username: copying\n      password: new-secret\n";
+
+        let diff = line_diff(old, new);
+        assert_eq!(diff, "   5 -       password: ***\n   5 +       password: ***\n");
+
+        let new = new.replace("proxy:3128", "proxy:8080");
+        let diff = line_diff(old, &new);
+        assert_eq!(
+            diff,
+            "   2 -   - address: proxy:3128\n   2 +   - address: proxy:8080\n   5 -       password: ***\n   5 +       password: ***\n"
+        );
+        assert_eq!(line_diff(old, old), "(none)\n");
+    }
+}
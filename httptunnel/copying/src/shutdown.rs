@@ -0,0 +1,141 @@
+use log::{info, warn};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::time::timeout;
+
+/// The proxy lifecycle, broadcast to every tunnel.
+/// Ordered, so a tunnel can wait for a state "or later".
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub enum ProxyState {
+    Running,
+    /// New connections are not accepted, active tunnels keep relaying.
+    Draining,
+    /// The drain deadline passed, active tunnels must close.
+    Closing,
+}
+
+/// Stops the proxy: tells tunnels to drain, then to close, and waits for them to finish.
+///
+/// Tunnels are tracked by `ShutdownListener`s, each of them holds an `mpsc::Sender`.
+/// Once all of them are dropped, the receiver returns `None`, i.e. there is nothing in flight.
+/// https://tokio.rs/tokio/topics/shutdown#waiting-for-things-to-finish-shutting-down
+pub struct Shutdown {
+    state: watch::Sender<ProxyState>,
+    // Listeners are cloned from it, it also keeps the channel open for `send`.
+    state_receiver: watch::Receiver<ProxyState>,
+    in_flight_sender: Option<mpsc::Sender<()>>,
+    in_flight_receiver: mpsc::Receiver<()>,
+}
+
+/// Held by a tunnel for its whole lifetime, including reporting the stats.
+#[derive(Clone)]
+pub struct ShutdownListener {
+    state: watch::Receiver<ProxyState>,
+    _in_flight: mpsc::Sender<()>,
+}
+
+/// Completes once the tunnel must be closed.
+#[derive(Clone)]
+pub struct ForceClose {
+    state: watch::Receiver<ProxyState>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (state, state_receiver) = watch::channel(ProxyState::Running);
+        let (in_flight_sender, in_flight_receiver) = mpsc::channel(1);
+        Self {
+            state,
+            state_receiver,
+            in_flight_sender: Some(in_flight_sender),
+            in_flight_receiver,
+        }
+    }
+
+    pub fn listener(&self) -> ShutdownListener {
+        ShutdownListener {
+            state: self.state_receiver.clone(),
+            _in_flight: self
+                .in_flight_sender
+                .clone()
+                .expect("Bug: listener requested after shutdown"),
+        }
+    }
+
+    /// Lets active tunnels finish within `drain_timeout`, then force-closes the rest
+    /// and waits until all of them reported their stats.
+    /// The caller must stop accepting connections (i.e. drop the accept loop with its listener) first.
+    pub async fn drain(mut self, drain_timeout: Duration) {
+        self.set_state(ProxyState::Draining);
+        // Otherwise `recv` never returns `None`.
+        self.in_flight_sender.take();
+
+        info!("Draining active tunnels for up to {:?}", drain_timeout);
+
+        if timeout(drain_timeout, self.in_flight_receiver.recv()).await.is_ok() {
+            info!("All tunnels drained");
+            return;
+        }
+
+        warn!("Drain timeout {:?} expired, closing active tunnels", drain_timeout);
+        self.set_state(ProxyState::Closing);
+
+        // Relays close right away, tunnels that haven't started relaying yet
+        // are still bound by the initiation and connect timeouts.
+        self.in_flight_receiver.recv().await;
+        info!("All tunnels closed");
+    }
+
+    fn set_state(&self, state: ProxyState) {
+        // Can't fail, `state_receiver` is alive as long as `self`.
+        let _ = self.state.send(state);
+    }
+}
+
+impl ShutdownListener {
+    pub fn force_close(&self) -> ForceClose {
+        ForceClose {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl ForceClose {
+    pub async fn wait(&mut self) {
+        loop {
+            if *self.state.borrow() >= ProxyState::Closing {
+                return;
+            }
+            // The sender is gone, the process is exiting anyway.
+            if self.state.changed().await.is_err() {
+                futures::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+/// Waits for SIGINT (Ctrl+C) or, on Unix, SIGTERM.
+/// https://docs.rs/tokio/1/tokio/signal/index.html
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+                    _ = sigterm.recv() => info!("Received SIGTERM"),
+                }
+                return;
+            }
+            Err(e) => warn!("Cannot listen for SIGTERM: {}", e),
+        }
+    }
+
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        warn!("Cannot listen for SIGINT: {}", e);
+        futures::future::pending::<()>().await;
+    }
+    info!("Received SIGINT");
+}
@@ -0,0 +1,375 @@
+use bytes::{Buf, BufMut, BytesMut};
+use log::debug;
+use regex::Regex;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::access_control::{AccessControlList, AccessDecision, AccessRequest};
+use crate::http_tunnel_codec::{is_valid_host_name, HttpTunnelTarget, HttpTunnelTargetBuilder};
+use crate::proxy_target::Nugget;
+use crate::tunnel::{EstablishTunnelResult, TunnelCtx};
+
+use tokio_util::codec::{Decoder, Encoder};
+
+/// The port of the target, SNI carries only the host name.
+const HTTPS_PORT: u16 = 443;
+
+/// TLS record layer
+/// https://datatracker.ietf.org/doc/html/rfc8446#section-5.1
+/// > struct {
+/// >     ContentType type;
+/// >     ProtocolVersion legacy_record_version;
+/// >     uint16 length;
+/// >     opaque fragment[TLSPlaintext.length];
+/// > } TLSPlaintext;
+const RECORD_HEADER_SIZE: usize = 5;
+/// > The length MUST NOT exceed 2^14 bytes.
+const MAX_RECORD_SIZE: usize = 16384;
+
+const CONTENT_TYPE_ALERT: u8 = 0x15;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+
+/// Server Name Indication
+/// https://datatracker.ietf.org/doc/html/rfc6066#section-3
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// https://datatracker.ietf.org/doc/html/rfc8446#section-6
+const ALERT_LEVEL_FATAL: u8 = 0x02;
+const ALERT_HANDSHAKE_FAILURE: u8 = 40;
+const ALERT_ACCESS_DENIED: u8 = 49;
+const ALERT_DECODE_ERROR: u8 = 50;
+const ALERT_INTERNAL_ERROR: u8 = 80;
+
+/// Codec to route TLS connections by the SNI of their `ClientHello`, without terminating TLS,
+/// the counterpart of `HttpTunnelCodec` for clients that can't be configured with a proxy.
+/// The `ClientHello` is sent to the target as the nugget, so the client completes the handshake with the target itself.
+///
+/// The `ClientHello` has to fit into the first record, which it does unless it's unusually large.
+#[derive(Clone, Builder)]
+pub struct SniCodec {
+    tunnel_ctx: TunnelCtx,
+    enabled_targets: Regex,
+    #[builder(default)]
+    access_control: AccessControlList,
+    #[builder(default)]
+    client_addr: Option<SocketAddr>,
+}
+
+impl SniCodec {
+    /// Checks the access rules before connecting, the same as `HttpTunnelCodec` does.
+    /// There is no user, the client doesn't talk to the proxy.
+    fn check_access(&self, target: &str) -> Result<(), EstablishTunnelResult> {
+        let mut request = AccessRequest::from_target(target).ok_or_else(|| {
+            debug!("Bad target `{}`, CTX={}", target, self.tunnel_ctx);
+            EstablishTunnelResult::BadRequest
+        })?;
+        request.client = self.client_addr.map(|addr| addr.ip());
+
+        match self.access_control.check(&request) {
+            AccessDecision::Deny => {
+                debug!(
+                    "Target `{}` is denied by the access rules, CTX={}",
+                    target, self.tunnel_ctx
+                );
+                Err(EstablishTunnelResult::Forbidden)
+            }
+            AccessDecision::Allow | AccessDecision::Undecided => Ok(()),
+        }
+    }
+}
+
+/// Returns the host name of the `server_name` extension, `None` if the `ClientHello` is malformed or has none.
+/// https://datatracker.ietf.org/doc/html/rfc8446#section-4.1.2
+/// > struct {
+/// >     ProtocolVersion legacy_version = 0x0303;    /* TLS v1.2 */
+/// >     Random random;
+/// >     opaque legacy_session_id<0..32>;
+/// >     CipherSuite cipher_suites<2..2^16-2>;
+/// >     opaque legacy_compression_methods<1..2^8-1>;
+/// >     Extension extensions<8..2^16-1>;
+/// > } ClientHello;
+fn parse_server_name(mut handshake: &[u8]) -> Option<String> {
+    if take(&mut handshake, 1)?[0] != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let length = take_u24(&mut handshake)?;
+    let mut client_hello = take(&mut handshake, length)?;
+
+    // legacy_version and random
+    take(&mut client_hello, 2 + 32)?;
+    take_u8_prefixed(&mut client_hello)?;
+    take_u16_prefixed(&mut client_hello)?;
+    take_u8_prefixed(&mut client_hello)?;
+    let mut extensions = take_u16_prefixed(&mut client_hello)?;
+
+    while !extensions.is_empty() {
+        let extension_type = take_u16(&mut extensions)?;
+        let mut extension_data = take_u16_prefixed(&mut extensions)?;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        // > struct {
+        // >     NameType name_type;
+        // >     select (name_type) {
+        // >         case host_name: HostName;
+        // >     } name;
+        // > } ServerName;
+        // > struct {
+        // >     ServerName server_name_list<1..2^16-1>
+        // > } ServerNameList;
+        let mut server_names = take_u16_prefixed(&mut extension_data)?;
+        while !server_names.is_empty() {
+            let name_type = take(&mut server_names, 1)?[0];
+            let name = take_u16_prefixed(&mut server_names)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                // > The hostname is represented as a byte string using ASCII encoding without a trailing dot.
+                return std::str::from_utf8(name).ok().map(|name| name.to_ascii_lowercase());
+            }
+        }
+    }
+
+    None
+}
+
+/// The SNI becomes the target, so it has to be a host name an HTTP `CONNECT` could have sent,
+/// made of labels of 1 to 63 characters.
+/// https://datatracker.ietf.org/doc/html/rfc1035#section-2.3.4
+///
+/// https://datatracker.ietf.org/doc/html/rfc6066#section-3
+/// > Literal IPv4 and IPv6 addresses are not permitted in "HostName".
+fn is_dns_host_name(name: &str) -> bool {
+    is_valid_host_name(name)
+        && name.parse::<IpAddr>().is_err()
+        && name.split('.').all(|label| !label.is_empty() && label.len() <= 63)
+}
+
+fn take<'a>(data: &mut &'a [u8], size: usize) -> Option<&'a [u8]> {
+    if data.len() < size {
+        return None;
+    }
+    let (taken, rest) = data.split_at(size);
+    *data = rest;
+    Some(taken)
+}
+
+fn take_u16(data: &mut &[u8]) -> Option<u16> {
+    take(data, 2).map(|mut bytes| bytes.get_u16())
+}
+
+fn take_u24(data: &mut &[u8]) -> Option<usize> {
+    take(data, 3).map(|mut bytes| bytes.get_uint(3) as usize)
+}
+
+fn take_u8_prefixed<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let size = take(data, 1)?[0] as usize;
+    take(data, size)
+}
+
+fn take_u16_prefixed<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let size = take_u16(data)? as usize;
+    take(data, size)
+}
+
+impl Decoder for SniCodec {
+    type Item = HttpTunnelTarget;
+    type Error = EstablishTunnelResult;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < RECORD_HEADER_SIZE {
+            return Ok(None);
+        }
+        if src[0] != CONTENT_TYPE_HANDSHAKE {
+            debug!("Not a TLS handshake record: {:#04x}, CTX={}", src[0], self.tunnel_ctx);
+            return Err(EstablishTunnelResult::BadRequest);
+        }
+        let record_size = u16::from_be_bytes([src[3], src[4]]) as usize;
+        if record_size > MAX_RECORD_SIZE {
+            debug!("TLS record size {} exceeds limit {}, CTX={}", record_size, MAX_RECORD_SIZE, self.tunnel_ctx);
+            return Err(EstablishTunnelResult::BadRequest);
+        }
+        if src.len() < RECORD_HEADER_SIZE + record_size {
+            return Ok(None);
+        }
+
+        let host = parse_server_name(&src[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + record_size])
+            .ok_or_else(|| {
+                debug!("No SNI in the ClientHello, CTX={}", self.tunnel_ctx);
+                EstablishTunnelResult::BadRequest
+            })?;
+        if !is_dns_host_name(&host) {
+            debug!("Bad SNI {:?}, CTX={}", host, self.tunnel_ctx);
+            return Err(EstablishTunnelResult::BadRequest);
+        }
+        let target = format!("{}:{}", host, HTTPS_PORT);
+
+        if !self.enabled_targets.is_match(&target) {
+            debug!(
+                "Target `{}` is not allowed. Allowed: `{}`, CTX={}",
+                target, self.enabled_targets, self.tunnel_ctx
+            );
+            return Err(EstablishTunnelResult::Forbidden);
+        }
+        self.check_access(&target)?;
+
+        // Everything the client has sent so far goes to the target, starting with the `ClientHello`.
+        Ok(Some(
+            HttpTunnelTargetBuilder::default()
+                .target(target)
+                .nugget(Some(Nugget::new(src.split().to_vec())))
+                .build()
+                .expect("HttpTunnelTargetBuilder failed"),
+        ))
+    }
+
+    /// The client closed the connection in the middle of a `ClientHello`.
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(buf)? {
+            Some(frame) => Ok(Some(frame)),
+            None if buf.is_empty() => Ok(None),
+            None => Err(EstablishTunnelResult::BadRequest),
+        }
+    }
+}
+
+impl Encoder<EstablishTunnelResult> for SniCodec {
+    type Error = std::io::Error;
+
+    /// The target answers the `ClientHello`, the proxy only speaks up with a fatal alert if it can't connect.
+    fn encode(
+        &mut self,
+        item: EstablishTunnelResult,
+        dst: &mut BytesMut,
+    ) -> Result<(), Self::Error> {
+        let description = match item {
+            EstablishTunnelResult::Ok | EstablishTunnelResult::OkWithNugget => return Ok(()),
+            EstablishTunnelResult::Forbidden => ALERT_ACCESS_DENIED,
+            EstablishTunnelResult::BadRequest => ALERT_DECODE_ERROR,
+            EstablishTunnelResult::ProxyAuthenticationRequired
+            | EstablishTunnelResult::OperationNotAllowed => ALERT_HANDSHAKE_FAILURE,
+            EstablishTunnelResult::RequestTimeout
+            | EstablishTunnelResult::TooManyRequests
+            | EstablishTunnelResult::ServerError
+            | EstablishTunnelResult::BadGateway
+            | EstablishTunnelResult::GatewayTimeout => ALERT_INTERNAL_ERROR,
+            EstablishTunnelResult::TlsHandshakeFailed => return Ok(()),
+        };
+
+        // > struct {
+        // >     AlertLevel level;
+        // >     AlertDescription description;
+        // > } Alert;
+        dst.put_slice(&[CONTENT_TYPE_ALERT, 0x03, 0x03, 0x00, 0x02, ALERT_LEVEL_FATAL, description]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_codec() -> SniCodec {
+        SniCodecBuilder::default()
+            .tunnel_ctx(TunnelCtx::default())
+            .enabled_targets(Regex::new(".*").unwrap())
+            .build()
+            .unwrap()
+    }
+
+    /// A TLS record with a `ClientHello`, with a `server_name` extension if `server_name` is set.
+    fn client_hello(server_name: Option<&[u8]>) -> BytesMut {
+        let mut extensions = BytesMut::new();
+        // supported_versions, to have another extension before the SNI
+        extensions.put_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        if let Some(name) = server_name {
+            extensions.put_u16(EXTENSION_SERVER_NAME);
+            extensions.put_u16(name.len() as u16 + 5);
+            extensions.put_u16(name.len() as u16 + 3);
+            extensions.put_u8(NAME_TYPE_HOST_NAME);
+            extensions.put_u16(name.len() as u16);
+            extensions.put_slice(name);
+        }
+
+        let mut body = BytesMut::new();
+        body.put_slice(&[0x03, 0x03]);
+        body.put_slice(&[0x42; 32]);
+        // session id, cipher suites, compression methods
+        body.put_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        body.put_u16(extensions.len() as u16);
+        body.put_slice(&extensions);
+
+        let mut record = BytesMut::new();
+        record.put_slice(&[CONTENT_TYPE_HANDSHAKE, 0x03, 0x01]);
+        record.put_u16(body.len() as u16 + 4);
+        record.put_u8(HANDSHAKE_CLIENT_HELLO);
+        record.put_uint(body.len() as u64, 3);
+        record.put_slice(&body);
+        record
+    }
+
+    fn alert(result: EstablishTunnelResult) -> BytesMut {
+        let mut alert = BytesMut::new();
+        new_codec().encode(result, &mut alert).unwrap();
+        alert
+    }
+
+    #[test]
+    fn sni_becomes_the_target_and_the_client_hello_the_nugget() {
+        let record = client_hello(Some(b"WWW.Example.com"));
+        let mut buffer = record.clone();
+
+        let target = new_codec().decode(&mut buffer).unwrap().unwrap();
+        assert_eq!(target.target, "www.example.com:443");
+        assert_eq!(*target.nugget.unwrap().data(), record.to_vec());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn incomplete_record_waits_for_more() {
+        let record = client_hello(Some(b"www.example.com"));
+        for size in [3, RECORD_HEADER_SIZE, record.len() - 1] {
+            let mut partial = BytesMut::from(&record[..size]);
+            assert!(new_codec().decode(&mut partial).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn client_hello_without_sni_is_rejected() {
+        let result = new_codec().decode(&mut client_hello(None)).err();
+        assert_eq!(result, Some(EstablishTunnelResult::BadRequest));
+    }
+
+    #[test]
+    fn invalid_server_names_are_rejected() {
+        let long_label = [b'a'; 64];
+        for name in [
+            &b""[..],
+            b"evil.com:22",
+            b"evil.com\r\nX-Injected: 1",
+            b"evil com",
+            b"a..example.com",
+            b".example.com",
+            b"127.0.0.1",
+            b"\xff\xfe",
+            &long_label,
+        ] {
+            let result = new_codec().decode(&mut client_hello(Some(name))).err();
+            assert_eq!(result, Some(EstablishTunnelResult::BadRequest), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn disallowed_target_is_denied_with_an_alert() {
+        let mut codec = new_codec();
+        codec.enabled_targets = Regex::new("^www\\.example\\.com:443$").unwrap();
+        let result = codec.decode(&mut client_hello(Some(b"other.example.com"))).err().unwrap();
+        assert_eq!(result, EstablishTunnelResult::Forbidden);
+        assert_eq!(
+            &alert(result)[..],
+            &[CONTENT_TYPE_ALERT, 0x03, 0x03, 0x00, 0x02, ALERT_LEVEL_FATAL, ALERT_ACCESS_DENIED]
+        );
+        assert!(alert(EstablishTunnelResult::Ok).is_empty());
+    }
+}
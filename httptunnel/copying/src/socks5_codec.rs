@@ -0,0 +1,410 @@
+use bytes::{Buf, BufMut, BytesMut};
+use log::debug;
+use regex::Regex;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::access_control::{AccessControlList, AccessDecision, AccessRequest};
+use crate::authentication::AuthenticationConfig;
+use crate::http_tunnel_codec::{is_valid_host_name, HttpTunnelTarget, HttpTunnelTargetBuilder};
+use crate::tunnel::{EstablishTunnelResult, TunnelCtx};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// SOCKS Protocol Version 5
+/// https://datatracker.ietf.org/doc/html/rfc1928
+const SOCKS_VERSION: u8 = 0x05;
+
+const METHOD_NO_AUTHENTICATION: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NO_ACCEPTABLE: u8 = 0xFF;
+
+/// Username/Password Authentication for SOCKS V5
+/// https://datatracker.ietf.org/doc/html/rfc1929
+const USERNAME_PASSWORD_VERSION: u8 = 0x01;
+const USERNAME_PASSWORD_SUCCESS: u8 = 0x00;
+const USERNAME_PASSWORD_FAILURE: u8 = 0x01;
+
+const COMMAND_CONNECT: u8 = 0x01;
+
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN: u8 = 0x03;
+const ADDRESS_IPV6: u8 = 0x04;
+
+/// RFC 1928 Section 6
+/// > o  REP    Reply field:
+/// >    o  X'00' succeeded
+/// >    o  X'01' general SOCKS server failure
+/// >    o  X'02' connection not allowed by ruleset
+/// >    o  X'03' Network unreachable
+/// >    o  X'04' Host unreachable
+/// >    o  X'05' Connection refused
+/// >    o  X'06' TTL expired
+/// >    o  X'07' Command not supported
+/// >    o  X'08' Address type not supported
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_TTL_EXPIRED: u8 = 0x06;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// Method selection and, if the authentication is enabled, the username/password sub-negotiation.
+/// Both are answered before the client sends its request, so they can't go through `Socks5Codec`,
+/// which decodes a single request and encodes a single reply, like any `ConnectionTunnel` codec.
+/// Returns the authenticated user.
+///
+/// Reads exactly the bytes of the negotiation, the request stays in the stream for the codec.
+pub async fn negotiate<C: AsyncRead + AsyncWrite + Unpin>(
+    client: &mut C,
+    authentication: Option<&AuthenticationConfig>,
+    tunnel_ctx: TunnelCtx,
+) -> Result<Option<String>, EstablishTunnelResult> {
+    // +----+----------+----------+
+    // |VER | NMETHODS | METHODS  |
+    // +----+----------+----------+
+    let version = read_u8(client).await?;
+    if version != SOCKS_VERSION {
+        debug!("Unsupported SOCKS version {}, CTX={}", version, tunnel_ctx);
+        return Err(EstablishTunnelResult::BadRequest);
+    }
+    let methods = read_bytes(client).await?;
+
+    let method = match authentication {
+        None => METHOD_NO_AUTHENTICATION,
+        Some(_) => METHOD_USERNAME_PASSWORD,
+    };
+    if !methods.contains(&method) {
+        debug!("No acceptable SOCKS method in {:?}, CTX={}", methods, tunnel_ctx);
+        write_all(client, &[SOCKS_VERSION, METHOD_NO_ACCEPTABLE]).await?;
+        return Err(match authentication {
+            None => EstablishTunnelResult::BadRequest,
+            Some(_) => EstablishTunnelResult::ProxyAuthenticationRequired,
+        });
+    }
+    write_all(client, &[SOCKS_VERSION, method]).await?;
+
+    let authentication = match authentication {
+        None => return Ok(None),
+        Some(authentication) => authentication,
+    };
+
+    // +----+------+----------+------+----------+
+    // |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+    // +----+------+----------+------+----------+
+    if read_u8(client).await? != USERNAME_PASSWORD_VERSION {
+        return Err(EstablishTunnelResult::BadRequest);
+    }
+    let user = String::from_utf8_lossy(&read_bytes(client).await?).to_string();
+    let password = String::from_utf8_lossy(&read_bytes(client).await?).to_string();
+
+    if authentication.credentials.verify(&user, &password) {
+        debug!("Authenticated `{}`, CTX={}", user, tunnel_ctx);
+        write_all(client, &[USERNAME_PASSWORD_VERSION, USERNAME_PASSWORD_SUCCESS]).await?;
+        Ok(Some(user))
+    } else {
+        debug!("Proxy authentication failed, CTX={}", tunnel_ctx);
+        // > If the server returns a `failure' status, it MUST close the connection.
+        write_all(client, &[USERNAME_PASSWORD_VERSION, USERNAME_PASSWORD_FAILURE]).await?;
+        Err(EstablishTunnelResult::ProxyAuthenticationRequired)
+    }
+}
+
+async fn read_u8<C: AsyncRead + Unpin>(client: &mut C) -> Result<u8, EstablishTunnelResult> {
+    // The client closed the connection in the middle of the negotiation.
+    client.read_u8().await.map_err(|_| EstablishTunnelResult::BadRequest)
+}
+
+/// A length-prefixed field.
+async fn read_bytes<C: AsyncRead + Unpin>(client: &mut C) -> Result<Vec<u8>, EstablishTunnelResult> {
+    let mut bytes = vec![0; read_u8(client).await? as usize];
+    client
+        .read_exact(&mut bytes)
+        .await
+        .map_err(|_| EstablishTunnelResult::BadRequest)?;
+    Ok(bytes)
+}
+
+async fn write_all<C: AsyncWrite + Unpin>(client: &mut C, bytes: &[u8]) -> Result<(), EstablishTunnelResult> {
+    client
+        .write_all(bytes)
+        .await
+        .map_err(|_| EstablishTunnelResult::BadRequest)
+}
+
+/// Codec to extract SOCKS5 `CONNECT` requests and build the corresponding reply,
+/// the counterpart of `HttpTunnelCodec`. Run `negotiate` on the stream first.
+#[derive(Clone, Builder)]
+pub struct Socks5Codec {
+    tunnel_ctx: TunnelCtx,
+    enabled_targets: Regex,
+    #[builder(default)]
+    access_control: AccessControlList,
+    #[builder(default)]
+    client_addr: Option<SocketAddr>,
+    /// From `negotiate`.
+    #[builder(default)]
+    user: Option<String>,
+    // Set when a request is rejected with a reason `EstablishTunnelResult` has no variant for.
+    #[builder(setter(skip))]
+    reply_override: Option<u8>,
+}
+
+impl Socks5Codec {
+    /// Checks the access rules before connecting, the same as `HttpTunnelCodec` does.
+    fn check_access(&self, target: &str) -> Result<(), EstablishTunnelResult> {
+        let mut request = AccessRequest::from_target(target).ok_or_else(|| {
+            debug!("Bad target `{}`, CTX={}", target, self.tunnel_ctx);
+            EstablishTunnelResult::BadRequest
+        })?;
+        request.user = self.user.as_deref();
+        request.client = self.client_addr.map(|addr| addr.ip());
+
+        match self.access_control.check(&request) {
+            AccessDecision::Deny => {
+                debug!(
+                    "Target `{}` is denied by the access rules, CTX={}",
+                    target, self.tunnel_ctx
+                );
+                Err(EstablishTunnelResult::Forbidden)
+            }
+            AccessDecision::Allow | AccessDecision::Undecided => Ok(()),
+        }
+    }
+
+    fn reject(&mut self, reply: u8, result: EstablishTunnelResult) -> EstablishTunnelResult {
+        self.reply_override = Some(reply);
+        result
+    }
+}
+
+/// The size of a complete request, if it has been received in full.
+/// +----+-----+-------+------+----------+----------+
+/// |VER | CMD |  RSV  | ATYP | DST.ADDR | DST.PORT |
+/// +----+-----+-------+------+----------+----------+
+fn socks_request_size(buffer: &BytesMut) -> Option<usize> {
+    let address_size = match *buffer.get(3)? {
+        ADDRESS_IPV4 => 4,
+        ADDRESS_IPV6 => 16,
+        ADDRESS_DOMAIN => 1 + *buffer.get(4)? as usize,
+        // Rejected when decoding.
+        _ => 0,
+    };
+    let size = 4 + address_size + 2;
+    if buffer.len() >= size {
+        Some(size)
+    } else {
+        None
+    }
+}
+
+impl Decoder for Socks5Codec {
+    type Item = HttpTunnelTarget;
+    type Error = EstablishTunnelResult;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let request_size = match socks_request_size(src) {
+            Some(size) => size,
+            None => return Ok(None),
+        };
+
+        // Bytes pipelined after the request stay in the buffer.
+        let mut request = src.split_to(request_size);
+        let version = request.get_u8();
+        let command = request.get_u8();
+        let _reserved = request.get_u8();
+        let address_type = request.get_u8();
+
+        if version != SOCKS_VERSION {
+            debug!("Unsupported SOCKS version {}, CTX={}", version, self.tunnel_ctx);
+            return Err(EstablishTunnelResult::BadRequest);
+        }
+        // BIND and UDP ASSOCIATE need the proxy to accept connections or datagrams for the client.
+        if command != COMMAND_CONNECT {
+            debug!("Unsupported SOCKS command {}, CTX={}", command, self.tunnel_ctx);
+            return Err(self.reject(
+                REPLY_COMMAND_NOT_SUPPORTED,
+                EstablishTunnelResult::OperationNotAllowed,
+            ));
+        }
+
+        // The same `host:port` form as an HTTP `CONNECT` target, with brackets around IPv6 addresses.
+        let host = match address_type {
+            ADDRESS_IPV4 => Ipv4Addr::from(request.get_u32()).to_string(),
+            ADDRESS_IPV6 => format!("[{}]", Ipv6Addr::from(request.get_u128())),
+            ADDRESS_DOMAIN => {
+                let length = request.get_u8() as usize;
+                let domain = request.split_to(length);
+                // The same host names as in an HTTP `CONNECT`, e.g. a `:` or a CR/LF would break the target apart.
+                match std::str::from_utf8(&domain) {
+                    Ok(domain) if is_valid_host_name(domain) => domain.to_string(),
+                    _ => {
+                        debug!("Bad SOCKS domain {:?}, CTX={}", String::from_utf8_lossy(&domain), self.tunnel_ctx);
+                        return Err(self.reject(REPLY_GENERAL_FAILURE, EstablishTunnelResult::BadRequest));
+                    }
+                }
+            }
+            _ => {
+                debug!("Unsupported SOCKS address type {}, CTX={}", address_type, self.tunnel_ctx);
+                return Err(self.reject(
+                    REPLY_ADDRESS_TYPE_NOT_SUPPORTED,
+                    EstablishTunnelResult::BadRequest,
+                ));
+            }
+        };
+        let target = format!("{}:{}", host, request.get_u16());
+
+        if !self.enabled_targets.is_match(&target) {
+            debug!(
+                "Target `{}` is not allowed. Allowed: `{}`, CTX={}",
+                target, self.enabled_targets, self.tunnel_ctx
+            );
+            return Err(EstablishTunnelResult::Forbidden);
+        }
+        self.check_access(&target)?;
+
+        Ok(Some(
+            HttpTunnelTargetBuilder::default()
+                .target(target)
+                .nugget(None)
+                .user(self.user.clone())
+                .build()
+                .expect("HttpTunnelTargetBuilder failed"),
+        ))
+    }
+
+    /// The client closed the connection in the middle of a request.
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(buf)? {
+            Some(frame) => Ok(Some(frame)),
+            None if buf.is_empty() => Ok(None),
+            None => Err(EstablishTunnelResult::BadRequest),
+        }
+    }
+}
+
+impl Encoder<EstablishTunnelResult> for Socks5Codec {
+    type Error = std::io::Error;
+
+    fn encode(
+        &mut self,
+        item: EstablishTunnelResult,
+        dst: &mut BytesMut,
+    ) -> Result<(), Self::Error> {
+        let reply = match item {
+            EstablishTunnelResult::Ok | EstablishTunnelResult::OkWithNugget => REPLY_SUCCEEDED,
+            EstablishTunnelResult::Forbidden
+            | EstablishTunnelResult::ProxyAuthenticationRequired => REPLY_NOT_ALLOWED,
+            EstablishTunnelResult::OperationNotAllowed => REPLY_COMMAND_NOT_SUPPORTED,
+            EstablishTunnelResult::BadGateway => REPLY_CONNECTION_REFUSED,
+            EstablishTunnelResult::GatewayTimeout => REPLY_TTL_EXPIRED,
+            EstablishTunnelResult::BadRequest
+            | EstablishTunnelResult::RequestTimeout
+            | EstablishTunnelResult::TooManyRequests
+            | EstablishTunnelResult::ServerError => REPLY_GENERAL_FAILURE,
+            EstablishTunnelResult::TlsHandshakeFailed => {
+                // there is no TLS session to send a reply over
+                return Ok(());
+            }
+        };
+        let reply = self.reply_override.take().unwrap_or(reply);
+
+        // +----+-----+-------+------+----------+----------+
+        // |VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
+        // +----+-----+-------+------+----------+----------+
+        // The bound address is of no use for CONNECT, clients ignore it.
+        dst.put_slice(&[SOCKS_VERSION, reply, 0x00, ADDRESS_IPV4]);
+        dst.put_u32(0);
+        dst.put_u16(0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_codec() -> Socks5Codec {
+        Socks5CodecBuilder::default()
+            .tunnel_ctx(TunnelCtx::default())
+            .enabled_targets(Regex::new(".*").unwrap())
+            .build()
+            .unwrap()
+    }
+
+    fn domain_request(domain: &[u8], port: u16) -> BytesMut {
+        let mut request = BytesMut::new();
+        request.put_slice(&[SOCKS_VERSION, COMMAND_CONNECT, 0x00, ADDRESS_DOMAIN, domain.len() as u8]);
+        request.put_slice(domain);
+        request.put_u16(port);
+        request
+    }
+
+    fn reply_code(codec: &mut Socks5Codec, result: EstablishTunnelResult) -> u8 {
+        let mut reply = BytesMut::new();
+        codec.encode(result, &mut reply).unwrap();
+        reply[1]
+    }
+
+    #[test]
+    fn domain_becomes_the_target() {
+        let mut codec = new_codec();
+        let mut request = domain_request(b"www.example.com", 443);
+        request.put_slice(b"pipelined");
+
+        let target = codec.decode(&mut request).unwrap().unwrap();
+        assert_eq!(target.target, "www.example.com:443");
+        assert_eq!(&request[..], b"pipelined");
+    }
+
+    #[test]
+    fn ip_addresses_become_the_target() {
+        let mut request = BytesMut::from(&[SOCKS_VERSION, COMMAND_CONNECT, 0x00, ADDRESS_IPV4, 10, 0, 0, 1, 0x01, 0xBB][..]);
+        assert_eq!(new_codec().decode(&mut request).unwrap().unwrap().target, "10.0.0.1:443");
+
+        let mut request = BytesMut::from(&[SOCKS_VERSION, COMMAND_CONNECT, 0x00, ADDRESS_IPV6][..]);
+        request.put_u128(1);
+        request.put_u16(443);
+        assert_eq!(new_codec().decode(&mut request).unwrap().unwrap().target, "[::1]:443");
+    }
+
+    #[test]
+    fn incomplete_request_waits_for_more() {
+        let request = domain_request(b"www.example.com", 443);
+        let mut partial = BytesMut::from(&request[..request.len() - 1]);
+        assert!(new_codec().decode(&mut partial).unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_domains_are_rejected_with_a_general_failure() {
+        for domain in [
+            &b""[..],
+            b"evil.com:22",
+            b"evil.com\r\nX-Injected: 1",
+            b"evil.com\n",
+            b"evil com",
+            b"\xff\xfe",
+            b"caf\xc3\xa9.example.com",
+        ] {
+            let mut codec = new_codec();
+            let result = codec.decode(&mut domain_request(domain, 443)).err();
+            assert_eq!(result, Some(EstablishTunnelResult::BadRequest), "{:?}", domain);
+            assert_eq!(reply_code(&mut codec, EstablishTunnelResult::BadRequest), REPLY_GENERAL_FAILURE);
+        }
+    }
+
+    #[test]
+    fn unsupported_commands_and_address_types_have_their_own_replies() {
+        let mut codec = new_codec();
+        let mut request = domain_request(b"www.example.com", 443);
+        request[1] = 0x02; // BIND
+        let result = codec.decode(&mut request).err().unwrap();
+        assert_eq!(reply_code(&mut codec, result), REPLY_COMMAND_NOT_SUPPORTED);
+
+        let mut codec = new_codec();
+        let mut request = BytesMut::from(&[SOCKS_VERSION, COMMAND_CONNECT, 0x00, 0x09, 0x00, 0x00][..]);
+        let result = codec.decode(&mut request).err().unwrap();
+        assert_eq!(reply_code(&mut codec, result), REPLY_ADDRESS_TYPE_NOT_SUPPORTED);
+    }
+}
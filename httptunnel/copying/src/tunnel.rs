@@ -1,17 +1,24 @@
 use async_trait::async_trait;
 
+use crate::bandwidth::{BandwidthLimiter, TokenBucket};
 use crate::configuration::TunnelConfig;
-use crate::proxy_target::{Nugget, TargetConnector};
+use crate::limits::{ConcurrencyLimiter, TunnelPermit};
+use crate::proxy_target::{ConnectStats, Nugget, TargetConnector};
+use crate::registry::LiveTunnel;
 use crate::relay::{RelayStats, RelayPolicy, Relay, RelayBuilder};
+use crate::shutdown::ForceClose;
 
 use core::fmt;
 use futures::{StreamExt, SinkExt};
 use futures::stream::SplitStream;
 use log::{debug, error};
 use std::fmt::Display;
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io;
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::time::timeout;
 use tokio_util::codec::{Decoder, Encoder, Framed};
 
@@ -20,16 +27,59 @@ use tokio_util::codec::{Decoder, Encoder, Framed};
 #[derive(Builder, Copy, Clone, Default, Serialize)]
 pub struct TunnelCtx {
     id: u128,
+    /// The peer of the accepted connection.
+    #[builder(default)]
+    client_addr: Option<SocketAddr>,
 }
 
 /// (Original comments)
 /// Statistics. No sensitive information
 #[derive(Serialize, Builder)]
 pub struct TunnelStats {
-    tunnel_ctx: TunnelCtx,
-    result: EstablishTunnelResult,
-    upstream_stats: Option<RelayStats>,
-    downstream_stats: Option<RelayStats>,
+    pub tunnel_ctx: TunnelCtx,
+    pub result: EstablishTunnelResult,
+    pub upstream_stats: Option<RelayStats>,
+    pub downstream_stats: Option<RelayStats>,
+    #[builder(default)]
+    pub connect_stats: Option<ConnectStats>,
+    /// The target requested by the client, `None` if the request was never decoded.
+    #[builder(default)]
+    pub target: Option<String>,
+    /// The address the target resolved to and got connected, the parent proxy's one if connected through it.
+    #[builder(default)]
+    pub target_addr: Option<SocketAddr>,
+    #[builder(default)]
+    pub timings: TunnelTimings,
+}
+
+/// Where the time before relaying went, a step is `None` if it didn't happen (or didn't finish).
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TunnelTimings {
+    /// Reading the tunnel request, after the TLS handshake or the SOCKS5 negotiation if any.
+    pub handshake: Option<Duration>,
+    /// Resolving the target, short on a DNS cache hit.
+    pub dns: Option<Duration>,
+    /// From the first connection attempt until one succeeded.
+    pub connect: Option<Duration>,
+}
+
+impl TunnelStats {
+    /// Also used for tunnels relayed without `ConnectionTunnel`, e.g. in TCP mode.
+    /// The resolved address and DNS and connect timings are taken from the `connect_stats`.
+    pub fn set_connect_stats(&mut self, connect_stats: Option<ConnectStats>) {
+        if let Some(connect_stats) = &connect_stats {
+            self.target_addr = connect_stats.connected;
+            self.timings.dns = connect_stats.dns_duration;
+            self.timings.connect = connect_stats.connect_latency();
+        }
+        self.connect_stats = connect_stats;
+    }
+}
+
+impl TunnelCtx {
+    pub fn id(&self) -> u128 {
+        self.id
+    }
 }
 
 // https://doc.rust-lang.org/std/fmt/trait.Display.html#examples
@@ -52,6 +102,8 @@ pub enum EstablishTunnelResult {
     BadRequest,
     /// Target is not allowed
     Forbidden,
+    /// The client didn't provide valid proxy credentials
+    ProxyAuthenticationRequired,
     /// Unsupported operation, however valid for the protocol
     OperationNotAllowed,
     /// The client failed to send a tunnel request timely
@@ -64,6 +116,8 @@ pub enum EstablishTunnelResult {
     TooManyRequests,
     /// Any other error. E.g. an abrupt I/O error.
     ServerError,
+    /// The client failed to establish a TLS session (HTTPS mode only).
+    TlsHandshakeFailed,
 }
 
 
@@ -94,7 +148,20 @@ pub struct ConnectionTunnel<H, C, T> {
     tunnel_ctx: TunnelCtx,
     target_connector: T,
     client: Option<C>,
-    tunnel_config: TunnelConfig
+    tunnel_config: TunnelConfig,
+    concurrency_limiter: Option<(Arc<ConcurrencyLimiter>, IpAddr)>,
+    // Released when the tunnel is dropped, i.e. after relaying is over.
+    tunnel_permit: Option<TunnelPermit>,
+    force_close: Option<ForceClose>,
+    live_tunnel: Option<Arc<LiveTunnel>>,
+    bandwidth_limiter: Option<Arc<BandwidthLimiter>>,
+    // Picked once the user and the target are known.
+    shared_buckets: Vec<Arc<TokenBucket>>,
+    // Set once the tunnel request is decoded, for `TunnelStats`.
+    target: Option<String>,
+    handshake_duration: Option<Duration>,
+    // Sent with the tunnel request and forwarded ahead of the relay, counted in `upstream_stats`.
+    pipelined_bytes: usize,
 }
 
 #[async_trait]
@@ -105,6 +172,10 @@ pub trait TunnelTarget {
     fn target_addr(&self) -> Self::Addr;
     fn has_nugget(&self) -> bool;
     fn nugget(&self) -> &Nugget;
+    /// The authenticated user, if the handshake has any.
+    fn user(&self) -> Option<&str> {
+        None
+    }
 }
 
 impl<H, C, T> ConnectionTunnel<H, C, T>
@@ -138,9 +209,47 @@ where
             tunnel_ctx,
             client: Some(client),
             tunnel_config,
+            concurrency_limiter: None,
+            tunnel_permit: None,
+            force_close: None,
+            live_tunnel: None,
+            bandwidth_limiter: None,
+            shared_buckets: vec![],
+            target: None,
+            handshake_duration: None,
+            pipelined_bytes: 0,
         }
     }
 
+    /// Closes the relays when the proxy is shutting down and the drain timeout expired.
+    pub fn with_force_close(mut self, force_close: ForceClose) -> Self {
+        self.force_close = Some(force_close);
+        self
+    }
+
+    /// Throttles the tunnel with the buckets shared by its user and target, see `BandwidthLimits`.
+    pub fn with_bandwidth_limiter(mut self, bandwidth_limiter: Arc<BandwidthLimiter>) -> Self {
+        self.bandwidth_limiter = Some(bandwidth_limiter);
+        self
+    }
+
+    /// Publishes the target and live byte counts, and lets the tunnel be terminated via the admin API.
+    pub fn with_live_tunnel(mut self, live_tunnel: Arc<LiveTunnel>) -> Self {
+        self.live_tunnel = Some(live_tunnel);
+        self
+    }
+
+    /// Counts the tunnel against the configured concurrency limits of the `client_ip`,
+    /// rejecting it with `TooManyRequests` before connecting to the target if any is reached.
+    pub fn with_concurrency_limiter(
+        mut self,
+        concurrency_limiter: Arc<ConcurrencyLimiter>,
+        client_ip: IpAddr,
+    ) -> Self {
+        self.concurrency_limiter = Some((concurrency_limiter, client_ip));
+        self
+    }
+
     /// (Original comments)
     /// Once the client connected we wait for a tunnel establishment handshake.
     /// For instance, an `HTTP/1.1 CONNECT` for HTTP tunnels.
@@ -164,26 +273,52 @@ where
 
         let tunnel_result = self.establish_tunnel(stream, self.tunnel_config.clone()).await;
 
+        // When `connect_to_target` timed out, the connector didn't get to finish its attempts.
+        let connect_stats = self.target_connector.connect_stats().map(|mut connect_stats| {
+            connect_stats.cancel_pending();
+            connect_stats
+        });
+
         if let Err(error) = tunnel_result {
-            return Ok(TunnelStats {
+            let mut stats = TunnelStats {
                 tunnel_ctx: self.tunnel_ctx,
                 result: error,
                 upstream_stats: None,
                 downstream_stats: None,
-            });
+                connect_stats: None,
+                target: self.target.take(),
+                target_addr: None,
+                timings: TunnelTimings {
+                    handshake: self.handshake_duration,
+                    ..TunnelTimings::default()
+                },
+            };
+            stats.set_connect_stats(connect_stats);
+            return Ok(stats);
         }
 
         // upwrap Returns the contained Ok value, consuming the self value.
         // https://doc.rust-lang.org/std/result/enum.Result.html#method.unwrap
         let (client, target) = tunnel_result.unwrap();
-        relay_connections(
+        let mut stats = relay_connections(
             client,
             target,
             self.tunnel_ctx,
             self.tunnel_config.client_connection.relay_policy,
             self.tunnel_config.target_connection.relay_policy,
+            self.force_close.take(),
+            self.live_tunnel.take(),
+            std::mem::take(&mut self.shared_buckets),
         )
-        .await
+        .await?;
+
+        stats.target = self.target.take();
+        stats.timings.handshake = self.handshake_duration;
+        if let Some(upstream_stats) = &mut stats.upstream_stats {
+            upstream_stats.total_bytes += self.pipelined_bytes;
+        }
+        stats.set_connect_stats(connect_stats);
+        Ok(stats)
     }
 
     async fn establish_tunnel(
@@ -221,12 +356,38 @@ where
         if response_sent {
             match target {
                 None => Err(response),
-                Some(u) => {
+                Some(mut u) => {
                     // lets take the original stream to either relay data, or to drop it on error
                     let framed = write.reunite(read).expect("Uniting previously split parts");
-                    let original_stream = framed.into_inner();
+                    // into_parts: Consumes the Framed, returning its underlying I/O stream, the buffer with unprocessed data, and the codec.
+                    // https://docs.rs/tokio-util/0.6.7/tokio_util/codec/struct.Framed.html#method.into_parts
+                    // Unlike into_inner, it keeps the bytes the client sent right after the request (e.g. a ClientHello in the same segment as CONNECT),
+                    // they go to the target ahead of the relayed ones.
+                    let parts = framed.into_parts();
+                    if !parts.read_buf.is_empty() {
+                        debug!(
+                            "Forwarding {} bytes sent after the tunnel request, CTX={}",
+                            parts.read_buf.len(),
+                            self.tunnel_ctx
+                        );
+                        let forwarded = timeout(
+                            configuration.target_connection.relay_policy.idle_timeout,
+                            u.write_all(&parts.read_buf),
+                        )
+                        .await;
+                        if !matches!(forwarded, Ok(Ok(()))) {
+                            error!("Failed to forward the pipelined bytes, CTX={}", self.tunnel_ctx);
+                            return Err(EstablishTunnelResult::BadGateway);
+                        }
+                        self.pipelined_bytes = parts.read_buf.len();
+                        if let Some(live_tunnel) = &self.live_tunnel {
+                            live_tunnel
+                                .upstream_bytes
+                                .fetch_add(parts.read_buf.len() as u64, Ordering::Relaxed);
+                        }
+                    }
 
-                    Ok((original_stream, u))
+                    Ok((parts.io, u))
                 }
             }
         } else {
@@ -248,6 +409,7 @@ where
         EstablishTunnelResult,
         Option<<T as TargetConnector>::Stream>,
     ) {
+        let handshake_start = Instant::now();
         let connect_request = timeout(
             configuration.client_connection.initiation_timeout,
             read.next(),
@@ -263,8 +425,27 @@ where
                 self.tunnel_ctx);
             response = EstablishTunnelResult::RequestTimeout;
         } else if let Some(event) = connect_request.unwrap() {
+            if let Ok(decoded_target) = &event {
+                self.handshake_duration = Some(handshake_start.elapsed());
+                self.target = Some(decoded_target.to_string());
+            }
             match event {
+                Ok(decoded_target) if !self.acquire_permit(configuration, &decoded_target) => {
+                    error!(
+                        "Too many tunnels, rejected {}, CTX={}",
+                        decoded_target, self.tunnel_ctx
+                    );
+                    response = EstablishTunnelResult::TooManyRequests;
+                }
                 Ok(decoded_target) => {
+                    if let Some(limiter) = &self.bandwidth_limiter {
+                        self.shared_buckets = limiter.shared_buckets(
+                            &configuration.bandwidth,
+                            decoded_target.user(),
+                            &decoded_target.to_string(),
+                        );
+                    }
+
                     let has_nugget = decoded_target.has_nugget();
                     response = match self
                         .connect_to_target(
@@ -295,6 +476,18 @@ where
         (response, target)
     }
 
+    /// Returns `false` if a concurrency limit is reached.
+    fn acquire_permit(&mut self, configuration: &TunnelConfig, target: &H::Item) -> bool {
+        match &self.concurrency_limiter {
+            None => true,
+            Some((limiter, client_ip)) => {
+                self.tunnel_permit =
+                    limiter.try_acquire(&configuration.limits, *client_ip, &target.to_string());
+                self.tunnel_permit.is_some()
+            }
+        }
+    }
+
     async fn connect_to_target(
         &mut self,
         target: T::Target,
@@ -305,6 +498,10 @@ where
             target, self.tunnel_ctx,
         );
 
+        if let Some(live_tunnel) = &self.live_tunnel {
+            live_tunnel.set_target(target.to_string());
+        }
+
         let timed_connection_result = timeout(
             connect_timeout, 
             self.target_connector.connect(&target)
@@ -321,6 +518,7 @@ where
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn relay_connections<
     D: AsyncRead + AsyncWrite + Sized + Send + Unpin + 'static,
     U: AsyncRead + AsyncWrite + Sized + Send + 'static,
@@ -330,6 +528,9 @@ pub async fn relay_connections<
     ctx: TunnelCtx,
     downstream_relay_policy: RelayPolicy,
     upstream_relay_policy: RelayPolicy,
+    force_close: Option<ForceClose>,
+    live_tunnel: Option<Arc<LiveTunnel>>,
+    shared_buckets: Vec<Arc<TokenBucket>>,
 ) -> io::Result<TunnelStats> {
     let (client_recv, client_send) = io::split(client);
     let (target_recv, target_send) = io::split(target);
@@ -338,6 +539,11 @@ pub async fn relay_connections<
         .name("Downstream")
         .tunnel_ctx(ctx)
         .relay_policy(downstream_relay_policy)
+        .force_close(force_close.clone())
+        .termination(live_tunnel.as_ref().map(|t| t.termination()))
+        // Client to target, reported as `upstream_stats`.
+        .live_bytes(live_tunnel.as_ref().map(|t| t.upstream_bytes.clone()))
+        .shared_buckets(shared_buckets.clone())
         .build()
         .expect("RepayBuilder failed");
     
@@ -345,6 +551,10 @@ pub async fn relay_connections<
         .name("Upstream")
         .tunnel_ctx(ctx)
         .relay_policy(upstream_relay_policy)
+        .force_close(force_close)
+        .termination(live_tunnel.as_ref().map(|t| t.termination()))
+        .live_bytes(live_tunnel.as_ref().map(|t| t.downstream_bytes.clone()))
+        .shared_buckets(shared_buckets)
         .build()
         .expect("RelayBuilder failed");
     
@@ -363,5 +573,103 @@ pub async fn relay_connections<
         result: EstablishTunnelResult::Ok,
         upstream_stats: Some(upstream_stats),
         downstream_stats: Some(downstream_stats),
+        connect_stats: None,
+        target: None,
+        target_addr: None,
+        timings: TunnelTimings::default(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_tunnel_codec::{HttpTunnelCodec, HttpTunnelCodecBuilder, HttpTunnelTarget};
+    use crate::registry::TunnelRegistry;
+    use regex::Regex;
+    use tokio::io::{AsyncReadExt, DuplexStream};
+
+    const CONFIG: &str = "
+client_connection:
+  initiation_timeout: 10s
+  relay_policy: {idle_timeout: 30s, min_rate_bpm: 0, max_rate_bpm: 10000000}
+target_connection:
+  dns_cache_ttl: 60s
+  allowed_targets: \".*\"
+  connect_timeout: 5s
+  relay_policy: {idle_timeout: 30s, min_rate_bpm: 0, max_rate_bpm: 10000000}
+";
+
+    /// Hands out one end of an in-memory pipe, the test keeps the other one as the target.
+    struct PipeConnector {
+        stream: Option<DuplexStream>,
+    }
+
+    #[async_trait]
+    impl TargetConnector for PipeConnector {
+        type Target = HttpTunnelTarget;
+        type Stream = DuplexStream;
+
+        async fn connect(&mut self, _target: &Self::Target) -> io::Result<Self::Stream> {
+            self.stream.take().ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionRefused))
+        }
+    }
+
+    fn http_codec() -> HttpTunnelCodec {
+        HttpTunnelCodecBuilder::default()
+            .tunnel_ctx(TunnelCtx::default())
+            .enabled_targets(Regex::new(".*").unwrap())
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn bytes_sent_with_the_request_reach_the_target() {
+        let (client, mut client_end) = io::duplex(4096);
+        let (target, mut target_end) = io::duplex(4096);
+        let registry = Arc::new(TunnelRegistry::default());
+        let registration = registry.register(TunnelCtx::default(), "192.0.2.1:50000".parse().unwrap());
+        let tunnel = ConnectionTunnel::new(
+            http_codec(),
+            PipeConnector { stream: Some(target) },
+            client,
+            TunnelConfig::from_yaml(CONFIG.as_bytes()).unwrap(),
+            TunnelCtx::default(),
+        )
+        .with_live_tunnel(registration.tunnel());
+        let tunnel = tokio::spawn(tunnel.start());
+
+        // The start of a TLS ClientHello record, in the same write as CONNECT.
+        let client_hello = b"\x16\x03\x01\x00\x2a\x01\x00\x00\x26\x03\x03";
+        let mut request = b"CONNECT www.example.com:443 HTTP/1.1\r\nHost: www.example.com:443\r\n\r\n".to_vec();
+        request.extend_from_slice(client_hello);
+        client_end.write_all(&request).await.unwrap();
+
+        let mut response = [0u8; 19];
+        client_end.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200 OK\r\n\r\n");
+
+        // Lost bytes would leave the target waiting, don't hang the test on it.
+        let mut received = [0u8; 11];
+        timeout(Duration::from_secs(5), target_end.read_exact(&mut received))
+            .await
+            .expect("ClientHello didn't reach the target")
+            .unwrap();
+        assert_eq!(&received, client_hello);
+
+        // The rest is relayed as usual.
+        client_end.write_all(b"more").await.unwrap();
+        let mut more = [0u8; 4];
+        target_end.read_exact(&mut more).await.unwrap();
+        assert_eq!(&more, b"more");
+
+        drop(client_end);
+        drop(target_end);
+        let stats = tunnel.await.unwrap().unwrap();
+        assert_eq!(stats.result, EstablishTunnelResult::Ok);
+        assert_eq!(stats.target.as_deref(), Some("www.example.com:443"));
+
+        // The forwarded ClientHello is counted along with the relayed bytes.
+        assert_eq!(stats.upstream_stats.unwrap().total_bytes, 15);
+        assert_eq!(registration.tunnel().upstream_bytes.load(Ordering::Relaxed), 15);
+    }
+}